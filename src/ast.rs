@@ -1,91 +1,117 @@
 /* AST Definition*/
 
+use crate::binops::BinopType;
 use crate::value::* ;
-use crate::value::Value::* ;
-use crate::ast::node::N_CONST;<>
-use crate::ast::ast_node::Constant;
-
-type MemoryLocation = usize ;
-type CodeLocation = usize ;
 
 /**
-p_node contains a binary representation of the
-data which can be emitted directly to either
-a constants pool, value type, or compound object (such as a function)
+Span records where in the source a token, or the node made from it, came
+from so that later passes (type checking, code generation) can report
+errors and emit line information without going back to the tokens.
+startByte and endByte are offsets into the source, end exclusive; column
+counts characters from 1 at the start of the line.
 */
-
-enum node {
-    N_VALUE(Value),
-    N_BINOP(Binop)
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct Span {
+    pub startByte: usize,
+    pub endByte: usize,
+    pub line: usize,
+    pub column: usize,
 }
 
-
-pub struct ast_tree {
-    node_ptr: usize,          // The current position we're in if we're traversing
-    ast_nodes: Vec<node>  // Vector of nodes
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum UnaryOp {
+    U_NEGATE,
+    U_NOT
 }
 
-impl ast_tree {
-    // ast::tree.new()
-    pub fn new() -> ast_tree {
-        return ast_tree{node_ptr: 0, ast_nodes: Vec::<node>::new() } ;
-    }
-
-    pub fn push(&mut self, n: node) {
-        self.ast_nodes.push(n) ;
-        self.node_ptr+=1 ;
-    }
-    /**
-    Check the type of the values in the preceding nodes
-    */
-    pub fn get_ast_value_type(&self, number_of_nodes_to_seek: i32 , current_position: usize) -> Vec<ValueType> {
-        let mut ptr:usize = 0 ;
-        let mut vtypes:Vec<ValueType> = Vec::new() ;
-        loop {
-            ptr += 1 ;
-            match &self.ast_nodes[current_position-1]  {
-                Constant  => vtypes.push(self.ast_nodes[current_position-1].)
-                _ => panic!() ;
-            }
-        }
-    }
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum LogicalOp {
+    L_AND,
+    L_OR
+}
 
+/**
+Expressions produce a value. Every expression carries its span, and its
+static type once the type checker has visited it.
+*/
+#[derive(Clone, PartialEq, Debug)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+    pub ty: Option<ValueType>,
 }
 
-/*
-This tell us what the node contains. Depending on the value type, the node in the tree
-will look and behave differently
+#[derive(Clone, PartialEq, Debug)]
+pub enum ExprKind {
+    Literal(Value),
+    // Strings live on the heap, so codegen allocates them
+    String(String),
+    // "a ${b} c": the string literals and expressions, in order, whose
+    // values are joined into one string
+    Interpolation(Vec<Expr>),
+    Unary { op: UnaryOp, right: Box<Expr> },
+    Binary { op: BinopType, left: Box<Expr>, right: Box<Expr> },
+    // Short-circuiting, so it evaluates to whichever operand decided it
+    Logical { op: LogicalOp, left: Box<Expr>, right: Box<Expr> },
+    Grouping(Box<Expr>),
+    Variable(String),
+    // <module>::<name>, a global of an imported module
+    Qualified { module: String, name: String },
+    Assign { name: String, value: Box<Expr> },
+    Call { callee: Box<Expr>, args: Vec<Expr> },
+    Array(Vec<Expr>),
+    Map(Vec<(Expr, Expr)>),
+    Index { object: Box<Expr>, index: Box<Expr> },
+    IndexSet { object: Box<Expr>, index: Box<Expr>, value: Box<Expr> },
+    // A missing bound runs to that end of the array
+    Slice { object: Box<Expr>, start: Option<Box<Expr>>, end: Option<Box<Expr>> },
+    // Fields and methods of an instance
+    Get { object: Box<Expr>, name: String },
+    Set { object: Box<Expr>, name: String, value: Box<Expr> },
+    This,
+    // A method of the superclass, looked up from inside a method
+    Super { method: String },
+}
 
-N_CONST: These are simply value types which we then use as constants
-N_BINOP: *,+,-,/, and so on.
+/**
+Statements are executed for their effect. A program is a list of them.
+*/
+#[derive(Clone, PartialEq, Debug)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+}
 
- */
+#[derive(Clone, PartialEq, Debug)]
+pub enum StmtKind {
+    Expression(Expr),
+    Print(Expr),
+    Let { name: String, initializer: Option<Expr> },
+    Block(Vec<Stmt>),
+    If { condition: Expr, then_branch: Vec<Stmt>, else_branch: Option<Box<Stmt>> },
+    Loop { body: Vec<Stmt> },
+    While { condition: Expr, body: Vec<Stmt> },
+    ForRange { name: String, start: Expr, end: Expr, body: Vec<Stmt> },
+    // Elements of an array or keys of a map
+    ForIn { name: String, iterable: Expr, body: Vec<Stmt> },
+    Break,
+    Continue,
+    Fn { name: String, params: Vec<String>, body: Vec<Stmt> },
+    // Every method is a Fn statement; the superclass is a Variable
+    Class { name: String, superclass: Option<Expr>, methods: Vec<Stmt> },
+    Return(Option<Expr>),
+    Module(String),
+    Import(String),
+}
 
-macro_rules! AST_CONSTANT {
-    {$op:tt} => {
-        self.push($valType!((lval $op rval)))
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Expr {
+        return Expr { kind, span, ty: None } ;
     }
 }
 
-/* =============== Unit tests ================= */
-
-#[cfg(test)]
-mod tests {
-
-    use crate::ast::* ;
-    #[test]
-    fn new_tree() {
-        let mut tree = ast_tree::new() ;
-        assert_eq!(tree.node_ptr,0) ;
-
-        // Add a number node
-        tree.push(N_CONST(INTEGER(42))) ;
-        tree.push(N_CONST(INTEGER(43))) ;
-        tree.push(N_CONST(STRING("HEY"))) ;
-
-        assert_eq!(tree.ast_nodes[0],N_CONST(INTEGER(42)), "Test INTEGER node == 42");
-        assert_eq!(tree.ast_nodes[1],N_CONST(INTEGER(43)), "Test INTEGER node == 43");
-        assert_eq!(tree.ast_nodes[2],N_CONST(STRING("HEY")), "Test STRING node == 'HEY'");
-
+impl Stmt {
+    pub fn new(kind: StmtKind, span: Span) -> Stmt {
+        return Stmt { kind, span } ;
     }
-}
\ No newline at end of file
+}
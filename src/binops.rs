@@ -1,4 +1,7 @@
+use crate::opcodes::OpCode ;
+use crate::opcodes::OpCode::* ;
 use crate::value::ValueType;
+use crate::value::ValueType::* ;
 
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub enum BinopType {
@@ -6,7 +9,35 @@ pub enum BinopType {
     B_MINUS,
     B_MULT,
     B_DIV,
-    B_MOD
+    B_MOD,
+    B_EQUAL,
+    B_NOT_EQUAL,
+    B_GREATER,
+    B_GREATER_EQUAL,
+    B_LESS,
+    B_LESS_EQUAL,
+    B_IN
+}
+
+use BinopType::* ;
+
+impl BinopType {
+    pub fn symbol(&self) -> &'static str {
+        return match self {
+            B_PLUS          => "+",
+            B_MINUS         => "-",
+            B_MULT          => "*",
+            B_DIV           => "/",
+            B_MOD           => "%",
+            B_EQUAL         => "==",
+            B_NOT_EQUAL     => "!=",
+            B_GREATER       => ">",
+            B_GREATER_EQUAL => ">=",
+            B_LESS          => "<",
+            B_LESS_EQUAL    => "<=",
+            B_IN            => "in",
+        }
+    }
 }
 
 /**
@@ -14,8 +45,23 @@ valtypes expresses the value type we have in the left and right
 side of the binary operation
 */
 pub struct Binop {
-    valtypes: (ValueType, ValueType),
-    optype: BinopType,
+    pub valtypes: (ValueType, ValueType),
+    pub optype: BinopType,
+}
+
+/**
+The outcome of resolving a Binop against its operand types:
+ops        : instructions to emit once both operands are on the stack
+result     : the type the expression produces
+promote_*  : the operand is an integer that has to be converted to a
+             double (OP_ITOD) right after it is evaluated
+*/
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct TypedBinop {
+    pub ops: &'static [OpCode],
+    pub result: ValueType,
+    pub promote_left: bool,
+    pub promote_right: bool,
 }
 
 impl Binop {
@@ -25,4 +71,171 @@ impl Binop {
             optype: v,
         };
     }
-}
\ No newline at end of file
+
+    fn is_number(vtype: ValueType) -> bool {
+        return vtype == VAL_INTEGER || vtype == VAL_DOUBLE ;
+    }
+
+    // A number, or something that may turn out to be one at runtime
+    fn maybe_number(vtype: ValueType) -> bool {
+        return Binop::is_number(vtype) || vtype == VAL_ANY ;
+    }
+
+    /**
+    Picks the typed instruction(s) for this operation, or None when the
+    operand types can't be combined this way (e.g. true + 1)
+    */
+    pub fn resolve(&self) -> Option<TypedBinop> {
+        let (left, right) = self.valtypes ;
+
+        let typed = |ops: &'static [OpCode], result: ValueType| {
+            Some(TypedBinop { ops, result, promote_left: false, promote_right: false })
+        } ;
+        // An integer compared with a double is compared as a double
+        let compared = |ops: &'static [OpCode]| {
+            let mixed = Binop::is_number(left) && Binop::is_number(right) && left != right ;
+            Some(TypedBinop {
+                ops,
+                result: VAL_BOOL,
+                promote_left: mixed && left == VAL_INTEGER,
+                promote_right: mixed && right == VAL_INTEGER,
+            })
+        } ;
+
+        match self.optype {
+            B_PLUS if left == VAL_STRING || right == VAL_STRING => {
+                if left == VAL_STRING && right == VAL_STRING {
+                    return typed(&[OP_CONCAT], VAL_STRING) ;
+                }
+                if left == VAL_ANY || right == VAL_ANY {
+                    // OP_ADD concatenates when it finds two strings
+                    return typed(&[OP_ADD], VAL_STRING) ;
+                }
+                return None ;
+            }
+            B_PLUS | B_MINUS | B_MULT | B_DIV => {
+                if !Binop::maybe_number(left) || !Binop::maybe_number(right) {
+                    return None ;
+                }
+                if left == VAL_ANY || right == VAL_ANY {
+                    // Fall back to the instructions that check at runtime
+                    let ops: &'static [OpCode] = match self.optype {
+                        B_PLUS  => &[OP_ADD],
+                        B_MINUS => &[OP_SUB],
+                        B_MULT  => &[OP_MUL],
+                        _       => &[OP_DIV],
+                    } ;
+                    return typed(ops, VAL_ANY) ;
+                }
+                if left == VAL_INTEGER && right == VAL_INTEGER {
+                    let ops: &'static [OpCode] = match self.optype {
+                        B_PLUS  => &[OP_IADD],
+                        B_MINUS => &[OP_ISUB],
+                        B_MULT  => &[OP_IMUL],
+                        _       => &[OP_IDIV],
+                    } ;
+                    return typed(ops, VAL_INTEGER) ;
+                }
+                // At least one side is a double, so the whole operation is done in doubles
+                let ops: &'static [OpCode] = match self.optype {
+                    B_PLUS  => &[OP_DADD],
+                    B_MINUS => &[OP_DSUB],
+                    B_MULT  => &[OP_DMUL],
+                    _       => &[OP_DDIV],
+                } ;
+                return Some(TypedBinop {
+                    ops,
+                    result: VAL_DOUBLE,
+                    promote_left: left == VAL_INTEGER,
+                    promote_right: right == VAL_INTEGER,
+                }) ;
+            }
+            B_MOD => return None,
+            B_GREATER | B_GREATER_EQUAL | B_LESS | B_LESS_EQUAL => {
+                if !Binop::maybe_number(left) || !Binop::maybe_number(right) {
+                    return None ;
+                }
+                // Not the negation of < and >, which a NaN makes false
+                let ops: &'static [OpCode] = match self.optype {
+                    B_GREATER       => &[OP_GREATER],
+                    B_GREATER_EQUAL => &[OP_GREATER_EQUAL],
+                    B_LESS          => &[OP_LESS],
+                    _               => &[OP_LESS_EQUAL],
+                } ;
+                return compared(ops) ;
+            }
+            B_IN => {
+                if right != VAL_ARRAY && right != VAL_MAP && right != VAL_ANY {
+                    return None ;
+                }
+                return typed(&[OP_IN], VAL_BOOL) ;
+            }
+            B_EQUAL => return compared(&[OP_EQUAL]),
+            B_NOT_EQUAL => return compared(&[OP_EQUAL, OP_NOT]),
+        }
+    }
+}
+
+/* =============== Unit tests ================= */
+
+#[cfg(test)]
+mod tests {
+
+    use crate::binops::* ;
+
+    #[test]
+    fn resolve_arithmetic() {
+        let op = Binop::new(B_PLUS, (VAL_INTEGER, VAL_INTEGER)).resolve().unwrap() ;
+        assert_eq!(op.ops, &[OP_IADD]) ;
+        assert_eq!(op.result, VAL_INTEGER) ;
+
+        let op = Binop::new(B_DIV, (VAL_INTEGER, VAL_DOUBLE)).resolve().unwrap() ;
+        assert_eq!(op.ops, &[OP_DDIV]) ;
+        assert_eq!(op.result, VAL_DOUBLE) ;
+        assert!(op.promote_left && !op.promote_right) ;
+    }
+
+    #[test]
+    fn resolve_comparisons() {
+        let op = Binop::new(B_EQUAL, (VAL_DOUBLE, VAL_INTEGER)).resolve().unwrap() ;
+        assert_eq!(op.ops, &[OP_EQUAL]) ;
+        assert!(!op.promote_left && op.promote_right) ;
+
+        let op = Binop::new(B_LESS_EQUAL, (VAL_INTEGER, VAL_DOUBLE)).resolve().unwrap() ;
+        assert_eq!(op.ops, &[OP_LESS_EQUAL]) ;
+        assert_eq!(op.result, VAL_BOOL) ;
+        assert!(op.promote_left && !op.promote_right) ;
+
+        // Only numbers of different types are promoted
+        let op = Binop::new(B_NOT_EQUAL, (VAL_INTEGER, VAL_ANY)).resolve().unwrap() ;
+        assert!(!op.promote_left && !op.promote_right) ;
+    }
+
+    #[test]
+    fn resolve_incompatible() {
+        assert!(Binop::new(B_PLUS, (VAL_BOOL, VAL_INTEGER)).resolve().is_none()) ;
+        assert!(Binop::new(B_LESS, (VAL_NIL, VAL_DOUBLE)).resolve().is_none()) ;
+        assert!(Binop::new(B_EQUAL, (VAL_NIL, VAL_DOUBLE)).resolve().is_some()) ;
+        assert!(Binop::new(B_IN, (VAL_STRING, VAL_MAP)).resolve().is_some()) ;
+        assert!(Binop::new(B_IN, (VAL_STRING, VAL_STRING)).resolve().is_none()) ;
+        assert!(Binop::new(B_PLUS, (VAL_STRING, VAL_INTEGER)).resolve().is_none()) ;
+        assert!(Binop::new(B_MINUS, (VAL_STRING, VAL_STRING)).resolve().is_none()) ;
+    }
+
+    #[test]
+    fn resolve_concatenation() {
+        let op = Binop::new(B_PLUS, (VAL_STRING, VAL_STRING)).resolve().unwrap() ;
+        assert_eq!(op.ops, &[OP_CONCAT]) ;
+        assert_eq!(op.result, VAL_STRING) ;
+        let op = Binop::new(B_PLUS, (VAL_ANY, VAL_STRING)).resolve().unwrap() ;
+        assert_eq!(op.ops, &[OP_ADD]) ;
+    }
+
+    #[test]
+    fn resolve_dynamic() {
+        let op = Binop::new(B_MULT, (VAL_ANY, VAL_DOUBLE)).resolve().unwrap() ;
+        assert_eq!(op.ops, &[OP_MUL]) ;
+        assert_eq!(op.result, VAL_ANY) ;
+        assert!(Binop::new(B_PLUS, (VAL_ANY, VAL_BOOL)).resolve().is_none()) ;
+    }
+}
@@ -1,8 +1,6 @@
+use crate::ast::Span ;
 use crate::value::* ;
 
-const CODE_CAPACITY: usize = 1024000 ;
-const CONST_CAPACITY: usize = 255 ;
-
 pub struct Chunk {
     pub code: Vec<u8>,
     pub code_ptr: usize,
@@ -10,58 +8,69 @@ pub struct Chunk {
     pub constants: Vec<Value>,
     const_ptr: usize,
 
-    pub lines: Vec<usize>
+    // Where in the source each byte of code came from
+    pub spans: Vec<Span>,
+
+    // Names of the global variables, indexed by the operand of the
+    // OP_*_GLOBAL instructions
+    pub globals: Vec<String>
 }
 
 pub fn newChunk() -> Chunk {
     return Chunk {
-        code: Vec::<u8>::with_capacity(CODE_CAPACITY),
+        code: Vec::new(),
         code_ptr: 0,
         constants: vec![],
-        lines: Vec::<usize>::with_capacity(CODE_CAPACITY),
-        const_ptr: 0
+        spans: Vec::new(),
+        const_ptr: 0,
+        globals: vec![]
     }
 }
 
 impl Chunk {
 
-    fn check_capacity(&mut self) {
-        // Check capacity - if we need more size
-        if self.code.capacity() < self.code.len() + 1 {
-            self.code.reserve(CODE_CAPACITY);
-            self.lines.reserve(CODE_CAPACITY);
-        }
-    }
-
-    pub fn writeChunk(&mut self, byte: u8, line: usize) {
-        
-        self.check_capacity() ;
-
+    pub fn writeChunk(&mut self, byte: u8, span: Span) {
         // Add the code to the end
         self.code.push(byte);
-        self.lines.push(line);
-        
+        self.spans.push(span);
     }
 
-    pub fn writeConstant(&mut self, Index: u16, line: usize) {
-
-        self.check_capacity() ;
-
+    pub fn writeConstant(&mut self, Index: u16, span: Span) {
         // Add the code to the end
         self.code.append(&mut u16::to_le_bytes(Index).to_vec());
-        self.code_ptr = self.code_ptr + 2;
-        self.lines.push(line);
-        self.lines.push(line);
-
+        self.code_ptr += 2;
+        self.spans.push(span);
+        self.spans.push(span);
     }
 
-    pub fn addConstant(&mut self, value: Value) -> usize {
+    /**
+    The source line the byte of code at offset came from, or 0 if there is
+    no such byte
+    */
+    pub fn line(&self, offset: usize) -> usize {
+        return self.spans.get(offset).map_or(0, |span| span.line) ;
+    }
 
-        // Check capacity - if we need more size
-        if self.constants.capacity() < self.code.len() + 1 {
-            self.constants.reserve(CONST_CAPACITY);
+    /**
+    Jumps are written with a placeholder operand since the target isn't
+    known yet; patchJump fills in the distance from the end of the jump
+    instruction to the current end of the code. Returns false if the
+    distance doesn't fit in the operand.
+    */
+    pub fn patchJump(&mut self, offset: usize) -> bool {
+        // -2 to adjust for the bytes of the jump offset itself
+        let jump = self.code.len() - offset - 2 ;
+        if jump > u16::MAX as usize {
+            return false ;
         }
-        // Add the code to the end
+        let bytes = u16::to_le_bytes(jump as u16) ;
+        self.code[offset] = bytes[0] ;
+        self.code[offset + 1] = bytes[1] ;
+        return true ;
+    }
+
+    pub fn addConstant(&mut self, value: Value) -> usize {
+        // Add the constant to the end
         self.constants.push(value);
         self.const_ptr += 1;
         return self.const_ptr-1 ;
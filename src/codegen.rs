@@ -0,0 +1,1277 @@
+/*
+Code generation
+
+Lowers the AST produced by the compiler front end into a Chunk. Keeping
+this separate from parsing means other passes can inspect or rewrite the
+tree before any bytecode is written.
+*/
+
+use crate::ast::* ;
+use crate::ast::UnaryOp::* ;
+use crate::ast::LogicalOp::* ;
+use crate::binops::* ;
+use crate::chunk::* ;
+use crate::diagnostics::* ;
+use crate::diagnostics::ErrorCode::* ;
+use crate::modules::Module ;
+use crate::object::* ;
+use crate::opcodes::OpCode ;
+use crate::opcodes::OpCode::* ;
+use crate::value::* ;
+use crate::value::ValueType::* ;
+
+use std::collections::HashMap ;
+use std::rc::Rc ;
+
+/**
+A local variable lives in a stack slot; its index in `locals` is the slot
+number and depth is the block nesting level it was declared at.
+isCaptured is set once a nested function refers to it, so the slot has to
+be closed over rather than simply popped when it goes out of scope.
+*/
+struct Local {
+    name: String,
+    depth: usize,
+    isCaptured: bool,
+}
+
+/**
+A variable captured by the function being compiled: either a local slot of
+the function immediately around it (isLocal) or one of that function's own
+upvalues.
+*/
+#[derive(Copy, Clone, PartialEq)]
+struct UpvalueRef {
+    index: u16,
+    isLocal: bool,
+}
+
+/**
+Book-keeping for the loop currently being compiled.
+localCount : locals that were already live when the body started; break
+             and continue pop everything above this
+continueTarget : backward target of continue, or None when continue has to
+             jump forward (range loops, where the increment follows the body)
+*/
+struct LoopContext {
+    localCount: usize,
+    continueTarget: Option<usize>,
+    continueJumps: Vec<usize>,
+    breakJumps: Vec<usize>,
+}
+
+/**
+Methods get the instance they were called on in slot 0, and an initializer
+always returns it.
+*/
+#[derive(Copy, Clone, PartialEq)]
+enum FunctionKind {
+    FN_FUNCTION,
+    FN_METHOD,
+    FN_INITIALIZER,
+}
+
+use FunctionKind::* ;
+
+/**
+Everything that belongs to the function being compiled. Slot 0 of every
+call frame holds the function that was called, so it is reserved up front
+under a name no script can refer to. In a method it holds the instance
+instead and is named after 'this'.
+*/
+struct FunctionState {
+    name: String,
+    kind: FunctionKind,
+    arity: usize,
+    chunk: Chunk,
+    locals: Vec<Local>,
+    upvalues: Vec<UpvalueRef>,
+    loops: Vec<LoopContext>,
+    scopeDepth: usize,
+}
+
+impl FunctionState {
+    fn new(name: &str, kind: FunctionKind) -> FunctionState {
+        let slotZero = if kind == FN_FUNCTION { "" } else { "this" } ;
+        return FunctionState {
+            name: name.to_string(),
+            kind,
+            arity: 0,
+            chunk: newChunk(),
+            locals: vec![Local { name: slotZero.to_string(), depth: 0, isCaptured: false }],
+            upvalues: Vec::new(),
+            loops: Vec::new(),
+            scopeDepth: 0,
+        } ;
+    }
+}
+
+/**
+current is the function being compiled; the functions it is nested in are
+suspended on enclosing, innermost last. Global names are shared by all of
+them and end up on the script's chunk.
+The globals a module declares are named <module>::<name>, so each module
+has a namespace of its own. While a module is being compiled, module is
+its name and exports what it declares.
+*/
+pub struct CodeGen<'h> {
+    current: FunctionState,
+    enclosing: Vec<FunctionState>,
+    globals: Vec<String>,
+    functions: Vec<ObjRef>,
+    module: Option<String>,
+    exports: Vec<String>,
+    // The function running the top level of each module
+    modules: HashMap<String, ObjRef>,
+    heap: &'h mut Heap,
+    // Span of the node being lowered, for line information and errors
+    span: Span,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'h> CodeGen<'h> {
+
+    pub fn new(heap: &'h mut Heap) -> CodeGen<'h> {
+        return CodeGen {
+            current: FunctionState::new("", FN_FUNCTION),
+            enclosing: Vec::new(),
+            globals: Vec::new(),
+            functions: Vec::new(),
+            module: None,
+            exports: Vec::new(),
+            modules: HashMap::new(),
+            heap,
+            span: Span::default(),
+            diagnostics: Vec::new(),
+        }
+    }
+
+    /* Emit byte combos */
+    fn emitByte(&mut self, op:OpCode) {
+        self.current.chunk.writeChunk(op.to_byte(), self.span);
+    }
+
+    fn emitBytes(&mut self, op: OpCode, operand: u16) {
+        let bytes = u16::to_le_bytes(operand) ;
+        self.current.chunk.writeChunk(op.to_byte(), self.span);
+        self.current.chunk.writeChunk(bytes[0],self.span);
+        self.current.chunk.writeChunk(bytes[1],self.span);
+    }
+
+    // A second operand following an instruction
+    fn emitOperand(&mut self, operand: u16) {
+        let bytes = u16::to_le_bytes(operand) ;
+        self.current.chunk.writeChunk(bytes[0], self.span);
+        self.current.chunk.writeChunk(bytes[1], self.span);
+    }
+
+    fn emitReturn(&mut self) {
+        self.emitByte(OP_RETURN) ;
+    }
+
+    // Names of globals, properties and methods
+    fn identifierConstant(&mut self, name: &str) -> u16 {
+        let string = self.heap.intern(name) ;
+        return self.makeConstant(Value::from(string)) ;
+    }
+
+    fn emitConstant(&mut self, value: Value) {
+        let operand = self.makeConstant(value) ;
+        self.emitBytes(OP_CONSTANT, operand) ;
+    }
+
+    fn makeConstant(&mut self, value: Value) -> u16 {
+        let constant = self.current.chunk.addConstant(value);
+        if constant > u16::MAX as usize {
+            self.error(E_LIMIT, "Too many constants in one chunk.");
+            return 0;
+        }
+        return constant as u16;
+    }
+
+    /* Jumps */
+
+    // Emits a jump with a placeholder operand and returns where the operand is
+    fn emitJump(&mut self, op: OpCode) -> usize {
+        self.emitBytes(op, 0xffff) ;
+        return self.current.chunk.code.len() - 2 ;
+    }
+
+    fn patchJump(&mut self, offset: usize) {
+        if !self.current.chunk.patchJump(offset) {
+            self.error(E_LIMIT, "Too much code to jump over.") ;
+        }
+    }
+
+    fn emitLoop(&mut self, loopStart: usize) {
+        // +3 to step back over the OP_LOOP instruction itself
+        let offset = self.current.chunk.code.len() - loopStart + 3 ;
+        if offset > u16::MAX as usize {
+            self.error(E_LIMIT, "Loop body too large.") ;
+        }
+        self.emitBytes(OP_LOOP, offset as u16) ;
+    }
+
+    /* Variables */
+
+    fn beginScope(&mut self) {
+        self.current.scopeDepth += 1 ;
+    }
+
+    fn endScope(&mut self) {
+        self.current.scopeDepth -= 1 ;
+        while let Some(local) = self.current.locals.last() {
+            if local.depth <= self.current.scopeDepth {
+                break ;
+            }
+            let op = if local.isCaptured { OP_CLOSE_UPVALUE } else { OP_POP } ;
+            self.emitByte(op) ;
+            self.current.locals.pop() ;
+        }
+    }
+
+    /**
+    The function `depth` levels out from the one being compiled; 0 is the
+    current function itself
+    */
+    fn state(&mut self, depth: usize) -> &mut FunctionState {
+        if depth == 0 {
+            return &mut self.current ;
+        }
+        let index = self.enclosing.len() - depth ;
+        return &mut self.enclosing[index] ;
+    }
+
+    fn resolveLocal(&mut self, depth: usize, name: &str) -> Option<u16> {
+        return self.state(depth).locals.iter().rposition(|local| local.name == name).map(|slot| slot as u16) ;
+    }
+
+    fn addUpvalue(&mut self, depth: usize, upvalue: UpvalueRef) -> u16 {
+        let upvalues = &mut self.state(depth).upvalues ;
+        if let Some(index) = upvalues.iter().position(|u| *u == upvalue) {
+            return index as u16 ;
+        }
+        upvalues.push(upvalue) ;
+        let count = upvalues.len() ;
+        if count > u16::MAX as usize {
+            self.error(E_LIMIT, "Too many closure variables in function.") ;
+            return 0 ;
+        }
+        return (count - 1) as u16 ;
+    }
+
+    /**
+    Looks for name in the functions enclosing the one at `depth`, walking
+    outwards. Every function between the one that declares the variable and
+    the one using it gets an upvalue, so the reference can be threaded
+    through them at runtime.
+    */
+    fn resolveUpvalue(&mut self, depth: usize, name: &str) -> Option<u16> {
+        if depth >= self.enclosing.len() {
+            return None ;
+        }
+        if let Some(slot) = self.resolveLocal(depth + 1, name) {
+            self.state(depth + 1).locals[slot as usize].isCaptured = true ;
+            return Some(self.addUpvalue(depth, UpvalueRef { index: slot, isLocal: true })) ;
+        }
+        if let Some(index) = self.resolveUpvalue(depth + 1, name) {
+            return Some(self.addUpvalue(depth, UpvalueRef { index, isLocal: false })) ;
+        }
+        return None ;
+    }
+
+    // The global an unqualified name refers to in the code being compiled
+    fn globalName(&self, name: &str) -> String {
+        return match &self.module {
+            Some(module) if self.exports.iter().any(|export| export == name) => format!("{}::{}", module, name),
+            _ => name.to_string(),
+        }
+    }
+
+    fn resolveGlobal(&mut self, name: &str) -> u16 {
+        let name = self.globalName(name) ;
+        return self.resolveQualified(&name) ;
+    }
+
+    fn resolveQualified(&mut self, name: &str) -> u16 {
+        let slot = match self.globals.iter().position(|global| global == name) {
+            Some(slot) => slot,
+            None => {
+                self.globals.push(name.to_string()) ;
+                self.globals.len() - 1
+            }
+        } ;
+        if slot > u16::MAX as usize {
+            self.error(E_LIMIT, "Too many global variables.");
+            return 0;
+        }
+        return slot as u16 ;
+    }
+
+    fn addLocal(&mut self, name: &str) {
+        if self.current.locals.len() > u16::MAX as usize {
+            self.error(E_LIMIT, "Too many local variables.");
+            return ;
+        }
+        self.current.locals.push(Local { name: name.to_string(), depth: self.current.scopeDepth, isCaptured: false }) ;
+    }
+
+    fn letDeclaration(&mut self, name: &str, initializer: &Option<Expr>) {
+        match initializer {
+            Some(expr) => self.expression(expr),
+            None => self.emitByte(OP_NIL),
+        }
+
+        if self.current.scopeDepth > 0 {
+            // The value just pushed becomes the local's stack slot
+            self.addLocal(name) ;
+        } else {
+            let global = self.resolveGlobal(name) ;
+            self.emitBytes(OP_DEFINE_GLOBAL, global) ;
+        }
+    }
+
+    fn namedVariable(&mut self, name: &str, value: Option<&Expr>) {
+        let (getOp, setOp, operand) = if let Some(slot) = self.resolveLocal(0, name) {
+            (OP_GET_LOCAL, OP_SET_LOCAL, slot)
+        } else if let Some(index) = self.resolveUpvalue(0, name) {
+            (OP_GET_UPVALUE, OP_SET_UPVALUE, index)
+        } else {
+            (OP_GET_GLOBAL, OP_SET_GLOBAL, self.resolveGlobal(name))
+        } ;
+
+        match value {
+            Some(expr) => {
+                self.expression(expr) ;
+                self.emitBytes(setOp, operand) ;
+            }
+            None => self.emitBytes(getOp, operand),
+        }
+    }
+
+    /* Statements */
+
+    /**
+    The value of a trailing expression statement is left on the stack so
+    that it becomes the result of the script, which the repl echoes.
+    */
+    pub fn program(&mut self, program: &[Stmt]) {
+        for (i, stmt) in program.iter().enumerate() {
+            let last = i == program.len() - 1 ;
+            match &stmt.kind {
+                StmtKind::Expression(expr) if last => {
+                    self.span = stmt.span ;
+                    self.expression(expr) ;
+                }
+                _ => {
+                    self.statement(stmt) ;
+                    if last {
+                        self.emitByte(OP_NIL) ;
+                    }
+                }
+            }
+        }
+        if program.is_empty() {
+            self.emitByte(OP_NIL) ;
+        }
+        self.emitReturn() ;
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        self.span = stmt.span ;
+        match &stmt.kind {
+            StmtKind::Expression(expr) => {
+                self.expression(expr) ;
+                self.emitByte(OP_POP) ;
+            }
+            StmtKind::Print(expr) => {
+                self.expression(expr) ;
+                self.emitByte(OP_PRINT) ;
+            }
+            StmtKind::Block(statements) => {
+                self.beginScope() ;
+                for s in statements {
+                    self.statement(s) ;
+                }
+                self.endScope() ;
+            }
+            StmtKind::Let { name, initializer } => self.letDeclaration(name, initializer),
+            StmtKind::If { condition, then_branch, else_branch } => {
+                self.ifStatement(condition, then_branch, else_branch) ;
+            }
+            StmtKind::Loop { body } => self.loopStatement(body),
+            StmtKind::While { condition, body } => self.whileStatement(condition, body),
+            StmtKind::ForRange { name, start, end, body } => self.forRangeStatement(name, start, end, body),
+            StmtKind::ForIn { name, iterable, body } => self.forInStatement(name, iterable, body),
+            StmtKind::Break => self.breakStatement(),
+            StmtKind::Continue => self.continueStatement(),
+            StmtKind::Fn { name, params, body } => self.fnDeclaration(name, params, body),
+            StmtKind::Class { name, superclass, methods } => self.classDeclaration(name, superclass, methods),
+            StmtKind::Return(value) => self.returnStatement(value),
+            StmtKind::Module(_) => {}
+            // Runs the module unless it has already been imported
+            StmtKind::Import(name) => {
+                let function = self.modules[name.as_str()] ;
+                let constant = self.makeConstant(Value::from(function)) ;
+                self.emitBytes(OP_IMPORT, constant) ;
+                self.emitByte(OP_POP) ;
+            }
+        }
+    }
+
+    /**
+    Compiles the top level of a module into a function of its own, which
+    runs when the module is first imported
+    */
+    pub fn module(&mut self, module: &Module) {
+        let state = std::mem::replace(&mut self.current, FunctionState::new(&module.name, FN_FUNCTION)) ;
+        self.module = Some(module.name.clone()) ;
+        self.exports = module.exports.clone() ;
+
+        for s in module.program.iter() {
+            self.statement(s) ;
+        }
+        self.emitByte(OP_NIL) ;
+        self.emitReturn() ;
+
+        self.module = None ;
+        self.exports.clear() ;
+        let compiled = std::mem::replace(&mut self.current, state) ;
+        let function = self.allocFunction(compiled).0 ;
+        self.modules.insert(module.name.clone(), function) ;
+    }
+
+    /* Functions */
+
+    /**
+    A local function is declared before its body is compiled so that it can
+    call itself; the closure pushed afterwards lands in that slot.
+    */
+    fn fnDeclaration(&mut self, name: &str, params: &[String], body: &[Stmt]) {
+        if self.current.scopeDepth > 0 {
+            self.addLocal(name) ;
+        }
+
+        self.closure(name, FN_FUNCTION, params, body) ;
+
+        if self.current.scopeDepth == 0 {
+            let global = self.resolveGlobal(name) ;
+            self.emitBytes(OP_DEFINE_GLOBAL, global) ;
+        }
+    }
+
+    /**
+    The class is created and bound to its name first, then loaded again so
+    that each method can be attached to it in turn. A superclass is kept in
+    a local called super in a scope around the methods, so that they
+    capture it like any other variable.
+    */
+    fn classDeclaration(&mut self, name: &str, superclass: &Option<Expr>, methods: &[Stmt]) {
+        let nameConstant = self.identifierConstant(name) ;
+        if self.current.scopeDepth > 0 {
+            self.addLocal(name) ;
+        }
+        self.emitBytes(OP_CLASS, nameConstant) ;
+        if self.current.scopeDepth == 0 {
+            let global = self.resolveGlobal(name) ;
+            self.emitBytes(OP_DEFINE_GLOBAL, global) ;
+        }
+
+        if let Some(superclass) = superclass {
+            self.expression(superclass) ;
+            self.beginScope() ;
+            self.addLocal("super") ;
+            self.namedVariable(name, None) ;
+            // Copies the inherited methods down into the class
+            self.emitByte(OP_INHERIT) ;
+        }
+
+        self.namedVariable(name, None) ;
+        for method in methods {
+            if let StmtKind::Fn { name, params, body } = &method.kind {
+                self.span = method.span ;
+                let kind = if name == "init" { FN_INITIALIZER } else { FN_METHOD } ;
+                self.closure(name, kind, params, body) ;
+                let constant = self.identifierConstant(name) ;
+                self.emitBytes(OP_METHOD, constant) ;
+            }
+        }
+        self.emitByte(OP_POP) ;
+
+        if superclass.is_some() {
+            self.endScope() ;
+        }
+    }
+
+    // Compiles a function and emits the instruction creating its closure
+    fn closure(&mut self, name: &str, kind: FunctionKind, params: &[String], body: &[Stmt]) {
+        let (function, upvalues) = self.function(name, kind, params, body) ;
+        let constant = self.makeConstant(Value::from(function)) ;
+        self.emitBytes(OP_CLOSURE, constant) ;
+        for upvalue in upvalues {
+            self.current.chunk.writeChunk(upvalue.isLocal as u8, self.span) ;
+            self.emitOperand(upvalue.index) ;
+        }
+    }
+
+    // Compiles the body into a chunk of its own and allocates the function
+    // object. Also returns what the closure has to capture.
+    fn function(&mut self, name: &str, kind: FunctionKind, params: &[String], body: &[Stmt]) -> (ObjRef, Vec<UpvalueRef>) {
+        let span = self.span ;
+        let state = std::mem::replace(&mut self.current, FunctionState::new(name, kind)) ;
+        self.enclosing.push(state) ;
+
+        self.beginScope() ;
+        self.current.arity = params.len() ;
+        for param in params {
+            self.addLocal(param) ;
+        }
+        for s in body {
+            self.statement(s) ;
+        }
+        // Falling off the end returns nil, or the instance from an initializer
+        self.emitImplicitReturn() ;
+
+        let state = self.enclosing.pop().unwrap() ;
+        let compiled = std::mem::replace(&mut self.current, state) ;
+        self.span = span ;
+        return self.allocFunction(compiled) ;
+    }
+
+    fn allocFunction(&mut self, compiled: FunctionState) -> (ObjRef, Vec<UpvalueRef>) {
+        let function = self.heap.alloc(Obj::FUNCTION(Function {
+            name: compiled.name,
+            arity: compiled.arity,
+            upvalueCount: compiled.upvalues.len(),
+            chunk: Rc::new(compiled.chunk),
+        })) ;
+        self.functions.push(function) ;
+        return (function, compiled.upvalues) ;
+    }
+
+    fn returnStatement(&mut self, value: &Option<Expr>) {
+        match value {
+            Some(expr) => {
+                self.expression(expr) ;
+                self.emitReturn() ;
+            }
+            None => self.emitImplicitReturn(),
+        }
+    }
+
+    fn emitImplicitReturn(&mut self) {
+        if self.current.kind == FN_INITIALIZER {
+            self.emitBytes(OP_GET_LOCAL, 0) ;
+        } else {
+            self.emitByte(OP_NIL) ;
+        }
+        self.emitReturn() ;
+    }
+
+    /**
+    The condition is left on the stack by OP_JUMP_IF_FALSE, so each branch
+    starts by popping it
+    */
+    fn ifStatement(&mut self, condition: &Expr, then_branch: &[Stmt], else_branch: &Option<Box<Stmt>>) {
+        self.expression(condition) ;
+        let thenJump = self.emitJump(OP_JUMP_IF_FALSE) ;
+        self.emitByte(OP_POP) ;
+
+        self.beginScope() ;
+        for s in then_branch {
+            self.statement(s) ;
+        }
+        self.endScope() ;
+
+        let elseJump = self.emitJump(OP_JUMP) ;
+        self.patchJump(thenJump) ;
+        self.emitByte(OP_POP) ;
+
+        if let Some(stmt) = else_branch {
+            self.statement(stmt) ;
+        }
+        self.patchJump(elseJump) ;
+    }
+
+    /* Loops */
+
+    fn beginLoop(&mut self, continueTarget: Option<usize>) {
+        self.current.loops.push(LoopContext {
+            localCount: self.current.locals.len(),
+            continueTarget,
+            continueJumps: Vec::new(),
+            breakJumps: Vec::new(),
+        }) ;
+    }
+
+    // Patches the pending breaks to land on the current end of the code
+    fn endLoop(&mut self) {
+        if let Some(context) = self.current.loops.pop() {
+            for jump in context.breakJumps {
+                self.patchJump(jump) ;
+            }
+        }
+    }
+
+    fn loopBody(&mut self, body: &[Stmt]) {
+        self.beginScope() ;
+        for s in body {
+            self.statement(s) ;
+        }
+        self.endScope() ;
+    }
+
+    // Pop the body's locals without forgetting them, since the code after
+    // the break or continue is still inside their scope
+    fn popLoopLocals(&mut self) {
+        let localCount = match self.current.loops.last() {
+            Some(context) => context.localCount,
+            None => return,
+        } ;
+        for slot in localCount..self.current.locals.len() {
+            let op = if self.current.locals[slot].isCaptured { OP_CLOSE_UPVALUE } else { OP_POP } ;
+            self.emitByte(op) ;
+        }
+    }
+
+    fn breakStatement(&mut self) {
+        if self.current.loops.is_empty() {
+            self.error(E_MISPLACED, "Can't use 'break' outside of a loop.") ;
+            return ;
+        }
+        self.popLoopLocals() ;
+        let jump = self.emitJump(OP_JUMP) ;
+        self.current.loops.last_mut().unwrap().breakJumps.push(jump) ;
+    }
+
+    fn continueStatement(&mut self) {
+        if self.current.loops.is_empty() {
+            self.error(E_MISPLACED, "Can't use 'continue' outside of a loop.") ;
+            return ;
+        }
+        self.popLoopLocals() ;
+        match self.current.loops.last().unwrap().continueTarget {
+            Some(target) => self.emitLoop(target),
+            None => {
+                let jump = self.emitJump(OP_JUMP) ;
+                self.current.loops.last_mut().unwrap().continueJumps.push(jump) ;
+            }
+        }
+    }
+
+    fn loopStatement(&mut self, body: &[Stmt]) {
+        let start = self.current.chunk.code.len() ;
+        self.beginLoop(Some(start)) ;
+        self.loopBody(body) ;
+        self.emitLoop(start) ;
+        self.endLoop() ;
+    }
+
+    fn whileStatement(&mut self, condition: &Expr, body: &[Stmt]) {
+        let start = self.current.chunk.code.len() ;
+        self.expression(condition) ;
+        let exitJump = self.emitJump(OP_JUMP_IF_FALSE) ;
+        self.emitByte(OP_POP) ;
+
+        self.beginLoop(Some(start)) ;
+        self.loopBody(body) ;
+        self.emitLoop(start) ;
+
+        self.patchJump(exitJump) ;
+        self.emitByte(OP_POP) ;
+        // Breaks land after the condition has been popped
+        self.endLoop() ;
+    }
+
+    /**
+    for x in start..end is compiled with two locals in a scope around the
+    loop: the loop variable and the end bound, which is evaluated once.
+    Each iteration tests x < end, runs the body and then increments x.
+    */
+    fn forRangeStatement(&mut self, name: &str, start: &Expr, end: &Expr, body: &[Stmt]) {
+        self.beginScope() ;
+        self.expression(start) ;
+        self.addLocal(name) ;
+        let var = (self.current.locals.len() - 1) as u16 ;
+        self.expression(end) ;
+        // Not a valid identifier, so scripts can't refer to it
+        self.addLocal("..end") ;
+        let bound = (self.current.locals.len() - 1) as u16 ;
+
+        let loopStart = self.current.chunk.code.len() ;
+        self.emitBytes(OP_GET_LOCAL, var) ;
+        self.emitBytes(OP_GET_LOCAL, bound) ;
+        self.emitByte(OP_LESS) ;
+        let exitJump = self.emitJump(OP_JUMP_IF_FALSE) ;
+        self.emitByte(OP_POP) ;
+
+        self.beginLoop(None) ;
+        self.loopBody(body) ;
+
+        // continue lands on the increment
+        let continueJumps = std::mem::take(&mut self.current.loops.last_mut().unwrap().continueJumps) ;
+        for jump in continueJumps {
+            self.patchJump(jump) ;
+        }
+        let increment = match start.ty {
+            Some(VAL_INTEGER) => OP_IADD,
+            _ => OP_ADD,
+        } ;
+        self.emitBytes(OP_GET_LOCAL, var) ;
+        self.emitConstant(Value::from(1i64)) ;
+        self.emitByte(increment) ;
+        self.emitBytes(OP_SET_LOCAL, var) ;
+        self.emitByte(OP_POP) ;
+        self.emitLoop(loopStart) ;
+
+        self.patchJump(exitJump) ;
+        self.emitByte(OP_POP) ;
+        self.endLoop() ;
+        self.endScope() ;
+    }
+
+    /**
+    for x in collection keeps the collection and a position in hidden
+    locals next to x. Each iteration stops once the position reaches the
+    collection's length, otherwise loads the item at that position into x.
+    */
+    fn forInStatement(&mut self, name: &str, iterable: &Expr, body: &[Stmt]) {
+        self.beginScope() ;
+        self.expression(iterable) ;
+        self.addLocal("..collection") ;
+        let collection = (self.current.locals.len() - 1) as u16 ;
+        self.emitConstant(Value::from(0i64)) ;
+        self.addLocal("..position") ;
+        let position = (self.current.locals.len() - 1) as u16 ;
+        self.emitByte(OP_NIL) ;
+        self.addLocal(name) ;
+        let var = (self.current.locals.len() - 1) as u16 ;
+
+        let loopStart = self.current.chunk.code.len() ;
+        self.emitBytes(OP_GET_LOCAL, position) ;
+        self.emitBytes(OP_GET_LOCAL, collection) ;
+        self.emitByte(OP_LEN) ;
+        self.emitByte(OP_LESS) ;
+        let exitJump = self.emitJump(OP_JUMP_IF_FALSE) ;
+        self.emitByte(OP_POP) ;
+
+        self.emitBytes(OP_GET_LOCAL, collection) ;
+        self.emitBytes(OP_GET_LOCAL, position) ;
+        self.emitByte(OP_ITER_ITEM) ;
+        self.emitBytes(OP_SET_LOCAL, var) ;
+        self.emitByte(OP_POP) ;
+
+        self.beginLoop(None) ;
+        self.loopBody(body) ;
+
+        // continue lands on the increment
+        let continueJumps = std::mem::take(&mut self.current.loops.last_mut().unwrap().continueJumps) ;
+        for jump in continueJumps {
+            self.patchJump(jump) ;
+        }
+        self.emitBytes(OP_GET_LOCAL, position) ;
+        self.emitConstant(Value::from(1i64)) ;
+        self.emitByte(OP_IADD) ;
+        self.emitBytes(OP_SET_LOCAL, position) ;
+        self.emitByte(OP_POP) ;
+        self.emitLoop(loopStart) ;
+
+        self.patchJump(exitJump) ;
+        self.emitByte(OP_POP) ;
+        self.endLoop() ;
+        self.endScope() ;
+    }
+
+    /* Expressions */
+
+    fn expression(&mut self, expr: &Expr) {
+        self.span = expr.span ;
+        match &expr.kind {
+            ExprKind::Literal(value) => self.literal(*value),
+            ExprKind::String(value) => {
+                let string = self.heap.intern(value) ;
+                self.emitConstant(Value::from(string)) ;
+            }
+            ExprKind::Grouping(inner) => self.expression(inner),
+            ExprKind::Unary { op, right } => {
+                self.expression(right) ;
+                self.span = expr.span ;
+                match op {
+                    U_NEGATE => self.emitByte(OP_NEGATE),
+                    U_NOT => self.emitByte(OP_NOT),
+                }
+            }
+            ExprKind::Binary { op, left, right } => {
+                // The type checker has already made sure this resolves
+                let valtypes = (left.ty.unwrap_or(VAL_NIL), right.ty.unwrap_or(VAL_NIL)) ;
+                let typed = match Binop::new(*op, valtypes).resolve() {
+                    Some(typed) => typed,
+                    None => {
+                        self.error(E_TYPE, format!("Unresolved operator '{}'.", op.symbol()).as_str()) ;
+                        return ;
+                    }
+                } ;
+
+                self.expression(left) ;
+                if typed.promote_left {
+                    self.emitByte(OP_ITOD) ;
+                }
+                self.expression(right) ;
+                if typed.promote_right {
+                    self.emitByte(OP_ITOD) ;
+                }
+                self.span = expr.span ;
+
+                // Emit the operator instruction(s).
+                for op in typed.ops {
+                    self.emitByte(*op) ;
+                }
+            }
+            ExprKind::Logical { op, left, right } => self.logical(*op, left, right),
+            ExprKind::Variable(name) => self.namedVariable(name, None),
+            ExprKind::Qualified { module, name } => {
+                let global = self.resolveQualified(format!("{}::{}", module, name).as_str()) ;
+                self.emitBytes(OP_GET_GLOBAL, global) ;
+            }
+            ExprKind::Assign { name, value } => self.namedVariable(name, Some(value)),
+            ExprKind::Array(elements) => {
+                if elements.len() > u16::MAX as usize {
+                    self.error(E_LIMIT, "Too many elements in array literal.") ;
+                    return ;
+                }
+                for element in elements {
+                    self.expression(element) ;
+                }
+                self.span = expr.span ;
+                self.emitBytes(OP_BUILD_ARRAY, elements.len() as u16) ;
+            }
+            ExprKind::Interpolation(parts) => {
+                if parts.len() > u16::MAX as usize {
+                    self.error(E_LIMIT, "Too many parts in interpolated string.") ;
+                    return ;
+                }
+                for part in parts {
+                    self.expression(part) ;
+                }
+                self.span = expr.span ;
+                self.emitBytes(OP_BUILD_STRING, parts.len() as u16) ;
+            }
+            ExprKind::Map(entries) => {
+                if entries.len() > u16::MAX as usize {
+                    self.error(E_LIMIT, "Too many entries in map literal.") ;
+                    return ;
+                }
+                for (key, value) in entries {
+                    self.expression(key) ;
+                    self.expression(value) ;
+                }
+                self.span = expr.span ;
+                self.emitBytes(OP_BUILD_MAP, entries.len() as u16) ;
+            }
+            ExprKind::Index { object, index } => {
+                self.expression(object) ;
+                self.expression(index) ;
+                self.span = expr.span ;
+                self.emitByte(OP_INDEX_GET) ;
+            }
+            ExprKind::IndexSet { object, index, value } => {
+                self.expression(object) ;
+                self.expression(index) ;
+                self.expression(value) ;
+                self.span = expr.span ;
+                self.emitByte(OP_INDEX_SET) ;
+            }
+            ExprKind::Slice { object, start, end } => {
+                self.expression(object) ;
+                // nil stands in for a missing bound
+                for bound in [start, end] {
+                    match bound {
+                        Some(bound) => self.expression(bound),
+                        None => self.emitByte(OP_NIL),
+                    }
+                }
+                self.span = expr.span ;
+                self.emitByte(OP_SLICE) ;
+            }
+            ExprKind::Get { object, name } => {
+                self.expression(object) ;
+                self.span = expr.span ;
+                let constant = self.identifierConstant(name) ;
+                self.emitBytes(OP_GET_PROPERTY, constant) ;
+            }
+            ExprKind::Set { object, name, value } => {
+                self.expression(object) ;
+                self.expression(value) ;
+                self.span = expr.span ;
+                let constant = self.identifierConstant(name) ;
+                self.emitBytes(OP_SET_PROPERTY, constant) ;
+            }
+            ExprKind::This => self.namedVariable("this", None),
+            ExprKind::Super { method } => {
+                self.namedVariable("this", None) ;
+                self.namedVariable("super", None) ;
+                self.span = expr.span ;
+                let constant = self.identifierConstant(method) ;
+                self.emitBytes(OP_GET_SUPER, constant) ;
+            }
+            // Calling a method straight away skips creating a bound method
+            ExprKind::Call { callee, args } => if let ExprKind::Get { object, name } = &callee.kind {
+                self.expression(object) ;
+                for arg in args {
+                    self.expression(arg) ;
+                }
+                self.span = expr.span ;
+                let constant = self.identifierConstant(name) ;
+                self.emitBytes(OP_INVOKE, constant) ;
+                self.emitOperand(args.len() as u16) ;
+            } else if let ExprKind::Super { method } = &callee.kind {
+                self.namedVariable("this", None) ;
+                for arg in args {
+                    self.expression(arg) ;
+                }
+                self.namedVariable("super", None) ;
+                self.span = expr.span ;
+                let constant = self.identifierConstant(method) ;
+                self.emitBytes(OP_SUPER_INVOKE, constant) ;
+                self.emitOperand(args.len() as u16) ;
+            } else {
+                self.expression(callee) ;
+                for arg in args {
+                    self.expression(arg) ;
+                }
+                self.span = expr.span ;
+                self.emitBytes(OP_CALL, args.len() as u16) ;
+            }
+        }
+    }
+
+    /**
+    The left operand stays on the stack as the result when it decides the
+    outcome: false for and, true for or. Otherwise it is popped and the
+    right operand is evaluated in its place.
+    */
+    fn logical(&mut self, op: LogicalOp, left: &Expr, right: &Expr) {
+        self.expression(left) ;
+        let endJump = match op {
+            L_AND => self.emitJump(OP_JUMP_IF_FALSE),
+            L_OR => {
+                let elseJump = self.emitJump(OP_JUMP_IF_FALSE) ;
+                let endJump = self.emitJump(OP_JUMP) ;
+                self.patchJump(elseJump) ;
+                endJump
+            }
+        } ;
+        self.emitByte(OP_POP) ;
+        self.expression(right) ;
+        self.patchJump(endJump) ;
+    }
+
+    fn literal(&mut self, value: Value) {
+        match value {
+            Value::NIL => self.emitByte(OP_NIL),
+            Value::BOOL(true) => self.emitByte(OP_TRUE),
+            Value::BOOL(false) => self.emitByte(OP_FALSE),
+            _ => self.emitConstant(value),
+        }
+    }
+
+    /* Error management*/
+
+    fn error(&mut self, code: ErrorCode, message: &str) {
+        self.diagnostics.push(Diagnostic::error(code, message, self.span)) ;
+    }
+
+}
+
+/**
+Lower a parsed program, and the modules it imports, into bytecode. Every
+function ends up in the heap; the one returned is the top level script.
+*/
+pub fn generate(program: &[Stmt], modules: &[Module], heap: &mut Heap) -> Result<ObjRef,Vec<Diagnostic>> {
+    return generateAfter(program, modules, heap, &mut Vec::new(), &mut HashMap::new()) ;
+}
+
+/**
+generate, for a program compiled after others whose globals were given
+the slots in globals and whose modules compiled to the functions in
+compiled. Both are brought up to date when it succeeds; the modules in
+compiled aren't compiled again.
+*/
+pub fn generateAfter(program: &[Stmt], modules: &[Module], heap: &mut Heap,
+                     globals: &mut Vec<String>, compiled: &mut HashMap<String, ObjRef>) -> Result<ObjRef,Vec<Diagnostic>> {
+    let mut codegen = CodeGen::new(heap) ;
+    codegen.globals = globals.clone() ;
+    codegen.modules = compiled.clone() ;
+    for module in modules.iter().filter(|module| !compiled.contains_key(&module.name)) {
+        codegen.module(module) ;
+    }
+    codegen.program(program) ;
+
+    if !codegen.diagnostics.is_empty() {
+        return Result::Err(codegen.diagnostics);
+    }
+    compiled.clone_from(&codegen.modules) ;
+    globals.clone_from(&codegen.globals) ;
+
+    // The global names are only complete now, so hand every chunk a copy
+    // for error messages and disassembly
+    for fref in codegen.functions.iter() {
+        codegen.heap.setGlobalNames(*fref, &codegen.globals) ;
+    }
+    let mut chunk = codegen.current.chunk ;
+    chunk.globals = codegen.globals ;
+    return Result::Ok(codegen.heap.alloc(Obj::FUNCTION(Function {
+        name: String::new(),
+        arity: 0,
+        upvalueCount: 0,
+        chunk: Rc::new(chunk),
+    })));
+}
+
+/* =============== Unit tests ================= */
+
+#[cfg(test)]
+mod tests {
+
+    use crate::ast::Span ;
+    use crate::chunk::Chunk ;
+    use crate::compiler::* ;
+    use crate::object::Heap ;
+    use crate::opcodes::OpCode ;
+    use crate::opcodes::OpCode::* ;
+    use crate::value::* ;
+
+    use std::rc::Rc ;
+
+    fn script(source: &str) -> Rc<Chunk> {
+        let mut heap = Heap::new() ;
+        let fref = compile(source.to_string(), &mut heap).ok().unwrap() ;
+        return heap.function(fref).unwrap().chunk.clone() ;
+    }
+
+    fn decode(heap: &Heap, chunk: &Chunk) -> Vec<OpCode> {
+        let mut ops = Vec::new() ;
+        let mut i = 0 ;
+        while i < chunk.code.len() {
+            let op = OpCode::from_byte(chunk.code[i]) ;
+            i += match op {
+                OP_CONSTANT | OP_DEFINE_GLOBAL | OP_GET_GLOBAL | OP_SET_GLOBAL
+                | OP_GET_LOCAL | OP_SET_LOCAL | OP_JUMP | OP_JUMP_IF_FALSE | OP_LOOP
+                | OP_CALL | OP_GET_UPVALUE | OP_SET_UPVALUE | OP_BUILD_ARRAY | OP_BUILD_MAP | OP_BUILD_STRING
+                | OP_CLASS | OP_METHOD | OP_GET_PROPERTY | OP_SET_PROPERTY | OP_GET_SUPER
+                | OP_IMPORT => 3,
+                OP_INVOKE | OP_SUPER_INVOKE => 5,
+                OP_CLOSURE => {
+                    let constant = u16::from_le_bytes([chunk.code[i+1], chunk.code[i+2]]) ;
+                    let function = ObjRef::try_from(chunk.constants[constant as usize]).unwrap() ;
+                    3 + 3 * heap.function(function).unwrap().upvalueCount
+                }
+                _ => 1
+            } ;
+            ops.push(op) ;
+        }
+        return ops ;
+    }
+
+    fn ops(source: &str) -> Vec<OpCode> {
+        return function_ops(source, "") ;
+    }
+
+    // The instructions of the function called name, "" being the script
+    fn function_ops(source: &str, name: &str) -> Vec<OpCode> {
+        let mut heap = Heap::new() ;
+        let script = compile(source.to_string(), &mut heap).ok().unwrap() ;
+        let mut i = 0 ;
+        while i <= script.0 {
+            if let Some(function) = heap.function(ObjRef(i)) {
+                if function.name == name {
+                    return decode(&heap, &function.chunk) ;
+                }
+            }
+            i += 1 ;
+        }
+        panic!("No function called '{}'", name) ;
+    }
+
+    #[test]
+    fn emits_postfix_order() {
+        assert_eq!(ops("1 + 2 * 3"),
+                   vec![OP_CONSTANT, OP_CONSTANT, OP_CONSTANT, OP_IMUL, OP_IADD, OP_RETURN]) ;
+    }
+
+    #[test]
+    fn emits_interpolation() {
+        assert_eq!(ops("\"a ${1 + 2} b ${true}\""),
+                   vec![OP_CONSTANT, OP_CONSTANT, OP_CONSTANT, OP_IADD, OP_CONSTANT, OP_TRUE,
+                        OP_BUILD_STRING, OP_RETURN]) ;
+        let chunk = script("\"${1}\"") ;
+        assert_eq!(OpCode::from_byte(chunk.code[3]), OP_BUILD_STRING) ;
+        assert_eq!(&chunk.code[4..6], &[1, 0]) ;
+    }
+
+    #[test]
+    fn emits_typed_arithmetic() {
+        assert_eq!(ops("1.5 * 2.0"),
+                   vec![OP_CONSTANT, OP_CONSTANT, OP_DMUL, OP_RETURN]) ;
+        assert_eq!(ops("1 - 2.0"),
+                   vec![OP_CONSTANT, OP_ITOD, OP_CONSTANT, OP_DSUB, OP_RETURN]) ;
+        assert_eq!(ops("1 >= 2"),
+                   vec![OP_CONSTANT, OP_CONSTANT, OP_GREATER_EQUAL, OP_RETURN]) ;
+        assert_eq!(ops("1 == 2.0"),
+                   vec![OP_CONSTANT, OP_ITOD, OP_CONSTANT, OP_EQUAL, OP_RETURN]) ;
+    }
+
+    #[test]
+    fn emits_globals_and_locals() {
+        assert_eq!(ops("let x = 1; x = 2"),
+                   vec![OP_CONSTANT, OP_DEFINE_GLOBAL, OP_CONSTANT, OP_SET_GLOBAL, OP_RETURN]) ;
+        assert_eq!(ops("{ let y = 1; y }"),
+                   vec![OP_CONSTANT, OP_GET_LOCAL, OP_POP, OP_POP, OP_NIL, OP_RETURN]) ;
+    }
+
+    #[test]
+    fn emits_if_else_jumps() {
+        assert_eq!(ops("if true { 1 } else { 2 }"),
+                   vec![OP_TRUE, OP_JUMP_IF_FALSE, OP_POP, OP_CONSTANT, OP_POP, OP_JUMP,
+                        OP_POP, OP_CONSTANT, OP_POP, OP_NIL, OP_RETURN]) ;
+
+        let chunk = script("if true { 1 }") ;
+        // OP_JUMP_IF_FALSE skips the then branch and the OP_JUMP over the else
+        assert_eq!(&chunk.code[2..3], &[8]) ;
+    }
+
+    #[test]
+    fn emits_short_circuit_jumps() {
+        assert_eq!(ops("true and false"),
+                   vec![OP_TRUE, OP_JUMP_IF_FALSE, OP_POP, OP_FALSE, OP_RETURN]) ;
+        assert_eq!(ops("true or false"),
+                   vec![OP_TRUE, OP_JUMP_IF_FALSE, OP_JUMP, OP_POP, OP_FALSE, OP_RETURN]) ;
+        assert_eq!(ops("!true"), vec![OP_TRUE, OP_NOT, OP_RETURN]) ;
+    }
+
+    #[test]
+    fn emits_loop_backward_jump() {
+        assert_eq!(ops("loop { break }"),
+                   vec![OP_JUMP, OP_LOOP, OP_NIL, OP_RETURN]) ;
+
+        let chunk = script("loop { }") ;
+        // Jumps back over itself to the start of the body
+        assert_eq!(&chunk.code[0..3], &[OpCode::to_byte(&OP_LOOP), 3, 0]) ;
+    }
+
+    #[test]
+    fn emits_functions_and_calls() {
+        assert_eq!(ops("fn f(a) { return a } f(1)"),
+                   vec![OP_CLOSURE, OP_DEFINE_GLOBAL, OP_GET_GLOBAL, OP_CONSTANT, OP_CALL, OP_RETURN]) ;
+
+        let mut heap = Heap::new() ;
+        compile("fn f(a, b) { a + b }".to_string(), &mut heap).ok().unwrap() ;
+        let function = heap.function(crate::value::ObjRef(0)).unwrap() ;
+        assert_eq!(function.arity, 2) ;
+        // Parameters start at slot 1, after the callee
+        assert_eq!(&function.chunk.code[0..3], &[OpCode::to_byte(&OP_GET_LOCAL), 1, 0]) ;
+    }
+
+    #[test]
+    fn emits_upvalues() {
+        let source = "fn outer() { let x = 1; fn inner() { x = x + 1 } return inner }" ;
+        assert_eq!(function_ops(source, "inner"),
+                   vec![OP_GET_UPVALUE, OP_CONSTANT, OP_IADD, OP_SET_UPVALUE, OP_POP, OP_NIL, OP_RETURN]) ;
+        assert_eq!(function_ops(source, "outer"),
+                   vec![OP_CONSTANT, OP_CLOSURE, OP_GET_LOCAL, OP_RETURN, OP_NIL, OP_RETURN]) ;
+
+        // A captured block local is closed instead of popped
+        assert_eq!(ops("{ let x = 1; fn f() { return x } }"),
+                   vec![OP_CONSTANT, OP_CLOSURE, OP_POP, OP_CLOSE_UPVALUE, OP_NIL, OP_RETURN]) ;
+
+        // Variables further out are threaded through the functions in between
+        let source = "fn a() { let x = 1; fn b() { fn c() { return x } } }" ;
+        assert_eq!(function_ops(source, "c"), vec![OP_GET_UPVALUE, OP_RETURN, OP_NIL, OP_RETURN]) ;
+        assert_eq!(function_ops(source, "b"), vec![OP_CLOSURE, OP_NIL, OP_RETURN]) ;
+    }
+
+    #[test]
+    fn emits_classes() {
+        let source = "class A { fn init(x) { this.x = x } fn get() { return this.x } }" ;
+        assert_eq!(ops(source),
+                   vec![OP_CLASS, OP_DEFINE_GLOBAL, OP_GET_GLOBAL, OP_CLOSURE, OP_METHOD,
+                        OP_CLOSURE, OP_METHOD, OP_POP, OP_NIL, OP_RETURN]) ;
+        // An initializer hands back the instance, even from a bare return
+        assert_eq!(function_ops(source, "init"),
+                   vec![OP_GET_LOCAL, OP_GET_LOCAL, OP_SET_PROPERTY, OP_POP, OP_GET_LOCAL, OP_RETURN]) ;
+        assert_eq!(function_ops(source, "get"),
+                   vec![OP_GET_LOCAL, OP_GET_PROPERTY, OP_RETURN, OP_NIL, OP_RETURN]) ;
+        assert_eq!(function_ops("class A { fn init() { return } }", "init"),
+                   vec![OP_GET_LOCAL, OP_RETURN, OP_GET_LOCAL, OP_RETURN]) ;
+
+        // A method called straight away is invoked, anything else goes through a call
+        assert_eq!(ops("let a; a.f(1)"), vec![OP_NIL, OP_DEFINE_GLOBAL, OP_GET_GLOBAL, OP_CONSTANT, OP_INVOKE, OP_RETURN]) ;
+        assert_eq!(ops("let a; a.f"), vec![OP_NIL, OP_DEFINE_GLOBAL, OP_GET_GLOBAL, OP_GET_PROPERTY, OP_RETURN]) ;
+    }
+
+    #[test]
+    fn emits_inheritance() {
+        let source = "class A { } class B < A { fn f() { return super.f(1) + super.g } }" ;
+        assert_eq!(ops(source),
+                   vec![OP_CLASS, OP_DEFINE_GLOBAL, OP_GET_GLOBAL, OP_POP,
+                        OP_CLASS, OP_DEFINE_GLOBAL, OP_GET_GLOBAL, OP_GET_GLOBAL, OP_INHERIT,
+                        OP_GET_GLOBAL, OP_CLOSURE, OP_METHOD, OP_POP, OP_CLOSE_UPVALUE, OP_NIL, OP_RETURN]) ;
+        // super is captured from the scope around the methods
+        assert_eq!(function_ops(source, "f"),
+                   vec![OP_GET_LOCAL, OP_CONSTANT, OP_GET_UPVALUE, OP_SUPER_INVOKE,
+                        OP_GET_LOCAL, OP_GET_UPVALUE, OP_GET_SUPER, OP_ADD, OP_RETURN, OP_NIL, OP_RETURN]) ;
+    }
+
+    #[test]
+    fn emits_print() {
+        assert_eq!(ops("print 1"), vec![OP_CONSTANT, OP_PRINT, OP_NIL, OP_RETURN]) ;
+    }
+
+    #[test]
+    fn emits_string_constants() {
+        assert_eq!(ops("\"a\" + \"b\""), vec![OP_CONSTANT, OP_CONSTANT, OP_CONCAT, OP_RETURN]) ;
+
+        // Equal literals are interned to the same object
+        let chunk = script("\"a\" == \"a\"") ;
+        assert_eq!(chunk.constants[0], chunk.constants[1]) ;
+    }
+
+    #[test]
+    fn emits_array_operations() {
+        assert_eq!(ops("[1, 2]"), vec![OP_CONSTANT, OP_CONSTANT, OP_BUILD_ARRAY, OP_RETURN]) ;
+        assert_eq!(ops("let a = [] a[0] = a[1]"),
+                   vec![OP_BUILD_ARRAY, OP_DEFINE_GLOBAL, OP_GET_GLOBAL, OP_CONSTANT,
+                        OP_GET_GLOBAL, OP_CONSTANT, OP_INDEX_GET, OP_INDEX_SET, OP_RETURN]) ;
+        assert_eq!(ops("[][1..]"), vec![OP_BUILD_ARRAY, OP_CONSTANT, OP_NIL, OP_SLICE, OP_RETURN]) ;
+    }
+
+    #[test]
+    fn emits_maps_and_iteration() {
+        assert_eq!(ops("[{\"a\": 1}]"), vec![OP_CONSTANT, OP_CONSTANT, OP_BUILD_MAP, OP_BUILD_ARRAY, OP_RETURN]) ;
+        assert_eq!(ops("1 in []"), vec![OP_CONSTANT, OP_BUILD_ARRAY, OP_IN, OP_RETURN]) ;
+        assert_eq!(ops("for x in [] { }"),
+                   vec![OP_BUILD_ARRAY, OP_CONSTANT, OP_NIL,
+                        OP_GET_LOCAL, OP_GET_LOCAL, OP_LEN, OP_LESS, OP_JUMP_IF_FALSE, OP_POP,
+                        OP_GET_LOCAL, OP_GET_LOCAL, OP_ITER_ITEM, OP_SET_LOCAL, OP_POP,
+                        OP_GET_LOCAL, OP_CONSTANT, OP_IADD, OP_SET_LOCAL, OP_POP, OP_LOOP,
+                        OP_POP, OP_POP, OP_POP, OP_POP, OP_NIL, OP_RETURN]) ;
+    }
+
+    #[test]
+    fn records_spans() {
+        // Every byte of an instruction points at the token it was made from
+        let chunk = script("1 +\n  2") ;
+        assert_eq!(chunk.spans.len(), chunk.code.len()) ;
+        assert_eq!(OpCode::from_byte(chunk.code[6]), OP_IADD) ;
+        assert_eq!(chunk.spans[0], Span { startByte: 0, endByte: 1, line: 1, column: 1 }) ;
+        assert_eq!(chunk.spans[5], Span { startByte: 6, endByte: 7, line: 2, column: 3 }) ;
+        assert_eq!(chunk.spans[6], Span { startByte: 2, endByte: 3, line: 1, column: 3 }) ;
+        assert_eq!(chunk.line(6), 1) ;
+    }
+
+    #[test]
+    fn pops_intermediate_statements() {
+        assert_eq!(ops("true; nil"),
+                   vec![OP_TRUE, OP_POP, OP_NIL, OP_RETURN]) ;
+    }
+}
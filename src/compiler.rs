@@ -1,32 +1,104 @@
 use crate::scanner::* ;
 use crate::scanner::TokenType::*;
-use crate::chunk::* ;
+use crate::object::Heap ;
 use crate::value::* ;
 use crate::rules::* ;
 use crate::rules::Precedence::* ;
-use crate::debug::* ;
-use crate::opcodes::* ;
-use crate::opcodes::OpCode::* ;
-
+use crate::ast::* ;
+use crate::ast::UnaryOp::* ;
+use crate::ast::LogicalOp::* ;
+use crate::binops::BinopType::* ;
+use crate::codegen::* ;
+use crate::debug::disassembleFunction ;
+use crate::diagnostics::* ;
+use crate::diagnostics::ErrorCode::* ;
+use crate::modules::{loadAfter, Module} ;
+use crate::typechecker::* ;
+
+use std::collections::HashMap;
 use std::io::{self, Write};
-use crate::ast::ast_node::BinaryOp;
-use crate::value::ValueType::VAL_INTEGER;
-use crate::ast::ast_tree;
+use std::path::PathBuf;
+
+// Calls carry their argument count in a u16 operand, but nobody needs more
+pub const MAX_ARGS: usize = 255 ;
+
+// What diagnostics call source that wasn't given a file name
+pub const SCRIPT_NAME: &str = "<script>" ;
+
+/**
+Debug output the compiler can produce along the way. It goes to the writer
+passed to compileWithOptions, and nothing is done for it when it is off.
+*/
+#[derive(Copy, Clone, Default, Debug)]
+pub struct CompilerOptions {
+    // Disassemble the script, and every function in it, once compiled
+    pub printCode: bool,
+    // Show each infix operator as the Pratt parser consumes it
+    pub parserDebug: bool,
+}
+
+/**
+What the scripts compiled one after another in a session, such as the
+lines typed at the repl, share: the modules loaded so far, the globals
+declared and their types, and the slots those globals were given. A
+script that fails to compile leaves it as it was.
+*/
+#[derive(Clone, Default)]
+pub struct Session {
+    modules: Vec<Module>,
+    checker: TypeChecker,
+    globals: Vec<String>,
+    // The function running the top level of each module compiled
+    compiled: HashMap<String, ObjRef>,
+}
 
+impl Session {
+
+    pub fn new() -> Session {
+        return Session::default() ;
+    }
+
+    /**
+    The module functions the session refers to, which the collector has
+    to keep even before they run
+    */
+    pub fn functions(&self) -> impl Iterator<Item = ObjRef> + '_ {
+        return self.compiled.values().copied() ;
+    }
+}
 
 #[derive(Clone)]
 struct Parser {
     current: Token,
     previous: Token,
+    // The token after current, when it had to be looked at early
+    next: Option<Token>,
     hadError: bool,
     panicMode: bool
 }
 
+/**
+The Compiler is the parsing front end: it pulls tokens from the scanner and
+builds the AST with a Pratt parser. Bytecode is emitted afterwards by the
+codegen pass, so nothing here touches a Chunk.
+*/
 pub struct Compiler {
     scanner: Scanner,
     parser: Parser,
-    chunk: Chunk,
-    ast: ast_tree
+    // How many loops enclose the statement being parsed, so that a
+    // stray break or continue can be reported
+    loopDepth: usize,
+    // How many function bodies enclose it, for the same reason with return
+    fnDepth: usize,
+    // One entry per class body enclosing it, true when that class has a
+    // superclass, so that misplaced 'this' and 'super' are caught. Also
+    // whether the innermost function is an initializer.
+    classes: Vec<bool>,
+    inInitializer: bool,
+    // Parser debug output, held until parsing finishes. None unless
+    // CompilerOptions::parserDebug is set.
+    parserLog: Option<String>,
+    diagnostics: Vec<Diagnostic>,
 }
 
 impl Compiler {
@@ -35,6 +107,14 @@ impl Compiler {
         return self.parser.current.toktype == t ;
     }
 
+    // Whether the token after the current one is of type t
+    fn t_checkNext(&mut self, t:TokenType) -> bool {
+        if self.parser.next.is_none() {
+            self.parser.next = Some(self.scanner.scanToken()) ;
+        }
+        return self.parser.next.as_ref().is_some_and(|token| token.toktype == t) ;
+    }
+
     fn t_match(&mut self, t:TokenType) -> bool {
          if self.t_check(t) {
              self.advance() ;
@@ -47,10 +127,16 @@ impl Compiler {
     fn advance(&mut self) {
 
        self.parser.previous = self.parser.current.clone();
-       self.parser.current = self.scanner.scanToken();
-       if self.parser.current.toktype == T_ERROR {
+       loop {
+            self.parser.current = match self.parser.next.take() {
+                Some(token) => token,
+                None => self.scanner.scanToken(),
+            };
+            if self.parser.current.toktype != T_ERROR {
+                break;
+            }
             let msg = self.parser.current.name.clone();
-            self.errorAtCurrent(msg.as_str());
+            self.errorAtCurrent(E_SCAN, msg.as_str());
        }
 
     }
@@ -61,168 +147,526 @@ impl Compiler {
             return;
         }
 
-        self.errorAtCurrent(message);
+        self.errorAtCurrent(E_SYNTAX, message);
     }
 
-    fn endCompiler(&mut self) {
-        if !self.parser.hadError {
-            disassembleChunk(&self.chunk,"code");
-        }
-        self.emitReturn() ;
+    // Span of the token we just consumed
+    fn span(&self) -> Span {
+        return self.parser.previous.span ;
+    }
+
+    /* Expressions and statements */
+    fn expression(&mut self) -> Expr {
+        return self.ParsePrecedence(PREC_ASSIGNMENT) ;
+    }
+
+    // import <name> [;]
+    fn import_module(&mut self) -> Stmt {
+        let span = self.span() ;
+        self.consume(T_IDENTIFIER, "Expect module name after 'import'.") ;
+        let name = self.parser.previous.name.clone() ;
+        self.t_match(T_SEMICOLON) ;
+        return Stmt::new(StmtKind::Import(name), span) ;
+    }
+
+    // module <name> [;]
+    fn declare_module(&mut self) -> Stmt {
+        let span = self.span() ;
+        self.consume(T_IDENTIFIER, "Expect module name after 'module'.") ;
+        let name = self.parser.previous.name.clone() ;
+        self.t_match(T_SEMICOLON) ;
+        return Stmt::new(StmtKind::Module(name), span) ;
+    }
+
+    // if <condition> { ... } [else if ... | else { ... }]
+    fn if_statement(&mut self) -> Stmt {
+        let span = self.span() ;
+        let condition = self.expression() ;
+
+        self.consume(T_LEFT_BRACE, "Expect '{' after if condition.") ;
+        let then_branch = self.block() ;
+
+        let else_branch = if self.t_match(T_ELSE) {
+            if self.t_match(T_IF) {
+                Some(Box::new(self.if_statement()))
+            } else {
+                self.consume(T_LEFT_BRACE, "Expect '{' after else.") ;
+                let span = self.span() ;
+                Some(Box::new(Stmt::new(StmtKind::Block(self.block()), span)))
+            }
+        } else {
+            None
+        } ;
+        return Stmt::new(StmtKind::If { condition, then_branch, else_branch }, span) ;
     }
 
-    //fn double(&mut self) {
-    //    let value:f64 = self.parser.previous.name.parse::<f64>().unwrap() ;
-    //    self.emitConstant(value) ;
-    //}
+    // let <name> [= <expression>] [;]
+    fn declare_variable(&mut self) -> Stmt {
+        self.consume(T_IDENTIFIER, "Expect variable name.") ;
+        let span = self.span() ;
+        let name = self.parser.previous.name.clone() ;
 
-    /* Emit byte combos */
-    fn emitByte(&mut self, op:OpCode) {
-        self.chunk.writeChunk(op.to_byte(), self.parser.previous.line);
+        let initializer = if self.t_match(T_EQUAL) {
+            Some(self.expression())
+        } else {
+            None
+        } ;
+        self.t_match(T_SEMICOLON) ;
+        return Stmt::new(StmtKind::Let { name, initializer }, span) ;
     }
 
-    fn emitBytes(&mut self, op: OpCode, operand: u16) {
-        let bytes = u16::to_le_bytes(operand) ;
-        self.chunk.writeChunk(op.to_byte(), self.parser.previous.line);
-        self.chunk.writeChunk(bytes[0],self.parser.previous.line);
-        self.chunk.writeChunk(bytes[1],self.parser.previous.line);
+    fn loop_body(&mut self) -> Vec<Stmt> {
+        self.consume(T_LEFT_BRACE, "Expect '{' before loop body.") ;
+        self.loopDepth += 1 ;
+        let body = self.block() ;
+        self.loopDepth -= 1 ;
+        return body ;
     }
 
-    fn emitReturn(&mut self) {
-        self.emitByte(OP_RETURN) ;
+    // loop { ... }
+    fn loop_statement(&mut self) -> Stmt {
+        let span = self.span() ;
+        let body = self.loop_body() ;
+        return Stmt::new(StmtKind::Loop { body }, span) ;
     }
 
-    fn emitConstant(&mut self, value: Value) {
-        let operand = self.makeConstant(value) ;
-        self.emitBytes(OP_CONSTANT, operand) ;
+    // for <condition> { ... }
+    // for <name> in <start>..<end> { ... }
+    // for <name> in <array or map> { ... }
+    fn for_statement(&mut self) -> Stmt {
+        let span = self.span() ;
+
+        // <name> in starts a range or for-in loop; anything else is a
+        // condition. Deciding before parsing keeps 'in' out of the
+        // expression, whatever follows it.
+        if self.t_check(T_IDENTIFIER) && self.t_checkNext(T_IN) {
+            self.advance() ;
+            let name = self.parser.previous.name.clone() ;
+            self.advance() ;
+            let iterable = self.expression() ;
+            if self.t_match(T_DOT_DOT) {
+                let end = self.expression() ;
+                let body = self.loop_body() ;
+                return Stmt::new(StmtKind::ForRange { name, start: iterable, end, body }, span) ;
+            }
+            let body = self.loop_body() ;
+            return Stmt::new(StmtKind::ForIn { name, iterable, body }, span) ;
+        }
+
+        let condition = self.expression() ;
+        if let ExprKind::Binary { op: B_IN, left, .. } = &condition.kind {
+            self.errorAt(left.span, E_SYNTAX, "Expect loop variable name before 'in'.") ;
+        }
+        let body = self.loop_body() ;
+        return Stmt::new(StmtKind::While { condition, body }, span) ;
     }
 
-    fn makeConstant(&mut self, value: Value) -> u16 {
-        let constant = self.chunk.addConstant(value) as u16;
-        if constant > u16::MAX {
-            self.error("Too many constants in one chunk.");
-            return 0;
+    // break and continue
+    fn loop_jump(&mut self, kind: StmtKind, keyword: &str) -> Stmt {
+        let span = self.span() ;
+        if self.loopDepth == 0 {
+            self.error(E_MISPLACED, format!("Can't use '{}' outside of a loop.", keyword).as_str()) ;
         }
-        return constant;
+        self.t_match(T_SEMICOLON) ;
+        return Stmt::new(kind, span) ;
     }
 
-    /* Expressions and statements */
-    fn expression(&mut self) {
-        self.ParsePrecedence(PREC_ASSIGNMENT) ;
+    // fn <name>(<params>) { ... }
+    fn declare_function(&mut self) -> Stmt {
+        return self.function(false) ;
     }
 
-    fn import_module(&self) {
-        unimplemented!()
+    // class <name> [< <superclass>] { fn <method>(<params>) { ... } ... }
+    fn declare_class(&mut self) -> Stmt {
+        self.consume(T_IDENTIFIER, "Expect class name.") ;
+        let span = self.span() ;
+        let name = self.parser.previous.name.clone() ;
+
+        let superclass = if self.t_match(T_LESS) {
+            self.consume(T_IDENTIFIER, "Expect superclass name.") ;
+            if self.parser.previous.name == name {
+                self.error(E_SYNTAX, "A class can't inherit from itself.") ;
+            }
+            Some(Expr::new(ExprKind::Variable(self.parser.previous.name.clone()), self.span()))
+        } else {
+            None
+        } ;
+        self.consume(T_LEFT_BRACE, "Expect '{' before class body.") ;
+
+        self.classes.push(superclass.is_some()) ;
+        let mut methods = Vec::new() ;
+        while !self.t_check(T_RIGHT_BRACE) && !self.t_check(T_EOF) {
+            self.consume(T_FN, "Expect 'fn' before method.") ;
+            methods.push(self.function(true)) ;
+        }
+        self.classes.pop() ;
+        self.consume(T_RIGHT_BRACE, "Expect '}' after class body.") ;
+
+        return Stmt::new(StmtKind::Class { name, superclass, methods }, span) ;
+    }
+
+    // The rest of a function or method declaration, after 'fn'
+    fn function(&mut self, method: bool) -> Stmt {
+        self.consume(T_IDENTIFIER, if method { "Expect method name." } else { "Expect function name." }) ;
+        let span = self.span() ;
+        let name = self.parser.previous.name.clone() ;
+
+        self.consume(T_LEFT_PAREN, "Expect '(' after function name.") ;
+        let mut params = Vec::new() ;
+        if !self.t_check(T_RIGHT_PAREN) {
+            loop {
+                if params.len() == MAX_ARGS {
+                    self.errorAtCurrent(E_LIMIT, format!("Can't have more than {} parameters.", MAX_ARGS).as_str()) ;
+                }
+                self.consume(T_IDENTIFIER, "Expect parameter name.") ;
+                params.push(self.parser.previous.name.clone()) ;
+                if !self.t_match(T_COMMA) {
+                    break ;
+                }
+            }
+        }
+        self.consume(T_RIGHT_PAREN, "Expect ')' after parameters.") ;
+        self.consume(T_LEFT_BRACE, "Expect '{' before function body.") ;
+
+        // Loops outside the function don't count inside its body
+        let loopDepth = std::mem::replace(&mut self.loopDepth, 0) ;
+        let inInitializer = std::mem::replace(&mut self.inInitializer, method && name == "init") ;
+        self.fnDepth += 1 ;
+        let body = self.block() ;
+        self.fnDepth -= 1 ;
+        self.inInitializer = inInitializer ;
+        self.loopDepth = loopDepth ;
+
+        return Stmt::new(StmtKind::Fn { name, params, body }, span) ;
     }
 
-    fn declare_module(&self) {
-        unimplemented!()
+    // return [<expression>] [;]
+    fn return_statement(&mut self) -> Stmt {
+        let span = self.span() ;
+        if self.fnDepth == 0 {
+            self.error(E_MISPLACED, "Can't return from top-level code.") ;
+        }
+
+        let value = if self.t_check(T_SEMICOLON) || self.t_check(T_RIGHT_BRACE) || self.t_check(T_EOF) {
+            None
+        } else {
+            if self.inInitializer {
+                self.error(E_MISPLACED, "Can't return a value from an initializer.") ;
+            }
+            Some(self.expression())
+        } ;
+        self.t_match(T_SEMICOLON) ;
+        return Stmt::new(StmtKind::Return(value), span) ;
     }
 
-    fn if_statement(&self) {
-        unimplemented!()
+    // print <expression> [;]
+    fn print_statement(&mut self) -> Stmt {
+        let span = self.span() ;
+        let value = self.expression() ;
+        self.t_match(T_SEMICOLON) ;
+        return Stmt::new(StmtKind::Print(value), span) ;
     }
 
-    fn declare_variable(&self) {
-        unimplemented!()
+    fn block(&mut self) -> Vec<Stmt> {
+        let mut statements = Vec::new() ;
+        while !self.t_check(T_RIGHT_BRACE) && !self.t_check(T_EOF) {
+            statements.push(self.statement()) ;
+            if self.parser.panicMode {
+                self.synchronize() ;
+            }
+        }
+        self.consume(T_RIGHT_BRACE, "Expect '}' after block.") ;
+        return statements ;
     }
 
-    fn expression_statement(&mut self) {
-        self.expression();
+    fn expression_statement(&mut self) -> Stmt {
+        let expr = self.expression();
+        let span = expr.span ;
+        self.t_match(T_SEMICOLON) ;
+        return Stmt::new(StmtKind::Expression(expr), span) ;
     }
 
-    fn statement(&mut self) {
+    fn statement(&mut self) -> Stmt {
 
         if self.t_match(T_MODULE) { self.declare_module()}
         else if self.t_match(T_IMPORT) { self.import_module()}
         else if self.t_match(T_LET) {self.declare_variable()}
+        else if self.t_match(T_FN) {self.declare_function()}
+        else if self.t_match(T_CLASS) {self.declare_class()}
+        else if self.t_match(T_RETURN) {self.return_statement()}
+        else if self.t_match(T_PRINT) {self.print_statement()}
         else if self.t_match(T_IF) {self.if_statement()}
-        else if self.t_match(T_ELSE) {}
-        else if self.t_match(T_FN) {}
-        else if self.t_match(T_RETURN) {}
-        else if self.t_match(T_LOOP) {}
+        else if self.t_match(T_ELSE) {
+            self.error(E_MISPLACED, "'else' without a matching 'if'.") ;
+            self.statement()
+        }
+        else if self.t_match(T_LOOP) {self.loop_statement()}
+        else if self.t_match(T_FOR) {self.for_statement()}
+        else if self.t_match(T_BREAK) {self.loop_jump(StmtKind::Break, "break")}
+        else if self.t_match(T_CONTINUE) {self.loop_jump(StmtKind::Continue, "continue")}
+        else if self.t_match(T_LEFT_BRACE) {
+            let span = self.span() ;
+            let statements = self.block() ;
+            Stmt::new(StmtKind::Block(statements), span)
+        }
         else {self.expression_statement()}
 
     }
 
-    fn evaluate(&mut self) {
-        self.statement() ;
-    }
-
-    pub fn unary(&mut self, _canAssign:bool) {
+    pub fn unary(&mut self, _canAssign:bool) -> Expr {
         let operatorType = self.parser.previous.toktype ;
+        let span = self.span() ;
 
-        self.ParsePrecedence(PREC_UNARY);
+        let right = self.ParsePrecedence(PREC_UNARY);
 
-        match operatorType {
-            T_MINUS=> self.emitByte(OP_NEGATE),
+        let op = match operatorType {
+            T_MINUS=> U_NEGATE,
+            T_BANG => U_NOT,
             _ => panic!("Unknown operator type")
-        }
+        } ;
+        return Expr::new(ExprKind::Unary { op, right: Box::new(right) }, span) ;
     }
 
-    pub fn binary(&mut self, _canAssign:bool) {
+    pub fn binary(&mut self, left: Expr, _canAssign:bool) -> Expr {
 
         let operatorType = self.parser.previous.toktype ;
+        let span = self.span() ;
         let rule = self.GetRule(operatorType) ;
         let rPrec = rule.prec as usize +1 ;
-        self.ParsePrecedence(PREC_LIST[rPrec]) ;
+        let right = self.ParsePrecedence(PREC_LIST[rPrec]) ;
+
+        let op = match operatorType {
+
+            T_PLUS          =>  B_PLUS,
+            T_MINUS         =>  B_MINUS,
+            T_STAR          =>  B_MULT,
+            T_SLASH         =>  B_DIV,
+
+            T_BANG_EQUAL    => B_NOT_EQUAL,
+            T_EQUAL_EQUAL   => B_EQUAL,
+            T_GREATER       => B_GREATER,
+            T_GREATER_EQUAL => B_GREATER_EQUAL,
+            T_LESS          => B_LESS,
+            T_LESS_EQUAL    => B_LESS_EQUAL,
+            T_IN            => B_IN,
+
+            _ =>  panic!("No match on binary OP {:?}", operatorType) // This really shouldn't happen
+        } ;
+        return Expr::new(ExprKind::Binary { op, left: Box::new(left), right: Box::new(right) }, span) ;
+    }
 
-        match operatorType {
-            T_PLUS          =>  {
-                // Check to see what type the expression should be by walking up the tree nodes
+    // The right operand binds tighter than the operator, so a or b and c
+    // groups as a or (b and c)
+    pub fn and_(&mut self, left: Expr, _canAssign:bool) -> Expr {
+        let span = self.span() ;
+        let right = self.ParsePrecedence(PREC_AND) ;
+        return Expr::new(ExprKind::Logical { op: L_AND, left: Box::new(left), right: Box::new(right) }, span) ;
+    }
 
+    pub fn or_(&mut self, left: Expr, _canAssign:bool) -> Expr {
+        let span = self.span() ;
+        let right = self.ParsePrecedence(PREC_AND) ;
+        return Expr::new(ExprKind::Logical { op: L_OR, left: Box::new(left), right: Box::new(right) }, span) ;
+    }
+
+    pub fn call(&mut self, callee: Expr, _canAssign:bool) -> Expr {
+        let span = self.span() ;
+        let mut args = Vec::new() ;
+        if !self.t_check(T_RIGHT_PAREN) {
+            loop {
+                if args.len() == MAX_ARGS {
+                    self.error(E_LIMIT, format!("Can't have more than {} arguments.", MAX_ARGS).as_str()) ;
+                }
+                args.push(self.expression()) ;
+                if !self.t_match(T_COMMA) {
+                    break ;
+                }
             }
-            _ =>  println!("No match on binary OP??") // This really shouldn't happen
         }
+        self.consume(T_RIGHT_PAREN, "Expect ')' after arguments.") ;
+        return Expr::new(ExprKind::Call { callee: Box::new(callee), args }, span) ;
+    }
 
-        // Emit the operator instruction.
-        match operatorType {
+    // <object>.<name> and <object>.<name> = <value>
+    pub fn dot(&mut self, object: Expr, canAssign:bool) -> Expr {
+        let span = self.span() ;
+        self.consume(T_IDENTIFIER, "Expect property name after '.'.") ;
+        let name = self.parser.previous.name.clone() ;
+        let object = Box::new(object) ;
 
-            T_PLUS          =>  self.emitByte(OP_IADD),
-            T_MINUS         =>  self.emitByte(OP_ISUB),
-            T_STAR          =>  self.emitByte(OP_IMUL),
-            T_SLASH         =>  self.emitByte(OP_IDIV),
+        if canAssign && self.t_match(T_EQUAL) {
+            let value = Box::new(self.expression()) ;
+            return Expr::new(ExprKind::Set { object, name, value }, span) ;
+        }
+        return Expr::new(ExprKind::Get { object, name }, span) ;
+    }
 
-            T_BANG_EQUAL    => {self.emitByte(OP_EQUAL); self.emitByte(OP_NOT);},
-            T_EQUAL_EQUAL   => self.emitByte(OP_EQUAL),
-            T_GREATER       => self.emitByte(OP_GREATER),
-            T_GREATER_EQUAL => {self.emitByte(OP_LESS); self.emitByte(OP_NOT);},
-            T_LESS          => self.emitByte(OP_LESS),
-            T_LESS_EQUAL    => {self.emitByte(OP_GREATER); self.emitByte(OP_NOT);},
+    pub fn this(&mut self, _canAssign:bool) -> Expr {
+        if self.classes.is_empty() {
+            self.error(E_MISPLACED, "Can't use 'this' outside of a class.") ;
+        }
+        return Expr::new(ExprKind::This, self.span()) ;
+    }
 
-            _ =>  println!("No match on binary OP??") // This really shouldn't happen
+    // super.<method>
+    pub fn super_(&mut self, _canAssign:bool) -> Expr {
+        let span = self.span() ;
+        match self.classes.last() {
+            None => self.error(E_MISPLACED, "Can't use 'super' outside of a class."),
+            Some(false) => self.error(E_MISPLACED, "Can't use 'super' in a class with no superclass."),
+            Some(true) => {}
         }
+        self.consume(T_DOT, "Expect '.' after 'super'.") ;
+        self.consume(T_IDENTIFIER, "Expect superclass method name.") ;
+        let method = self.parser.previous.name.clone() ;
+        return Expr::new(ExprKind::Super { method }, span) ;
     }
 
-    pub fn grouping(&mut self, _canAssign:bool) {
-        self.expression() ;
-        self.consume(TokenType::T_RIGHT_PAREN, "Expect ')' after expression")
+    // [<expression>, ...]
+    pub fn array(&mut self, _canAssign:bool) -> Expr {
+        let span = self.span() ;
+        let mut elements = Vec::new() ;
+        if !self.t_check(T_RIGHT_BRACKET) {
+            loop {
+                elements.push(self.expression()) ;
+                if !self.t_match(T_COMMA) {
+                    break ;
+                }
+            }
+        }
+        self.consume(T_RIGHT_BRACKET, "Expect ']' after array elements.") ;
+        return Expr::new(ExprKind::Array(elements), span) ;
+    }
+
+    // {<key>: <value>, ...}
+    // Keys are expressions like any other, so {name: 1} uses the value of
+    // the variable name as its key
+    pub fn map(&mut self, _canAssign:bool) -> Expr {
+        let span = self.span() ;
+        let mut entries = Vec::new() ;
+        if !self.t_check(T_RIGHT_BRACE) {
+            loop {
+                let key = self.expression() ;
+                self.consume(T_COLON, "Expect ':' after map key.") ;
+                let value = self.expression() ;
+                entries.push((key, value)) ;
+                if !self.t_match(T_COMMA) {
+                    break ;
+                }
+            }
+        }
+        self.consume(T_RIGHT_BRACE, "Expect '}' after map entries.") ;
+        return Expr::new(ExprKind::Map(entries), span) ;
     }
 
-    pub fn integer(&mut self, _canAssign:bool) {
-        let value = Value::INTEGER(self.parser.previous.name.parse::<i64>().unwrap()) ;
-        //ast_tree.push(value) ;
-        //self.emitConstant(INTEGER_VAL!(value)) ;
+    // <object>[<index>], <object>[<index>] = <value> and <object>[<start>..<end>]
+    pub fn index(&mut self, object: Expr, canAssign:bool) -> Expr {
+        let span = self.span() ;
+        let object = Box::new(object) ;
+
+        if self.t_match(T_DOT_DOT) {
+            return self.slice(object, None, span) ;
+        }
+        let index = Box::new(self.expression()) ;
+        if self.t_match(T_DOT_DOT) {
+            return self.slice(object, Some(index), span) ;
+        }
+        self.consume(T_RIGHT_BRACKET, "Expect ']' after index.") ;
+
+        if canAssign && self.t_match(T_EQUAL) {
+            let value = Box::new(self.expression()) ;
+            return Expr::new(ExprKind::IndexSet { object, index, value }, span) ;
+        }
+        return Expr::new(ExprKind::Index { object, index }, span) ;
     }
 
-    pub fn double(&mut self, _canAssign:bool) {
+    // The rest of a slice, once the '..' has been consumed
+    fn slice(&mut self, object: Box<Expr>, start: Option<Box<Expr>>, span: Span) -> Expr {
+        let end = if self.t_check(T_RIGHT_BRACKET) { None } else { Some(Box::new(self.expression())) } ;
+        self.consume(T_RIGHT_BRACKET, "Expect ']' after slice.") ;
+        return Expr::new(ExprKind::Slice { object, start, end }, span) ;
+    }
+
+    pub fn grouping(&mut self, _canAssign:bool) -> Expr {
+        let span = self.span() ;
+        let expr = self.expression() ;
+        self.consume(TokenType::T_RIGHT_PAREN, "Expect ')' after expression") ;
+        return Expr::new(ExprKind::Grouping(Box::new(expr)), span) ;
+    }
+
+    pub fn integer(&mut self, _canAssign:bool) -> Expr {
+        let value:i64 = match self.parser.previous.name.parse::<i64>() {
+            Ok(v) => v,
+            Err(_) => {
+                self.error(E_LIMIT, "Integer literal out of range.") ;
+                0
+            }
+        } ;
+        return Expr::new(ExprKind::Literal(Value::from(value)), self.span()) ;
+    }
+
+    pub fn double(&mut self, _canAssign:bool) -> Expr {
         let value:f64 = self.parser.previous.name.parse::<f64>().unwrap() ;
-        self.emitConstant(DOUBLE_VAL!(value)) ;
+        return Expr::new(ExprKind::Literal(Value::from(value)), self.span()) ;
+    }
+
+    pub fn string(&mut self, _canAssign:bool) -> Expr {
+        // The scanner has already taken off the quotes and unescaped it
+        let value = self.parser.previous.name.clone() ;
+        return Expr::new(ExprKind::String(value), self.span()) ;
+    }
+
+    // "total: ${count * price}", once the part before the first "${" has
+    // been consumed
+    pub fn interpolation(&mut self, _canAssign:bool) -> Expr {
+        let span = self.span() ;
+        let mut parts = Vec::new() ;
+        loop {
+            if !self.parser.previous.name.is_empty() {
+                parts.push(Expr::new(ExprKind::String(self.parser.previous.name.clone()), self.span())) ;
+            }
+            parts.push(self.expression()) ;
+            if !self.t_match(T_INTERPOLATION) {
+                break ;
+            }
+        }
+        self.consume(T_STRING, "Expect '}' after interpolated expression.") ;
+        if !self.parser.previous.name.is_empty() {
+            parts.push(Expr::new(ExprKind::String(self.parser.previous.name.clone()), self.span())) ;
+        }
+        return Expr::new(ExprKind::Interpolation(parts), span) ;
     }
 
-    pub fn literal(&mut self, _canAssign:bool) {
+    pub fn variable(&mut self, canAssign:bool) -> Expr {
+        let name = self.parser.previous.name.clone() ;
+        let span = self.span() ;
+
+        // Globals of other modules can be read but not assigned
+        if self.t_match(T_DOUBLE_COLON) {
+            self.consume(T_IDENTIFIER, "Expect name after '::'.") ;
+            let member = self.parser.previous.name.clone() ;
+            return Expr::new(ExprKind::Qualified { module: name, name: member }, span) ;
+        }
 
-        match self.parser.previous.toktype {
-            T_FALSE=> self.emitByte(OP_FALSE),
-            T_TRUE => self.emitByte(OP_TRUE),
-            T_NIL => self.emitByte(OP_NIL),
-            _ => self.error("Unknown literal")
+        if canAssign && self.t_match(T_EQUAL) {
+            let value = self.expression() ;
+            return Expr::new(ExprKind::Assign { name, value: Box::new(value) }, span) ;
         }
+        return Expr::new(ExprKind::Variable(name), span) ;
     }
 
-    /* Makes the negation possible by checking that we're only negating logical values */
-    fn isFalse(&mut self, value: Value) -> bool {
-        return IS_NIL!(value) || (IS_BOOL!(value) && !AS_BOOL!(value))
+    pub fn literal(&mut self, _canAssign:bool) -> Expr {
+
+        let value = match self.parser.previous.toktype {
+            T_FALSE=> Value::from(false),
+            T_TRUE => Value::from(true),
+            T_NIL => Value::NIL,
+            _ => {
+                self.error(E_SYNTAX, "Unknown literal") ;
+                Value::NIL
+            }
+        } ;
+        return Expr::new(ExprKind::Literal(value), self.span()) ;
     }
 
     fn GetRule(&self, t:TokenType) -> ParseRule {
@@ -230,109 +674,528 @@ impl Compiler {
         return t.get_rule() ;
     }
 
-    fn ParsePrecedence(&mut self, prec:Precedence) {
+    fn ParsePrecedence(&mut self, prec:Precedence) -> Expr {
 
         self.advance() ;
 
         // This loads the prefix rule which either contains a value such as
         // a variable or literal or a prefix that affects the next value
         let rule = self.GetRule(self.parser.previous.toktype);
-        let prefix = rule.prefix;
 
         // This is an error in that an expression needs to at least begin
         // with a prefix rule
-        if prefix.is_none() {
-            self.error("Expect expression (no prefix)");
-            return ;
-        }
+        let prefix = match rule.prefix {
+            Some(prefix) => prefix,
+            None => {
+                self.error(E_SYNTAX, "Expect expression.");
+                return Expr::new(ExprKind::Literal(Value::NIL), self.span()) ;
+            }
+        } ;
         let canAssign = prec <= PREC_ASSIGNMENT;
-        prefix.unwrap()(self,canAssign);
+        let mut expr = prefix(self,canAssign);
 
         while prec <= (self.GetRule(self.parser.current.toktype)).prec {
             self.advance() ;
-            println!("PREC (after advance): {:?}",self.parser.previous.toktype) ;
+            if let Some(log) = self.parserLog.as_mut() {
+                log.push_str(format!("PREC (after advance): {:?}\n", self.parser.previous.toktype).as_str()) ;
+            }
             let infix = self.GetRule(self.parser.previous.toktype).infix ;
-            if infix.is_some()  {
-                infix.unwrap()(self,canAssign) ;
+            if let Some(infix) = infix {
+                expr = infix(self,expr,canAssign) ;
             }
+        }
 
-            if canAssign && self.t_match(T_EQUAL) {
-               self.error("Invalid assignment target.") ;
-            }
+        // Anything that could have been assigned to was handled by its
+        // prefix rule, so an '=' left over here has nothing valid to its left
+        if canAssign && self.t_match(T_EQUAL) {
+           self.error(E_INVALID_ASSIGNMENT, "Invalid assignment target.") ;
         }
+        return expr ;
     }
 
     /* Error management*/
 
-    fn error(&mut self, message: &str) {
-        self.errorAt(message) ;
+    // An error in the token just consumed
+    fn error(&mut self, code: ErrorCode, message: &str) {
+        let span = self.span() ;
+        self.errorAt(span, code, message) ;
     }
 
-    fn errorAtCurrent(&mut self, message: &str) {
-        self.errorAt(message);
+    // An error in the token about to be consumed
+    fn errorAtCurrent(&mut self, code: ErrorCode, message: &str) {
+        let span = self.parser.current.span ;
+        self.errorAt(span, code, message);
     }
 
-    fn errorAt(&mut self,message: &str) {
+    // Only the first error is reported until the parser has synchronized,
+    // since whatever follows it is likely to be reported wrongly
+    fn errorAt(&mut self, span: Span, code: ErrorCode, message: &str) {
 
         if self.parser.panicMode {return;}
         self.parser.panicMode = true;
 
-        use TokenType::* ;
-        let _ = io::stderr().write_all(format!("[line {}] Error", &self.parser.current.line).as_bytes());
+        self.diagnostics.push(Diagnostic::error(code, message, span)) ;
+        self.parser.hadError = true;
+    }
 
-        if self.parser.current.toktype == T_EOF {
-            let _ = io::stderr().write_all(b" at end");
-        } else if self.parser.current.toktype == T_ERROR {
-            // Nothing.
-        } else {
-            let _ = io::stderr().write_all(format!(" at '{}'", &self.parser.current.name).as_bytes());
-        }
+    // After an error, skip ahead to what looks like the start of the next
+    // statement so that parsing can carry on from there
+    fn synchronize(&mut self) {
+        self.parser.panicMode = false;
 
-        let _ = io::stderr().write_all( format!(": {}\n", message).as_bytes());
-        self.parser.hadError = true;
+        while !self.t_check(T_EOF) {
+            if self.parser.previous.toktype == T_SEMICOLON {
+                return;
+            }
+            match self.parser.current.toktype {
+                T_MODULE | T_IMPORT | T_LET | T_FN | T_CLASS | T_RETURN | T_PRINT
+                | T_IF | T_LOOP | T_FOR | T_BREAK | T_CONTINUE | T_RIGHT_BRACE => return,
+                _ => {}
+            }
+            self.advance() ;
+        }
     }
 
 }
 
-pub fn compile(source: String) -> Result<Chunk,bool> {
+/**
+Parse a whole source file into a list of statements
+*/
+pub fn parse(source: String) -> Result<Vec<Stmt>,Vec<Diagnostic>> {
+    return parseWithOptions(source, &CompilerOptions::default(), &mut io::sink()) ;
+}
+
+/**
+Parse a whole source file, writing any parser debug output to debugOut
+*/
+pub fn parseWithOptions(source: String, options: &CompilerOptions, debugOut: &mut dyn Write) -> Result<Vec<Stmt>,Vec<Diagnostic>> {
 
     let scanner = newScanner(source);
 
     let mut compiler = Compiler {
         scanner,
+        loopDepth: 0,
+        fnDepth: 0,
+        classes: Vec::new(),
+        inInitializer: false,
+        parserLog: if options.parserDebug { Some(String::new()) } else { None },
+        diagnostics: Vec::new(),
         parser: Parser {
             current: Token{
                 name: "Start".to_string(),
                 toktype: TokenType::T_START,
-                line: 0
+                span: Span::default()
             },
             previous: Token{
                 name: "Start".to_string(),
                 toktype: TokenType::T_START,
-                line: 0
+                span: Span::default()
             },
+            next: None,
             hadError: false,
             panicMode: false
         },
-        chunk: newChunk(),
-        ast: ast_tree::new()
     } ;
 
     use TokenType::* ;
+    let mut program = Vec::new() ;
     compiler.advance() ;
-    loop {
-        if compiler.t_match(T_EOF) {
-            compiler.endCompiler() ;
-            break ;
+    while !compiler.t_match(T_EOF) {
+        program.push(compiler.statement());
+        if compiler.parser.panicMode {
+            compiler.synchronize() ;
         }
-        compiler.evaluate();
     }
 
+    if let Some(log) = compiler.parserLog {
+        let _ = debugOut.write_all(log.as_bytes()) ;
+    }
     if compiler.parser.hadError {
-        return Result::Err(false);
+        return Result::Err(compiler.diagnostics);
     }
-    return Result::Ok(compiler.chunk);
+    return Result::Ok(program);
+}
+
+/**
+Compile a source file, looking for the modules it imports in the current
+directory. The functions it declares are allocated in heap and the top
+level script is returned as a function taking no arguments.
+*/
+pub fn compile(source: String, heap: &mut Heap) -> Result<ObjRef,Vec<Diagnostic>> {
+    return compileWithPath(source, heap, &[PathBuf::from(".")]) ;
 }
 
+/**
+Compile a source file, looking for the modules it imports in each
+directory of searchPath in turn. The modules are compiled along with it.
+*/
+pub fn compileWithPath(source: String, heap: &mut Heap, searchPath: &[PathBuf]) -> Result<ObjRef,Vec<Diagnostic>> {
+    return compileWithOptions(source, SCRIPT_NAME, heap, searchPath, &CompilerOptions::default(), &mut io::sink()) ;
+}
+
+/**
+compileWithPath, with the debug output turned on in options written to
+debugOut. file names the source in the diagnostics, unless they come from
+an imported module.
+*/
+pub fn compileWithOptions(source: String, file: &str, heap: &mut Heap, searchPath: &[PathBuf],
+                          options: &CompilerOptions, debugOut: &mut dyn Write) -> Result<ObjRef,Vec<Diagnostic>> {
+    return compileInSession(source, file, heap, searchPath, options, debugOut, &mut Session::new()) ;
+}
+
+/**
+compileWithOptions, for a script that can use the globals and modules of
+the ones compiled before it in session
+*/
+pub fn compileInSession(source: String, file: &str, heap: &mut Heap, searchPath: &[PathBuf],
+                        options: &CompilerOptions, debugOut: &mut dyn Write, session: &mut Session) -> Result<ObjRef,Vec<Diagnostic>> {
+    let inScript = |diagnostics| inFile(diagnostics, file) ;
+    let mut program = parseWithOptions(source, options, debugOut).map_err(inScript)? ;
+    let modules = loadAfter(&program, searchPath, session.modules.clone()).map_err(inScript)? ;
+    let mut checker = session.checker.clone() ;
+    checker.check(&mut program, &modules).map_err(inScript)? ;
+    let script = generateAfter(&program, &modules, heap, &mut session.globals, &mut session.compiled).map_err(inScript)? ;
+    session.modules = modules ;
+    session.checker = checker ;
+    if options.printCode {
+        disassembleFunction(debugOut, heap, script) ;
+    }
+    return Ok(script) ;
+}
+
+/* =============== Unit tests ================= */
+
+#[cfg(test)]
+mod tests {
+
+    use crate::compiler::* ;
+    use crate::value::Value::* ;
+
+    fn expr_of(source: &str) -> Expr {
+        let mut program = parse(source.to_string()).ok().unwrap() ;
+        match program.remove(0).kind {
+            StmtKind::Expression(e) => e,
+            k => panic!("Expected an expression statement, got {:?}", k)
+        }
+    }
+
+    #[test]
+    fn parse_precedence() {
+        // 1 + 2 * 3 should group the multiplication first
+        let expr = expr_of("1 + 2 * 3") ;
+        match expr.kind {
+            ExprKind::Binary { op: B_PLUS, left, right } => {
+                assert_eq!(left.kind, ExprKind::Literal(INTEGER(1))) ;
+                match right.kind {
+                    ExprKind::Binary { op: B_MULT, .. } => {},
+                    k => panic!("Expected multiplication, got {:?}", k)
+                }
+            }
+            k => panic!("Expected addition, got {:?}", k)
+        }
+    }
+
+    #[test]
+    fn parse_unary_and_grouping() {
+        let expr = expr_of("-(2.5)") ;
+        match expr.kind {
+            ExprKind::Unary { op: U_NEGATE, right } => {
+                assert_eq!(right.kind, ExprKind::Grouping(Box::new(
+                    Expr::new(ExprKind::Literal(DOUBLE(2.5)), Span { startByte: 2, endByte: 5, line: 1, column: 3 })))) ;
+            }
+            k => panic!("Expected negation, got {:?}", k)
+        }
+    }
+
+    #[test]
+    fn parse_logical_operators() {
+        // and binds tighter than or, and both looser than comparison
+        let expr = expr_of("!a or b and 1 < 2") ;
+        match expr.kind {
+            ExprKind::Logical { op: L_OR, left, right } => {
+                assert!(matches!(left.kind, ExprKind::Unary { op: U_NOT, .. })) ;
+                match right.kind {
+                    ExprKind::Logical { op: L_AND, right, .. } =>
+                        assert!(matches!(right.kind, ExprKind::Binary { op: B_LESS, .. })),
+                    k => panic!("Expected and, got {:?}", k)
+                }
+            }
+            k => panic!("Expected or, got {:?}", k)
+        }
+    }
+
+    #[test]
+    fn parse_block_and_spans() {
+        let program = parse("{ 1\n 2 }".to_string()).ok().unwrap() ;
+        match &program[0].kind {
+            StmtKind::Block(stmts) => {
+                assert_eq!(stmts.len(), 2) ;
+                assert_eq!(stmts[1].span.line, 2) ;
+            }
+            k => panic!("Expected a block, got {:?}", k)
+        }
+    }
+
+    #[test]
+    fn parse_interpolation() {
+        match expr_of("\"Total: ${count * price}!\"").kind {
+            ExprKind::Interpolation(parts) => {
+                assert_eq!(parts.len(), 3) ;
+                assert!(matches!(&parts[0].kind, ExprKind::String(s) if s == "Total: ")) ;
+                assert!(matches!(parts[1].kind, ExprKind::Binary { .. })) ;
+                assert!(matches!(&parts[2].kind, ExprKind::String(s) if s == "!")) ;
+            }
+            k => panic!("Expected an interpolation, got {:?}", k)
+        }
+        // Empty segments are left out
+        match expr_of("\"${a}${b}\"").kind {
+            ExprKind::Interpolation(parts) => assert_eq!(parts.len(), 2),
+            k => panic!("Expected an interpolation, got {:?}", k)
+        }
+        assert!(parse("\"a ${1\"".to_string()).is_err()) ;
+    }
+
+    #[test]
+    fn parse_byte_spans() {
+        // Columns count characters, offsets count bytes
+        match expr_of("café + \"naïve\"").kind {
+            ExprKind::Binary { left, right, .. } => {
+                assert_eq!(left.span, Span { startByte: 0, endByte: 5, line: 1, column: 1 }) ;
+                assert_eq!(right.span, Span { startByte: 8, endByte: 16, line: 1, column: 8 }) ;
+            }
+            k => panic!("Expected a binary expression, got {:?}", k)
+        }
+    }
+
+    #[test]
+    fn parse_let_and_assignment() {
+        let program = parse("let x = 1; x = x + 1".to_string()).ok().unwrap() ;
+        match &program[0].kind {
+            StmtKind::Let { name, initializer: Some(_) } => assert_eq!(name, "x"),
+            k => panic!("Expected a let, got {:?}", k)
+        }
+        match &program[1].kind {
+            StmtKind::Expression(Expr { kind: ExprKind::Assign { name, .. }, .. }) => assert_eq!(name, "x"),
+            k => panic!("Expected an assignment, got {:?}", k)
+        }
+    }
+
+    #[test]
+    fn parse_invalid_assignment_target() {
+        assert!(parse("let a = 1; let b = 2; a + b = 3".to_string()).is_err()) ;
+        assert!(parse("1 = 2".to_string()).is_err()) ;
+    }
 
+    #[test]
+    fn parse_if_else_chain() {
+        let program = parse("if 1 < 2 { 1 } else if false { 2 } else { 3 }".to_string()).ok().unwrap() ;
+        match &program[0].kind {
+            StmtKind::If { then_branch, else_branch: Some(else_branch), .. } => {
+                assert_eq!(then_branch.len(), 1) ;
+                match &else_branch.kind {
+                    StmtKind::If { else_branch: Some(last), .. } =>
+                        assert!(matches!(last.kind, StmtKind::Block(_))),
+                    k => panic!("Expected 'else if', got {:?}", k)
+                }
+            }
+            k => panic!("Expected an if, got {:?}", k)
+        }
+        assert!(parse("else { 1 }".to_string()).is_err()) ;
+    }
+
+    #[test]
+    fn parse_loops() {
+        let program = parse("loop { break } for true { continue } for i in 0..10 { }".to_string()).ok().unwrap() ;
+        assert!(matches!(program[0].kind, StmtKind::Loop { .. })) ;
+        assert!(matches!(program[1].kind, StmtKind::While { .. })) ;
+        match &program[2].kind {
+            StmtKind::ForRange { name, .. } => assert_eq!(name, "i"),
+            k => panic!("Expected a range loop, got {:?}", k)
+        }
+        match &program[2].kind {
+            StmtKind::ForRange { start, .. } => assert!(matches!(start.kind, ExprKind::Literal(INTEGER(0)))),
+            k => panic!("Expected a range loop, got {:?}", k)
+        }
+        let program = parse("for k in {1: 2} { } for x in [1] { }".to_string()).ok().unwrap() ;
+        assert!(matches!(program[0].kind, StmtKind::ForIn { ref iterable, .. } if matches!(iterable.kind, ExprKind::Map(_)))) ;
+        assert!(matches!(program[1].kind, StmtKind::ForIn { .. })) ;
+        assert!(parse("for 1 in 0..2 { }".to_string()).is_err()) ;
+        assert!(parse("for (x) in [1] { }".to_string()).is_err()) ;
+
+        // Everything after 'in' is the iterable, however loosely it binds
+        let program = parse("for x in a == b { } for x in a or b { } for i in 0..n + 1 { } for (x in a) == b { }".to_string()).ok().unwrap() ;
+        assert!(matches!(&program[0].kind, StmtKind::ForIn { iterable, .. }
+                         if matches!(iterable.kind, ExprKind::Binary { op: B_EQUAL, .. }))) ;
+        assert!(matches!(&program[1].kind, StmtKind::ForIn { iterable, .. } if matches!(iterable.kind, ExprKind::Logical { .. }))) ;
+        assert!(matches!(&program[2].kind, StmtKind::ForRange { end, .. } if matches!(end.kind, ExprKind::Binary { op: B_PLUS, .. }))) ;
+        assert!(matches!(program[3].kind, StmtKind::While { .. })) ;
+        assert!(parse("break".to_string()).is_err()) ;
+        assert!(parse("loop { } continue".to_string()).is_err()) ;
+    }
+
+    #[test]
+    fn parse_functions_and_calls() {
+        let program = parse("fn add(a, b) { return a + b } add(1, 2)".to_string()).ok().unwrap() ;
+        match &program[0].kind {
+            StmtKind::Fn { name, params, body } => {
+                assert_eq!(name, "add") ;
+                assert_eq!(params, &vec!["a".to_string(), "b".to_string()]) ;
+                assert!(matches!(body[0].kind, StmtKind::Return(Some(_)))) ;
+            }
+            k => panic!("Expected a function, got {:?}", k)
+        }
+        match &program[1].kind {
+            StmtKind::Expression(Expr { kind: ExprKind::Call { args, .. }, .. }) => assert_eq!(args.len(), 2),
+            k => panic!("Expected a call, got {:?}", k)
+        }
+        assert!(parse("return 1".to_string()).is_err()) ;
+        assert!(parse("loop { fn f() { break } }".to_string()).is_err()) ;
+        assert!(parse("f(1,)".to_string()).is_err()) ;
+    }
+
+    #[test]
+    fn parse_strings() {
+        match expr_of("\"hello\" + \"\"").kind {
+            ExprKind::Binary { left, right, .. } => {
+                assert!(matches!(left.kind, ExprKind::String(ref s) if s == "hello")) ;
+                assert!(matches!(right.kind, ExprKind::String(ref s) if s.is_empty())) ;
+            }
+            k => panic!("Expected a binary expression, got {:?}", k)
+        }
+        assert!(parse("\"unterminated".to_string()).is_err()) ;
+    }
+
+    #[test]
+    fn parse_print() {
+        let program = parse("print 1 + 2; print \"a\"".to_string()).ok().unwrap() ;
+        assert_eq!(program.len(), 2) ;
+        assert!(matches!(program[0].kind, StmtKind::Print(Expr { kind: ExprKind::Binary { .. }, .. }))) ;
+        assert!(parse("print".to_string()).is_err()) ;
+    }
 
+    #[test]
+    fn parse_arrays() {
+        assert!(matches!(expr_of("[1, 2, 3]").kind, ExprKind::Array(ref e) if e.len() == 3)) ;
+        assert!(matches!(expr_of("[]").kind, ExprKind::Array(ref e) if e.is_empty())) ;
+        assert!(matches!(expr_of("a[0][1]").kind, ExprKind::Index { ref object, .. }
+                         if matches!(object.kind, ExprKind::Index { .. }))) ;
+        assert!(matches!(expr_of("a[i] = 2").kind, ExprKind::IndexSet { .. })) ;
+        assert!(matches!(expr_of("a[1..]").kind, ExprKind::Slice { start: Some(_), end: None, .. })) ;
+        assert!(matches!(expr_of("a[..2]").kind, ExprKind::Slice { start: None, end: Some(_), .. })) ;
+        assert!(matches!(expr_of("-a[0]").kind, ExprKind::Unary { .. })) ;
+        assert!(parse("a[1..2] = 3".to_string()).is_err()) ;
+        assert!(parse("[1, 2".to_string()).is_err()) ;
+    }
+
+    #[test]
+    fn parse_maps() {
+        match expr_of("[{\"a\": 1, 2: [3], true: {}}]").kind {
+            ExprKind::Array(mut elements) => match elements.remove(0).kind {
+                ExprKind::Map(entries) => {
+                    assert_eq!(entries.len(), 3) ;
+                    assert!(matches!(entries[2].1.kind, ExprKind::Map(ref e) if e.is_empty())) ;
+                }
+                k => panic!("Expected a map, got {:?}", k)
+            },
+            k => panic!("Expected an array, got {:?}", k)
+        }
+        assert!(matches!(expr_of("\"a\" in m").kind, ExprKind::Binary { op: B_IN, .. })) ;
+        // A brace starting a statement is still a block
+        assert!(matches!(parse("{ 1 }".to_string()).ok().unwrap()[0].kind, StmtKind::Block(_))) ;
+        assert!(parse("let m = {1 2}".to_string()).is_err()) ;
+    }
+
+    #[test]
+    fn parse_classes() {
+        let program = parse("class A { fn init(x) { this.x = x } fn get() { return this.x } }".to_string()).ok().unwrap() ;
+        match &program[0].kind {
+            StmtKind::Class { name, superclass, methods } => {
+                assert_eq!(name, "A") ;
+                assert!(superclass.is_none()) ;
+                assert_eq!(methods.len(), 2) ;
+                assert!(matches!(&methods[0].kind, StmtKind::Fn { name, .. } if name == "init")) ;
+            }
+            k => panic!("Expected a class, got {:?}", k)
+        }
+        assert!(matches!(expr_of("a.b.c").kind, ExprKind::Get { ref name, .. } if name == "c")) ;
+        assert!(matches!(expr_of("a.b = 1").kind, ExprKind::Set { ref name, .. } if name == "b")) ;
+        assert!(matches!(expr_of("a.b(1)").kind, ExprKind::Call { .. })) ;
+
+        assert!(parse("this".to_string()).is_err()) ;
+        assert!(parse("fn f() { return this }".to_string()).is_err()) ;
+        assert!(parse("class A { fn init() { return 1 } }".to_string()).is_err()) ;
+        assert!(parse("class A { fn init() { fn f() { return 1 } } }".to_string()).is_ok()) ;
+        assert!(parse("class A { let x = 1 }".to_string()).is_err()) ;
+        assert!(parse("a.1".to_string()).is_err()) ;
+    }
+
+    #[test]
+    fn parse_inheritance() {
+        let program = parse("class B < A { fn f() { return super.f() } }".to_string()).ok().unwrap() ;
+        match &program[0].kind {
+            StmtKind::Class { superclass: Some(superclass), .. } => {
+                assert!(matches!(superclass.kind, ExprKind::Variable(ref name) if name == "A")) ;
+            }
+            k => panic!("Expected a subclass, got {:?}", k)
+        }
+        assert!(parse("class A < A { }".to_string()).is_err()) ;
+        assert!(parse("class A < 1 { }".to_string()).is_err()) ;
+        assert!(parse("super.f()".to_string()).is_err()) ;
+        assert!(parse("class A { fn f() { super.f() } }".to_string()).is_err()) ;
+        assert!(parse("class B < A { fn f() { super } }".to_string()).is_err()) ;
+    }
+
+    #[test]
+    fn parse_modules() {
+        let program = parse("module shapes; import geometry\ngeometry::area(2)".to_string()).ok().unwrap() ;
+        assert!(matches!(&program[0].kind, StmtKind::Module(name) if name == "shapes")) ;
+        assert!(matches!(&program[1].kind, StmtKind::Import(name) if name == "geometry")) ;
+        match &program[2].kind {
+            StmtKind::Expression(Expr { kind: ExprKind::Call { callee, .. }, .. }) => {
+                assert!(matches!(&callee.kind, ExprKind::Qualified { module, name } if module == "geometry" && name == "area")) ;
+            }
+            k => panic!("Expected a call, got {:?}", k)
+        }
+        assert!(parse("import".to_string()).is_err()) ;
+        assert!(parse("geometry::".to_string()).is_err()) ;
+        assert!(parse("geometry::pi = 3".to_string()).is_err()) ;
+    }
+
+    #[test]
+    fn parse_debug_output() {
+        let options = CompilerOptions { parserDebug: true, ..CompilerOptions::default() } ;
+        let mut out = Vec::new() ;
+        assert!(parseWithOptions("1 + 2 * 3".to_string(), &options, &mut out).is_ok()) ;
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "PREC (after advance): T_PLUS\nPREC (after advance): T_STAR\n") ;
+
+        let mut out = Vec::new() ;
+        assert!(parseWithOptions("1 + 2".to_string(), &CompilerOptions::default(), &mut out).is_ok()) ;
+        assert!(out.is_empty()) ;
+    }
+
+    #[test]
+    fn parse_error() {
+        assert!(parse("1 +".to_string()).is_err()) ;
+    }
+
+    // Each error is reported once, at the token it is about, and parsing
+    // picks up again at the next statement
+    #[test]
+    fn parse_recovers_after_errors() {
+        let diagnostics = parse("let = 1\nlet y = 2\n1 = 2\nprint )\n  return 1".to_string()).err().unwrap() ;
+        let found: Vec<(ErrorCode, usize, usize)> = diagnostics.iter()
+            .map(|d| (d.code, d.span.line, d.span.column)).collect() ;
+        assert_eq!(found, vec![
+            (E_SYNTAX, 1, 5),
+            (E_INVALID_ASSIGNMENT, 3, 3),
+            (E_SYNTAX, 4, 7),
+            (E_MISPLACED, 5, 3),
+        ]) ;
+        assert_eq!(diagnostics[3].message, "Can't return from top-level code.") ;
+
+        let diagnostics = parse("let s = \"abc\nlet t = 1 @ 2".to_string()).err().unwrap() ;
+        assert_eq!(diagnostics.len(), 1) ;
+        assert_eq!((diagnostics[0].code, diagnostics[0].span.line, diagnostics[0].span.column), (E_SCAN, 1, 9)) ;
+    }
+}
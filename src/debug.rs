@@ -1,25 +1,52 @@
+/*
+Disassembler
+
+Everything here writes to the writer it is given, which is stdout for
+coyote disasm and the VM's debug output for tracing. Failing to write a
+diagnostic isn't worth stopping for, so write errors are ignored.
+*/
+
 use crate::opcodes::* ;
 use crate::opcodes::OpCode::* ;
 use crate::chunk::* ;
+use crate::object::* ;
 use crate::value::* ;
 use crate::value::Value::* ;
 
-pub fn disassembleChunk(chunk: &Chunk, name: &str) {
+use std::io::Write ;
+
+pub fn disassembleChunk(out: &mut dyn Write, heap: &Heap, chunk: &Chunk, name: &str) {
 
-    println!("== {} ==", name) ;
+    let _ = writeln!(out, "== {} ==", name) ;
     let mut i = 0 ;
     while i < chunk.code.len() {
-        i = disassembleInstruction(chunk,i) ;
+        i = disassembleInstruction(out, heap, chunk,i) ;
+    }
+    let _ = writeln!(out, "== Done ==") ;
+}
+
+/**
+Disassembles a function followed by every function declared inside it
+*/
+pub fn disassembleFunction(out: &mut dyn Write, heap: &Heap, fref: ObjRef) {
+    let function = match heap.function(fref) {
+        Some(function) => function,
+        None => return,
+    } ;
+    disassembleChunk(out, heap, &function.chunk, format!("{}", heap.get(fref)).as_str()) ;
+    for constant in function.chunk.constants.iter() {
+        if let OBJ(inner) = constant {
+            disassembleFunction(out, heap, *inner) ;
+        }
     }
-    println!("== Done ==") ;
 }
 
-pub fn disassembleInstruction(chunk: &Chunk, offset: usize) -> usize {
-    print!("{:04} ", offset);
-    if offset > 0 && chunk.lines[offset] == chunk.lines[offset - 1] {
-        print!("   | ");
+pub fn disassembleInstruction(out: &mut dyn Write, heap: &Heap, chunk: &Chunk, offset: usize) -> usize {
+    let _ = write!(out, "{:04} ", offset);
+    if offset > 0 && chunk.line(offset) == chunk.line(offset - 1) {
+        let _ = write!(out, "   | ");
     } else {
-        print!("{:4} ", chunk.lines[offset]);
+        let _ = write!(out, "{:4} ", chunk.line(offset));
     }
 
     let raw_byte = chunk.code[offset];
@@ -32,44 +59,131 @@ pub fn disassembleInstruction(chunk: &Chunk, offset: usize) -> usize {
         | OP_IMUL
         | OP_IDIV
         | OP_ISUB
+        | OP_ADD
+        | OP_SUB
+        | OP_MUL
+        | OP_DIV
+        | OP_DADD
+        | OP_DMUL
+        | OP_DDIV
+        | OP_DSUB
+        | OP_CONCAT
+        | OP_ITOD
         | OP_NIL
         | OP_TRUE
         | OP_FALSE
         | OP_NOT
+        | OP_POP
         | OP_EQUAL
         | OP_GREATER
-        | OP_LESS => return simpleInstruction(instruction_name.as_str(), offset),
-        | OP_CONSTANT => constantInstruction(instruction_name.as_str(), chunk, offset),
+        | OP_LESS
+        | OP_GREATER_EQUAL
+        | OP_LESS_EQUAL
+        | OP_CLOSE_UPVALUE
+        | OP_INDEX_GET
+        | OP_INDEX_SET
+        | OP_SLICE
+        | OP_IN
+        | OP_LEN
+        | OP_ITER_ITEM
+        | OP_INHERIT
+        | OP_PRINT => return simpleInstruction(out, instruction_name.as_str(), offset),
+        | OP_CONSTANT
+        | OP_CLASS
+        | OP_METHOD
+        | OP_GET_PROPERTY
+        | OP_SET_PROPERTY
+        | OP_GET_SUPER
+        | OP_IMPORT => constantInstruction(out, instruction_name.as_str(), heap, chunk, offset),
+        OP_INVOKE
+        | OP_SUPER_INVOKE => invokeInstruction(out, instruction_name.as_str(), heap, chunk, offset),
+        OP_DEFINE_GLOBAL
+        | OP_GET_GLOBAL
+        | OP_SET_GLOBAL => globalInstruction(out, instruction_name.as_str(), chunk, offset),
+        OP_GET_LOCAL
+        | OP_SET_LOCAL
+        | OP_GET_UPVALUE
+        | OP_SET_UPVALUE
+        | OP_CALL
+        | OP_BUILD_ARRAY
+        | OP_BUILD_MAP
+        | OP_BUILD_STRING => operandInstruction(out, instruction_name.as_str(), chunk, offset),
+        OP_JUMP
+        | OP_JUMP_IF_FALSE => jumpInstruction(out, instruction_name.as_str(), 1, chunk, offset),
+        OP_LOOP => jumpInstruction(out, instruction_name.as_str(), -1, chunk, offset),
+        OP_CLOSURE => closureInstruction(out, instruction_name.as_str(), heap, chunk, offset),
         _ => {
-            println!("Unknown code {:?}", raw_byte);
+            let _ = writeln!(out, "Unknown code {:?}", raw_byte);
             return offset + 1;
         }
     }
 }
-fn simpleInstruction(name: &str, offset: usize) -> usize {
-    println!("{}", name);
+fn simpleInstruction(out: &mut dyn Write, name: &str, offset: usize) -> usize {
+    let _ = writeln!(out, "{}", name);
     return offset + 1;
 }
 
-fn constantInstruction(name: &str, chunk: &Chunk ,offset: usize) -> usize {
+fn readOperand(chunk: &Chunk, offset: usize) -> usize {
+    return u16::from_le_bytes([chunk.code[offset+1], chunk.code[offset+2]]) as usize ;
+}
+
+fn operandInstruction(out: &mut dyn Write, name: &str, chunk: &Chunk, offset: usize) -> usize {
+    let _ = writeln!(out, "{:24} {:4}", name, readOperand(chunk, offset));
+    return offset + 3 ;
+}
+
+// sign is 1 for forward jumps and -1 for backward ones
+fn jumpInstruction(out: &mut dyn Write, name: &str, sign: i64, chunk: &Chunk, offset: usize) -> usize {
+    let jump = readOperand(chunk, offset) as i64 ;
+    let target = offset as i64 + 3 + sign * jump ;
+    let _ = writeln!(out, "{:24} {:4} -> {}", name, offset, target);
+    return offset + 3 ;
+}
+
+fn globalInstruction(out: &mut dyn Write, name: &str, chunk: &Chunk, offset: usize) -> usize {
+    let global = readOperand(chunk, offset) ;
+    let global_name = chunk.globals.get(global).map(|s| s.as_str()).unwrap_or("?") ;
+    let _ = writeln!(out, "{:24} {:4} '{}'", name, global, global_name);
+    return offset + 3 ;
+}
+
+fn constantInstruction(out: &mut dyn Write, name: &str, heap: &Heap, chunk: &Chunk ,offset: usize) -> usize {
     let mut ar:[u8;2] = Default::default() ;
     ar.copy_from_slice(&chunk.code[offset+1..offset+3] ) ;
     let constant = u16::from_le_bytes(ar) as usize;
-    print!("{:24} {:4} '", name, constant);
+    let _ = write!(out, "{:24} {:4} '", name, constant);
     let val = chunk.constants[constant] ;
-    printValue(val);
-    print!("'\n");
+    printValue(out, heap, val);
+    let _ = writeln!(out, "'");
     return offset + 3 ;
 }
 
-pub fn printValue(val: Value) {
+// The method name constant is followed by the argument count
+fn invokeInstruction(out: &mut dyn Write, name: &str, heap: &Heap, chunk: &Chunk, offset: usize) -> usize {
+    let constant = readOperand(chunk, offset) ;
+    let argCount = readOperand(chunk, offset + 2) ;
+    let _ = writeln!(out, "{:24} ({} args) {:4} '{}'", name, argCount, constant, heap.formatValue(chunk.constants[constant]));
+    return offset + 5 ;
+}
 
-    match val {
-        INTEGER(i) => print!("{}",i) ,
-        DOUBLE(i) => print!("{}",i) ,
-        NIL(_i) => print!("nil") ,
-        BOOL(b) => if b {print!("True")} else {print!{"False"}}  ,
-        _ => panic!("Unknown value type to print")
+// The function constant is followed by an (is local, index) triple for
+// every variable the closure captures
+fn closureInstruction(out: &mut dyn Write, name: &str, heap: &Heap, chunk: &Chunk, offset: usize) -> usize {
+    let mut offset = constantInstruction(out, name, heap, chunk, offset) ;
+    let constant = u16::from_le_bytes([chunk.code[offset-2], chunk.code[offset-1]]) as usize ;
+    let upvalueCount = match chunk.constants[constant] {
+        OBJ(fref) => heap.function(fref).map(|function| function.upvalueCount).unwrap_or(0),
+        _ => 0,
+    } ;
+    for _ in 0..upvalueCount {
+        let isLocal = chunk.code[offset] == 1 ;
+        let index = u16::from_le_bytes([chunk.code[offset+1], chunk.code[offset+2]]) ;
+        let _ = writeln!(out, "{:04}    |                     {} {}", offset, if isLocal {"local"} else {"upvalue"}, index) ;
+        offset += 3 ;
     }
+    return offset ;
+}
 
+pub fn printValue(out: &mut dyn Write, heap: &Heap, val: Value) {
+    let _ = write!(out, "{}", heap.formatValue(val)) ;
 }
\ No newline at end of file
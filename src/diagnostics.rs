@@ -0,0 +1,200 @@
+/*
+Diagnostics
+
+Every problem the compiler finds, in whichever pass, is recorded as a
+Diagnostic rather than printed where it is found. The passes carry on
+where they can so that one compile reports as many errors as possible,
+and the caller decides how to show them.
+*/
+
+use crate::ast::Span ;
+
+use std::fmt ;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Severity {
+    ERROR,
+    WARNING
+}
+
+/**
+What kind of problem a diagnostic reports. Each kind has a code that
+stays the same from release to release, so tools and documentation can
+refer to it.
+*/
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ErrorCode {
+    // Characters the scanner can't make a token of
+    E_SCAN,
+    // Tokens that don't form the construct expected
+    E_SYNTAX,
+    E_INVALID_ASSIGNMENT,
+    // Statements and keywords used where they aren't allowed
+    E_MISPLACED,
+    E_UNDEFINED,
+    E_REDECLARED,
+    E_TYPE,
+    E_MODULE,
+    // More of something than the bytecode can encode
+    E_LIMIT
+}
+
+use ErrorCode::* ;
+
+impl ErrorCode {
+    pub fn code(&self) -> &'static str {
+        return match self {
+            E_SCAN               => "E0001",
+            E_SYNTAX             => "E0002",
+            E_INVALID_ASSIGNMENT => "E0003",
+            E_MISPLACED          => "E0004",
+            E_UNDEFINED          => "E0101",
+            E_REDECLARED         => "E0102",
+            E_TYPE               => "E0201",
+            E_MODULE             => "E0301",
+            E_LIMIT              => "E0401",
+        } ;
+    }
+}
+
+/**
+Another place in the same file that helps explain a diagnostic, such as
+where a name was first declared
+*/
+#[derive(Clone, PartialEq, Debug)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+}
+
+/**
+A problem found while compiling. file is left empty by the pass that
+finds the problem and filled in by whoever knows which source it was
+given. help holds suggestions for fixing it.
+*/
+#[derive(Clone, PartialEq, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: ErrorCode,
+    pub message: String,
+    pub file: String,
+    pub span: Span,
+    pub labels: Vec<Label>,
+    pub help: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn error(code: ErrorCode, message: &str, span: Span) -> Diagnostic {
+        return Diagnostic {
+            severity: Severity::ERROR,
+            code,
+            message: message.to_string(),
+            file: String::new(),
+            span,
+            labels: Vec::new(),
+            help: Vec::new(),
+        } ;
+    }
+
+    pub fn withLabel(mut self, span: Span, message: &str) -> Diagnostic {
+        self.labels.push(Label { span, message: message.to_string() }) ;
+        return self ;
+    }
+
+    pub fn withHelp(mut self, message: &str) -> Diagnostic {
+        self.help.push(message.to_string()) ;
+        return self ;
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            Severity::ERROR => write!(f, "error"),
+            Severity::WARNING => write!(f, "warning"),
+        } ;
+    }
+}
+
+// file:line:column: error[E0002]: message, leaving out the column when
+// only the line is known
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.span.line)? ;
+        if self.span.column > 0 {
+            write!(f, ":{}", self.span.column)? ;
+        }
+        return write!(f, ": {}[{}]: {}", self.severity, self.code.code(), self.message) ;
+    }
+}
+
+/**
+Fills in the file of the diagnostics that don't have one yet
+*/
+pub fn inFile(mut diagnostics: Vec<Diagnostic>, file: &str) -> Vec<Diagnostic> {
+    for diagnostic in diagnostics.iter_mut().filter(|diagnostic| diagnostic.file.is_empty()) {
+        diagnostic.file = file.to_string() ;
+    }
+    return diagnostics ;
+}
+
+/**
+The candidate closest to name, if any is close enough to be a likely
+misspelling of it
+*/
+pub fn closest<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let limit = name.chars().count() / 3 ;
+    return candidates.into_iter()
+        .map(|candidate| (editDistance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance > 0 && *distance <= limit)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate) ;
+}
+
+// How many characters have to be inserted, removed or replaced to turn a
+// into b
+fn editDistance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect() ;
+    let mut row: Vec<usize> = (0..=b.len()).collect() ;
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0] ;
+        row[0] = i + 1 ;
+        for (j, cb) in b.iter().enumerate() {
+            let replaced = if ca == *cb { diagonal } else { diagonal + 1 } ;
+            diagonal = row[j + 1] ;
+            row[j + 1] = replaced.min(row[j] + 1).min(row[j + 1] + 1) ;
+        }
+    }
+    return row[b.len()] ;
+}
+
+/* =============== Unit tests ================= */
+
+#[cfg(test)]
+mod tests {
+
+    use crate::diagnostics::* ;
+
+    #[test]
+    fn formats_diagnostics() {
+        let diagnostic = Diagnostic::error(E_SYNTAX, "Expect expression.", Span { startByte: 30, endByte: 31, line: 3, column: 7 }) ;
+        let diagnostics = inFile(vec![diagnostic], "main.coy") ;
+        assert_eq!(diagnostics[0].to_string(), "main.coy:3:7: error[E0002]: Expect expression.") ;
+
+        // A file already filled in is kept, and a missing column left out
+        let mut diagnostic = Diagnostic::error(E_MODULE, "Import cycle: a -> a.", Span { line: 1, ..Span::default() }) ;
+        diagnostic.file = "a.coy".to_string() ;
+        let diagnostics = inFile(vec![diagnostic], "main.coy") ;
+        assert_eq!(diagnostics[0].to_string(), "a.coy:1: error[E0301]: Import cycle: a -> a.") ;
+    }
+
+    #[test]
+    fn suggests_close_names() {
+        assert_eq!(closest("lett", ["let", "fn", "loop"]), Some("let")) ;
+        assert_eq!(closest("coutn", ["count", "amount"]), None) ;
+        assert_eq!(closest("amout", ["count", "amount"]), Some("amount")) ;
+        // Every one letter name is a typo away from every other
+        assert_eq!(closest("x", ["y"]), None) ;
+        assert_eq!(closest("total", ["x", "y"]), None) ;
+    }
+}
@@ -0,0 +1,36 @@
+/*
+Coyote: a bytecode compiler and virtual machine for the Coyote language.
+
+The public surface is intentionally small: `compile` turns source into a
+`Chunk`, and `interpret` compiles and runs source in a fresh VM.
+*/
+
+// The code base keeps the naming of the original C implementation
+// (OP_CONSTANT, writeChunk, ...), so the Rust naming lints are relaxed.
+#![allow(non_snake_case, non_camel_case_types, non_upper_case_globals)]
+#![allow(clippy::needless_return, clippy::upper_case_acronyms)]
+
+pub mod ast;
+pub mod binops;
+pub mod chunk;
+pub mod codegen;
+pub mod compiler;
+pub mod debug;
+pub mod diagnostics;
+pub mod modules;
+pub mod natives;
+pub mod object;
+pub mod opcodes;
+pub mod render;
+pub mod rules;
+pub mod scanner;
+pub mod typechecker;
+pub mod value;
+pub mod vm;
+
+pub use chunk::Chunk;
+pub use compiler::{compile, compileWithOptions, compileWithPath, CompilerOptions};
+pub use diagnostics::{Diagnostic, ErrorCode, Severity};
+pub use object::{GcConfig, GcStats};
+pub use value::Value;
+pub use vm::{interpret, InterpretResult, RuntimeError, TraceFrame, VmOptions, VM};
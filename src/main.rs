@@ -0,0 +1,177 @@
+/*
+Command line front end for the Coyote VM
+
+coyote run <file.coy>     compile and execute a script
+coyote repl               interactive prompt, echoing what each line evaluates to
+coyote disasm <file.coy>  compile a script and print its bytecode
+
+run and repl accept --gc-stress, which makes the collector run on every
+allocation, and these debugging flags, which write to stderr:
+
+  --trace         show the stack and each instruction as it runs
+  --print-code    disassemble the compiled script before running it
+  --parser-debug  show each infix operator the parser consumes
+
+Imported modules are looked for next to the script (or in the current
+directory for the repl), then in each directory listed in COYOTE_PATH.
+*/
+
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use coyote::compiler::SCRIPT_NAME;
+use coyote::debug::disassembleFunction;
+use coyote::object::Heap;
+use coyote::render::Renderer;
+use coyote::{compileWithOptions, CompilerOptions, Diagnostic, GcConfig, InterpretResult, VmOptions, VM};
+
+// Exit codes follow the BSD sysexits convention
+const EXIT_USAGE: i32 = 64;
+const EXIT_COMPILE_ERROR: i32 = 65;
+const EXIT_RUNTIME_ERROR: i32 = 70;
+const EXIT_IO_ERROR: i32 = 74;
+
+fn usage() -> ! {
+    eprintln!("Usage: coyote run [--gc-stress] [--trace] [--print-code] [--parser-debug] <file.coy>");
+    eprintln!("       coyote repl [--gc-stress] [--trace] [--print-code] [--parser-debug]");
+    eprintln!("       coyote disasm <file.coy>");
+    process::exit(EXIT_USAGE);
+}
+
+fn read_file(path: &str) -> String {
+    match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("Could not read file \"{}\": {}", path, e);
+            process::exit(EXIT_IO_ERROR);
+        }
+    }
+}
+
+// Where modules imported by the script at path (or the repl) are found
+fn search_path(script: Option<&str>) -> Vec<PathBuf> {
+    let home = match script.and_then(|path| Path::new(path).parent()) {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let mut path = vec![home];
+    if let Some(extra) = env::var_os("COYOTE_PATH") {
+        path.extend(env::split_paths(&extra));
+    }
+    path
+}
+
+fn exit_code(result: &InterpretResult) -> i32 {
+    match result {
+        InterpretResult::INTERPRET_OK => 0,
+        InterpretResult::INTERPRET_COMPILE_ERROR(_) => EXIT_COMPILE_ERROR,
+        InterpretResult::INTERPRET_RUNTIME_ERROR(_) => EXIT_RUNTIME_ERROR,
+    }
+}
+
+// Compile errors are shown against the source of file, and of any module
+// they come from. Color is for terminals, and only if NO_COLOR isn't set.
+fn report_diagnostics(file: &str, source: &str, diagnostics: &[Diagnostic]) {
+    let mut renderer = if io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none() {
+        Renderer::colored()
+    } else {
+        Renderer::plain()
+    };
+    renderer.addSource(file, source);
+    for diagnostic in diagnostics {
+        eprintln!("{}", renderer.render(diagnostic));
+    }
+}
+
+fn report(file: &str, source: &str, result: &InterpretResult) {
+    match result {
+        InterpretResult::INTERPRET_OK => {}
+        InterpretResult::INTERPRET_COMPILE_ERROR(diagnostics) => report_diagnostics(file, source, diagnostics),
+        InterpretResult::INTERPRET_RUNTIME_ERROR(error) => eprintln!("{}", error),
+    }
+}
+
+fn run_file(path: &str, config: GcConfig, options: VmOptions) -> i32 {
+    let source = read_file(path);
+    let mut vm = VM::withConfig(config);
+    vm.setOptions(options);
+    vm.setSearchPath(search_path(Some(path)));
+    vm.setScriptName(path);
+    let result = vm.interpret(source.clone());
+    report(path, &source, &result);
+    exit_code(&result)
+}
+
+fn disasm_file(path: &str) -> i32 {
+    let source = read_file(path);
+    let mut heap = Heap::new();
+    match compileWithOptions(source.clone(), path, &mut heap, &search_path(Some(path)),
+                             &CompilerOptions::default(), &mut io::sink()) {
+        Ok(script) => {
+            disassembleFunction(&mut io::stdout(), &heap, script);
+            0
+        }
+        Err(diagnostics) => {
+            report_diagnostics(path, &source, &diagnostics);
+            EXIT_COMPILE_ERROR
+        }
+    }
+}
+
+fn repl(config: GcConfig, options: VmOptions) -> i32 {
+    let mut vm = VM::withConfig(config);
+    vm.setOptions(VmOptions { echoResult: true, ..options });
+    vm.setSearchPath(search_path(None));
+    let stdin = io::stdin();
+    let mut line = String::new();
+    loop {
+        print!("> ");
+        let _ = io::stdout().flush();
+
+        line.clear();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) => {
+                println!();
+                return 0;
+            }
+            Ok(_) => {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                report(SCRIPT_NAME, &line, &vm.interpret(line.clone()));
+            }
+            Err(e) => {
+                eprintln!("Could not read input: {}", e);
+                return EXIT_IO_ERROR;
+            }
+        }
+    }
+}
+
+fn main() {
+    let mut config = GcConfig::default();
+    let mut options = VmOptions::default();
+    let mut args: Vec<String> = Vec::new();
+    for arg in env::args() {
+        match arg.as_str() {
+            "--gc-stress" => config.stress = true,
+            "--trace" => options.traceExecution = true,
+            "--print-code" => options.compiler.printCode = true,
+            "--parser-debug" => options.compiler.parserDebug = true,
+            _ if arg.starts_with("--") => usage(),
+            _ => args.push(arg),
+        }
+    }
+
+    let code = match args.get(1).map(|s| s.as_str()) {
+        Some("run") if args.len() == 3 => run_file(&args[2], config, options),
+        Some("disasm") if args.len() == 3 => disasm_file(&args[2]),
+        Some("repl") if args.len() == 2 => repl(config, options),
+        _ => usage(),
+    };
+
+    process::exit(code);
+}
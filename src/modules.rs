@@ -0,0 +1,214 @@
+/*
+Modules
+
+`import geometry;` loads geometry.coy from the first directory on the
+search path that has it. The module is parsed and type checked on its
+own, after the modules it imports in turn, and is loaded once however
+many times it is imported. Its top level declarations are its globals,
+which other code reaches as geometry::name.
+*/
+
+use crate::ast::* ;
+use crate::compiler::parse ;
+use crate::diagnostics::* ;
+use crate::typechecker::check ;
+
+use std::fs ;
+use std::path::PathBuf ;
+
+pub const MODULE_EXTENSION: &str = "coy" ;
+
+/**
+A loaded module. exports are the names it declares at the top level.
+*/
+#[derive(Clone)]
+pub struct Module {
+    pub name: String,
+    pub program: Vec<Stmt>,
+    pub exports: Vec<String>,
+}
+
+struct ModuleLoader<'p> {
+    searchPath: &'p [PathBuf],
+    // Every module loaded so far, each one after the modules it imports
+    modules: Vec<Module>,
+    // Modules whose imports are still being loaded, outermost first, with
+    // the files they were read from
+    loading: Vec<(String, String)>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'p> ModuleLoader<'p> {
+
+    fn imports(&mut self, program: &[Stmt]) {
+        for stmt in program {
+            if let StmtKind::Import(name) = &stmt.kind {
+                self.import(stmt.span, name) ;
+            }
+            if !self.diagnostics.is_empty() {
+                return ;
+            }
+        }
+    }
+
+    fn import(&mut self, span: Span, name: &str) {
+        if self.modules.iter().any(|module| module.name == name) {
+            return ;
+        }
+        if let Some(start) = self.loading.iter().position(|(loading, _)| loading == name) {
+            let mut cycle: Vec<String> = self.loading[start..].iter().map(|(loading, _)| loading.clone()).collect() ;
+            cycle.push(name.to_string()) ;
+            self.error(span, format!("Import cycle: {}.", cycle.join(" -> ")).as_str()) ;
+            return ;
+        }
+
+        let file = format!("{}.{}", name, MODULE_EXTENSION) ;
+        let path = match self.searchPath.iter().map(|dir| dir.join(&file)).find(|path| path.is_file()) {
+            Some(path) => path,
+            None => {
+                self.error(span, format!("Can't find module '{}' on the search path.", name).as_str()) ;
+                return ;
+            }
+        } ;
+        let source = match fs::read_to_string(&path) {
+            Ok(source) => source,
+            Err(e) => {
+                self.error(span, format!("Can't read module '{}' from {}: {}", name, path.display(), e).as_str()) ;
+                return ;
+            }
+        } ;
+        let modulePath = path.display().to_string() ;
+        let mut program = match parse(source) {
+            Ok(program) => program,
+            Err(diagnostics) => {
+                self.diagnostics.extend(inFile(diagnostics, &modulePath)) ;
+                return ;
+            }
+        } ;
+        if let Some(StmtKind::Module(declared)) = program.first().map(|stmt| &stmt.kind) {
+            if declared != name {
+                self.error(span, format!("{} declares module '{}', not '{}'.", path.display(), declared, name).as_str()) ;
+                return ;
+            }
+        }
+
+        self.loading.push((name.to_string(), modulePath.clone())) ;
+        self.imports(&program) ;
+        self.loading.pop() ;
+        if !self.diagnostics.is_empty() {
+            return ;
+        }
+
+        if let Err(diagnostics) = check(&mut program, &self.modules) {
+            self.diagnostics.extend(inFile(diagnostics, &modulePath)) ;
+            return ;
+        }
+        let exports = exports(&program) ;
+        self.modules.push(Module { name: name.to_string(), program, exports }) ;
+    }
+
+    // span is in the module being loaded, if any, otherwise in the script,
+    // whose file the caller fills in
+    fn error(&mut self, span: Span, message: &str) {
+        let mut diagnostic = Diagnostic::error(ErrorCode::E_MODULE, message, span) ;
+        if let Some((_, file)) = self.loading.last() {
+            diagnostic.file = file.clone() ;
+        }
+        self.diagnostics.push(diagnostic) ;
+    }
+}
+
+/**
+The names a program declares at the top level
+*/
+pub fn exports(program: &[Stmt]) -> Vec<String> {
+    return program.iter().filter_map(|stmt| match &stmt.kind {
+        StmtKind::Let { name, .. } | StmtKind::Fn { name, .. } | StmtKind::Class { name, .. } => Some(name.clone()),
+        _ => None,
+    }).collect() ;
+}
+
+/**
+Load every module a program imports, directly or through other modules.
+They come back in the order they have to be compiled in.
+*/
+pub fn load(program: &[Stmt], searchPath: &[PathBuf]) -> Result<Vec<Module>,Vec<Diagnostic>> {
+    return loadAfter(program, searchPath, Vec::new()) ;
+}
+
+/**
+load, for a program compiled after others that loaded the modules in
+loaded. Those aren't loaded again, and come back first.
+*/
+pub fn loadAfter(program: &[Stmt], searchPath: &[PathBuf], loaded: Vec<Module>) -> Result<Vec<Module>,Vec<Diagnostic>> {
+    let mut loader = ModuleLoader {
+        searchPath,
+        modules: loaded,
+        loading: Vec::new(),
+        diagnostics: Vec::new(),
+    } ;
+    loader.imports(program) ;
+
+    if !loader.diagnostics.is_empty() {
+        return Result::Err(loader.diagnostics);
+    }
+    return Result::Ok(loader.modules);
+}
+
+/* =============== Unit tests ================= */
+
+#[cfg(test)]
+mod tests {
+
+    use crate::modules::* ;
+
+    use std::env ;
+    use std::path::Path ;
+    use std::process ;
+
+    // A fresh directory holding the given modules
+    fn module_dir(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = env::temp_dir().join(format!("coyote-{}-{}", test, process::id())) ;
+        let _ = fs::remove_dir_all(&dir) ;
+        fs::create_dir_all(&dir).unwrap() ;
+        for (name, source) in files {
+            fs::write(dir.join(format!("{}.{}", name, MODULE_EXTENSION)), source).unwrap() ;
+        }
+        return dir ;
+    }
+
+    fn load_source(source: &str, dir: &Path) -> Result<Vec<String>,Vec<Diagnostic>> {
+        let program = parse(source.to_string())? ;
+        let modules = load(&program, &[dir.to_path_buf()])? ;
+        return Ok(modules.into_iter().map(|module| module.name).collect()) ;
+    }
+
+    #[test]
+    fn loads_dependencies_first_and_once() {
+        let dir = module_dir("deps", &[
+            ("shapes", "module shapes; import geometry; fn unit() { return geometry::square(1) }"),
+            ("geometry", "module geometry; fn square(x) { return x * x }"),
+        ]) ;
+        assert_eq!(load_source("import shapes; import geometry; import shapes", &dir),
+                   Ok(vec!["geometry".to_string(), "shapes".to_string()])) ;
+        let _ = fs::remove_dir_all(&dir) ;
+    }
+
+    #[test]
+    fn rejects_bad_modules() {
+        let dir = module_dir("bad", &[
+            ("a", "module a; import b"),
+            ("b", "module b; import a"),
+            ("named", "module other; let x = 1"),
+            ("broken", "let = 1"),
+        ]) ;
+        assert!(load_source("import a", &dir).is_err()) ;
+        assert!(load_source("import named", &dir).is_err()) ;
+        // Errors inside a module are reported against its file
+        let diagnostics = load_source("import broken", &dir).err().unwrap() ;
+        assert!(diagnostics[0].file.ends_with("broken.coy")) ;
+        assert_eq!(diagnostics[0].span.line, 1) ;
+        assert!(load_source("import missing", &dir).is_err()) ;
+        let _ = fs::remove_dir_all(&dir) ;
+    }
+}
@@ -0,0 +1,40 @@
+/*
+Native functions
+
+Functions implemented in Rust that every script can call. They behave
+like globals that are already defined when the script starts, so a
+script is free to declare its own variable with the same name.
+*/
+
+use crate::object::* ;
+use crate::value::* ;
+
+pub static NATIVES: [(&str, usize, NativeFn); 1] = [
+    ("len", 1, len),
+] ;
+
+pub fn isNative(name: &str) -> bool {
+    return NATIVES.iter().any(|(native, _, _)| *native == name) ;
+}
+
+/**
+The Native object for name, ready to be allocated
+*/
+pub fn native(name: &str) -> Option<Native> {
+    return NATIVES.iter()
+        .find(|(native, _, _)| *native == name)
+        .map(|(name, arity, function)| Native { name, arity: *arity, function: *function }) ;
+}
+
+// Number of elements in an array, entries in a map or characters in a string
+fn len(heap: &Heap, args: &[Value]) -> Result<Value, String> {
+    if let Value::OBJ(oref) = args[0] {
+        match heap.get(oref) {
+            Obj::ARRAY(elements) => return Ok(Value::from(elements.len() as i64)),
+            Obj::MAP(map) => return Ok(Value::from(map.len() as i64)),
+            Obj::STRING(string) => return Ok(Value::from(string.chars().count() as i64)),
+            _ => {}
+        }
+    }
+    return Err(format!("Can't take the length of {}.", heap.formatValue(args[0]))) ;
+}
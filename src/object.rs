@@ -0,0 +1,693 @@
+/*
+Heap objects
+
+Anything that doesn't fit inline in a Value lives here and is referred to
+through an ObjRef. The Heap owns every object; an ObjRef is just the slot
+the object was allocated in.
+
+Memory is reclaimed by a mark and sweep collector. The heap can't see the
+VM, so the VM decides when to collect (shouldCollect) and hands over its
+roots; everything not reachable from them is freed and its slot reused.
+*/
+
+use crate::ast::Span ;
+use crate::chunk::* ;
+use crate::value::* ;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::mem::size_of;
+use std::rc::Rc;
+
+/**
+A compiled function. The chunk is shared with the call frames executing
+it, which is why it sits behind an Rc.
+*/
+pub struct Function {
+    pub name: String,
+    pub arity: usize,
+    pub upvalueCount: usize,
+    pub chunk: Rc<Chunk>,
+}
+
+/**
+A function together with the variables it captured from the functions
+around it. Every function is called through a closure, even one that
+captures nothing.
+*/
+pub struct Closure {
+    pub function: ObjRef,
+    pub upvalues: Vec<ObjRef>,
+}
+
+/**
+A captured variable. While the variable is still on the stack the upvalue
+is open and points at its slot; once the variable goes out of scope the
+value is moved in here and the upvalue is closed.
+*/
+pub struct Upvalue {
+    pub location: usize,
+    pub closed: Option<Value>,
+}
+
+/**
+A function implemented in Rust. It gets the arguments it was called with
+and returns its result, or an error message to report at runtime.
+*/
+pub type NativeFn = fn(&Heap, &[Value]) -> Result<Value, String> ;
+
+pub struct Native {
+    pub name: &'static str,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+/**
+A class and the methods declared in it, keyed by their interned names.
+initializer is the init method, looked up on every instantiation, so it
+is kept at hand.
+*/
+pub struct Class {
+    pub name: String,
+    pub methods: HashMap<ObjRef, ObjRef>,
+    pub initializer: Option<ObjRef>,
+}
+
+/**
+An instance of a class. Fields are created by assigning to them, so
+there is no fixed set.
+*/
+pub struct Instance {
+    pub class: ObjRef,
+    pub fields: HashMap<ObjRef, Value>,
+}
+
+/**
+A method looked up on an instance without calling it straight away. It
+remembers the instance so that `this` is still bound when it is called.
+*/
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
+
+/**
+The values a map can be keyed by. Strings are interned, so the reference
+identifies the string.
+*/
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum MapKey {
+    STRING(ObjRef),
+    INTEGER(i64),
+    BOOL(bool),
+}
+
+impl MapKey {
+    pub fn value(&self) -> Value {
+        return match self {
+            MapKey::STRING(oref) => Value::from(*oref),
+            MapKey::INTEGER(i) => Value::from(*i),
+            MapKey::BOOL(b) => Value::from(*b),
+        }
+    }
+}
+
+/**
+A hash map that remembers the order its keys were first inserted in,
+which is the order it is iterated and printed in. index maps a key to its
+position in entries.
+*/
+#[derive(Default)]
+pub struct Map {
+    entries: Vec<(MapKey, Value)>,
+    index: HashMap<MapKey, usize>,
+}
+
+impl Map {
+
+    pub fn new() -> Map {
+        return Map::default() ;
+    }
+
+    pub fn get(&self, key: MapKey) -> Option<Value> {
+        return self.index.get(&key).map(|position| self.entries[*position].1) ;
+    }
+
+    pub fn set(&mut self, key: MapKey, value: Value) {
+        match self.index.get(&key) {
+            Some(position) => self.entries[*position].1 = value,
+            None => {
+                self.index.insert(key, self.entries.len()) ;
+                self.entries.push((key, value)) ;
+            }
+        }
+    }
+
+    pub fn contains(&self, key: MapKey) -> bool {
+        return self.index.contains_key(&key) ;
+    }
+
+    pub fn len(&self) -> usize {
+        return self.entries.len() ;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.entries.is_empty() ;
+    }
+
+    // The entry inserted position'th
+    pub fn entry(&self, position: usize) -> (MapKey, Value) {
+        return self.entries[position] ;
+    }
+
+    pub fn entries(&self) -> &[(MapKey, Value)] {
+        return &self.entries ;
+    }
+}
+
+pub enum Obj {
+    STRING(String),
+    ARRAY(Vec<Value>),
+    MAP(Map),
+    FUNCTION(Function),
+    CLOSURE(Closure),
+    UPVALUE(Upvalue),
+    NATIVE(Native),
+    CLASS(Class),
+    INSTANCE(Instance),
+    BOUND_METHOD(BoundMethod),
+}
+
+impl fmt::Display for Obj {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Obj::STRING(string) => write!(f, "{}", string),
+            Obj::ARRAY(elements) => write!(f, "<array of {}>", elements.len()),
+            Obj::MAP(map) => write!(f, "<map of {}>", map.len()),
+            Obj::FUNCTION(function) => {
+                if function.name.is_empty() {
+                    write!(f, "<script>")
+                } else {
+                    write!(f, "<fn {}>", function.name)
+                }
+            }
+            Obj::CLOSURE(_) => write!(f, "<closure>"),
+            Obj::UPVALUE(_) => write!(f, "<upvalue>"),
+            Obj::NATIVE(native) => write!(f, "<native fn {}>", native.name),
+            Obj::CLASS(class) => write!(f, "<class {}>", class.name),
+            Obj::INSTANCE(_) => write!(f, "<instance>"),
+            Obj::BOUND_METHOD(_) => write!(f, "<bound method>"),
+        }
+    }
+}
+
+impl Obj {
+    // Rough number of bytes the object holds on to, for the GC's accounting
+    fn size(&self) -> usize {
+        let payload = match self {
+            Obj::STRING(string) => string.len(),
+            Obj::ARRAY(elements) => elements.len() * size_of::<Value>(),
+            Obj::MAP(map) => map.len() * (size_of::<(MapKey, Value)>() + size_of::<(MapKey, usize)>()),
+            Obj::FUNCTION(function) => {
+                function.name.len()
+                    + function.chunk.code.len()
+                    + function.chunk.constants.len() * size_of::<Value>()
+                    + function.chunk.spans.len() * size_of::<Span>()
+            }
+            Obj::CLOSURE(closure) => closure.upvalues.len() * size_of::<ObjRef>(),
+            Obj::UPVALUE(_) | Obj::NATIVE(_) | Obj::BOUND_METHOD(_) => 0,
+            Obj::CLASS(class) => class.name.len() + class.methods.len() * size_of::<(ObjRef, ObjRef)>(),
+            Obj::INSTANCE(instance) => instance.fields.len() * size_of::<(ObjRef, Value)>(),
+        } ;
+        return size_of::<Obj>() + payload ;
+    }
+
+    // The values an object keeps alive
+    fn references(&self) -> Vec<Value> {
+        return match self {
+            Obj::STRING(_) => Vec::new(),
+            Obj::ARRAY(elements) => elements.clone(),
+            Obj::MAP(map) => map.entries().iter().flat_map(|(key, value)| [key.value(), *value]).collect(),
+            Obj::FUNCTION(function) => function.chunk.constants.clone(),
+            Obj::CLOSURE(closure) => {
+                let mut refs = vec![Value::from(closure.function)] ;
+                refs.extend(closure.upvalues.iter().map(|upvalue| Value::from(*upvalue))) ;
+                refs
+            }
+            Obj::UPVALUE(upvalue) => upvalue.closed.into_iter().collect(),
+            Obj::NATIVE(_) => Vec::new(),
+            // Method names are interned strings, which only live as long
+            // as something refers to them
+            Obj::CLASS(class) => class.methods.iter().flat_map(|(name, method)| [Value::from(*name), Value::from(*method)]).collect(),
+            Obj::INSTANCE(instance) => {
+                let mut refs = vec![Value::from(instance.class)] ;
+                refs.extend(instance.fields.iter().flat_map(|(name, value)| [Value::from(*name), *value])) ;
+                refs
+            }
+            Obj::BOUND_METHOD(bound) => vec![bound.receiver, Value::from(bound.method)],
+        }
+    }
+}
+
+/**
+How eagerly the collector runs.
+initialThreshold : bytes that can be allocated before the first collection
+growthFactor     : after a collection the next one is due once the heap has
+                   grown to this multiple of what survived
+stress           : collect on every allocation, to shake out objects the VM
+                   forgot to root
+*/
+#[derive(Copy, Clone, Debug)]
+pub struct GcConfig {
+    pub initialThreshold: usize,
+    pub growthFactor: usize,
+    pub stress: bool,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        return GcConfig { initialThreshold: 1024 * 1024, growthFactor: 2, stress: false } ;
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct GcStats {
+    pub collections: usize,
+    pub objectsFreed: usize,
+    pub bytesFreed: usize,
+    pub liveObjects: usize,
+    pub bytesAllocated: usize,
+    pub nextGC: usize,
+}
+
+/**
+strings maps the contents of every string on the heap to its object.
+Strings are always allocated through intern, so two equal strings are the
+same object and comparing their ObjRefs is enough to compare them. The
+table doesn't keep strings alive; sweeping a string removes its entry.
+*/
+pub struct Heap {
+    objects: Vec<Option<Obj>>,
+    marks: Vec<bool>,
+    // What each slot's object was last charged to bytesAllocated, which
+    // is what freeing it gives back
+    sizes: Vec<usize>,
+    // Slots freed by the last sweeps, reused before the heap grows
+    free: Vec<usize>,
+    strings: HashMap<String, ObjRef>,
+    config: GcConfig,
+    stats: GcStats,
+}
+
+impl Heap {
+
+    pub fn new() -> Heap {
+        return Heap::withConfig(GcConfig::default()) ;
+    }
+
+    pub fn withConfig(config: GcConfig) -> Heap {
+        return Heap {
+            objects: Vec::new(),
+            marks: Vec::new(),
+            sizes: Vec::new(),
+            free: Vec::new(),
+            strings: HashMap::new(),
+            config,
+            stats: GcStats { nextGC: config.initialThreshold, ..GcStats::default() },
+        } ;
+    }
+
+    pub fn alloc(&mut self, obj: Obj) -> ObjRef {
+        let size = obj.size() ;
+        self.stats.bytesAllocated += size ;
+        self.stats.liveObjects += 1 ;
+        match self.free.pop() {
+            Some(slot) => {
+                self.objects[slot] = Some(obj) ;
+                self.sizes[slot] = size ;
+                return ObjRef(slot) ;
+            }
+            None => {
+                self.objects.push(Some(obj)) ;
+                self.marks.push(false) ;
+                self.sizes.push(size) ;
+                return ObjRef(self.objects.len() - 1) ;
+            }
+        }
+    }
+
+    /**
+    Charges the object behind oref for what it holds now. Called after
+    the object has grown or shrunk in place through one of the _mut
+    accessors, so the collector sees the change.
+    */
+    pub fn resized(&mut self, oref: ObjRef) {
+        let size = self.get(oref).size() ;
+        self.stats.bytesAllocated = self.stats.bytesAllocated - self.sizes[oref.0] + size ;
+        self.sizes[oref.0] = size ;
+    }
+
+    pub fn get(&self, oref: ObjRef) -> &Obj {
+        return match &self.objects[oref.0] {
+            Some(obj) => obj,
+            None => panic!("Dangling object reference {}", oref.0),
+        }
+    }
+
+    pub fn isInterned(&self, string: &str) -> bool {
+        return self.strings.contains_key(string) ;
+    }
+
+    pub fn intern(&mut self, string: &str) -> ObjRef {
+        if let Some(oref) = self.strings.get(string) {
+            return *oref ;
+        }
+        let oref = self.alloc(Obj::STRING(string.to_string())) ;
+        self.strings.insert(string.to_string(), oref) ;
+        return oref ;
+    }
+
+    pub fn string(&self, oref: ObjRef) -> Option<&str> {
+        return match self.get(oref) {
+            Obj::STRING(string) => Some(string.as_str()),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, oref: ObjRef) -> &mut Obj {
+        return match &mut self.objects[oref.0] {
+            Some(obj) => obj,
+            None => panic!("Dangling object reference {}", oref.0),
+        }
+    }
+
+    /**
+    Returns the function behind oref, or None if it refers to
+    some other kind of object
+    */
+    pub fn function(&self, oref: ObjRef) -> Option<&Function> {
+        return match self.get(oref) {
+            Obj::FUNCTION(function) => Some(function),
+            _ => None,
+        }
+    }
+
+    pub fn closure(&self, oref: ObjRef) -> Option<&Closure> {
+        return match self.get(oref) {
+            Obj::CLOSURE(closure) => Some(closure),
+            _ => None,
+        }
+    }
+
+    pub fn upvalue_mut(&mut self, oref: ObjRef) -> Option<&mut Upvalue> {
+        return match self.get_mut(oref) {
+            Obj::UPVALUE(upvalue) => Some(upvalue),
+            _ => None,
+        }
+    }
+
+    pub fn array(&self, oref: ObjRef) -> Option<&Vec<Value>> {
+        return match self.get(oref) {
+            Obj::ARRAY(elements) => Some(elements),
+            _ => None,
+        }
+    }
+
+    pub fn array_mut(&mut self, oref: ObjRef) -> Option<&mut Vec<Value>> {
+        return match self.get_mut(oref) {
+            Obj::ARRAY(elements) => Some(elements),
+            _ => None,
+        }
+    }
+
+    pub fn map(&self, oref: ObjRef) -> Option<&Map> {
+        return match self.get(oref) {
+            Obj::MAP(map) => Some(map),
+            _ => None,
+        }
+    }
+
+    pub fn map_mut(&mut self, oref: ObjRef) -> Option<&mut Map> {
+        return match self.get_mut(oref) {
+            Obj::MAP(map) => Some(map),
+            _ => None,
+        }
+    }
+
+    pub fn class(&self, oref: ObjRef) -> Option<&Class> {
+        return match self.get(oref) {
+            Obj::CLASS(class) => Some(class),
+            _ => None,
+        }
+    }
+
+    pub fn class_mut(&mut self, oref: ObjRef) -> Option<&mut Class> {
+        return match self.get_mut(oref) {
+            Obj::CLASS(class) => Some(class),
+            _ => None,
+        }
+    }
+
+    pub fn instance(&self, oref: ObjRef) -> Option<&Instance> {
+        return match self.get(oref) {
+            Obj::INSTANCE(instance) => Some(instance),
+            _ => None,
+        }
+    }
+
+    pub fn instance_mut(&mut self, oref: ObjRef) -> Option<&mut Instance> {
+        return match self.get_mut(oref) {
+            Obj::INSTANCE(instance) => Some(instance),
+            _ => None,
+        }
+    }
+
+    /**
+    The key a value stands for when it indexes a map, or None if it can't
+    be used as one
+    */
+    pub fn mapKey(&self, value: Value) -> Option<MapKey> {
+        return match value {
+            Value::INTEGER(i) => Some(MapKey::INTEGER(i)),
+            Value::BOOL(b) => Some(MapKey::BOOL(b)),
+            Value::OBJ(oref) if self.string(oref).is_some() => Some(MapKey::STRING(oref)),
+            _ => None,
+        }
+    }
+
+    /**
+    How a value is shown to the user
+    */
+    pub fn formatValue(&self, value: Value) -> String {
+        return self.formatNested(value, &mut Vec::new()) ;
+    }
+
+    /**
+    How a value referring to oref is shown to the user. A closure, or a
+    method bound to an instance, is shown as the function it wraps.
+    */
+    pub fn describe(&self, oref: ObjRef) -> String {
+        return self.formatValue(Value::from(oref)) ;
+    }
+
+    // enclosing holds the collections being printed around this value, so
+    // one that contains itself doesn't recurse forever
+    fn formatNested(&self, value: Value, enclosing: &mut Vec<ObjRef>) -> String {
+        let oref = match value {
+            Value::INTEGER(i) => return format!("{}", i),
+            Value::DOUBLE(d) => return format!("{}", d),
+            Value::NIL => return "nil".to_string(),
+            Value::BOOL(b) => return if b { "True".to_string() } else { "False".to_string() },
+            Value::OBJ(oref) => oref,
+        } ;
+        return match self.get(oref) {
+            Obj::CLOSURE(closure) => format!("{}", self.get(closure.function)),
+            Obj::BOUND_METHOD(bound) => self.describe(bound.method),
+            Obj::INSTANCE(instance) => format!("<{} instance>", self.class(instance.class).unwrap().name),
+            Obj::ARRAY(elements) => {
+                if enclosing.contains(&oref) {
+                    return "[...]".to_string() ;
+                }
+                enclosing.push(oref) ;
+                let items: Vec<String> = elements.iter().map(|e| self.formatNested(*e, enclosing)).collect() ;
+                enclosing.pop() ;
+                format!("[{}]", items.join(", "))
+            }
+            Obj::MAP(map) => {
+                if enclosing.contains(&oref) {
+                    return "{...}".to_string() ;
+                }
+                enclosing.push(oref) ;
+                let items: Vec<String> = map.entries().iter().map(|(key, value)| {
+                    format!("{}: {}", self.formatNested(key.value(), enclosing), self.formatNested(*value, enclosing))
+                }).collect() ;
+                enclosing.pop() ;
+                format!("{{{}}}", items.join(", "))
+            }
+            obj => format!("{}", obj),
+        }
+    }
+
+    /**
+    Fills in the global name table of a function compiled before the
+    table was complete. Only valid while nothing else shares its chunk.
+    */
+    pub fn setGlobalNames(&mut self, oref: ObjRef, names: &[String]) {
+        if let Some(Obj::FUNCTION(function)) = &mut self.objects[oref.0] {
+            if let Some(chunk) = Rc::get_mut(&mut function.chunk) {
+                chunk.globals = names.to_vec() ;
+            }
+        }
+    }
+}
+
+/* Garbage collection */
+
+impl Heap {
+
+    pub fn stats(&self) -> GcStats {
+        return self.stats ;
+    }
+
+    // Whether the next allocation should be preceded by a collection
+    pub fn shouldCollect(&self) -> bool {
+        return self.config.stress || self.stats.bytesAllocated > self.stats.nextGC ;
+    }
+
+    /**
+    Frees every object that can't be reached from roots
+    */
+    pub fn collect(&mut self, roots: &[Value]) {
+        let mut gray = Vec::new() ;
+        for root in roots {
+            self.markValue(*root, &mut gray) ;
+        }
+        while let Some(oref) = gray.pop() {
+            for value in self.get(oref).references() {
+                self.markValue(value, &mut gray) ;
+            }
+        }
+        self.sweep() ;
+
+        self.stats.collections += 1 ;
+        self.stats.nextGC = std::cmp::max(self.stats.bytesAllocated * self.config.growthFactor,
+                                          self.config.initialThreshold) ;
+    }
+
+    fn markValue(&mut self, value: Value, gray: &mut Vec<ObjRef>) {
+        if let Value::OBJ(oref) = value {
+            if !self.marks[oref.0] {
+                self.marks[oref.0] = true ;
+                gray.push(oref) ;
+            }
+        }
+    }
+
+    fn sweep(&mut self) {
+        for slot in 0..self.objects.len() {
+            if self.marks[slot] {
+                self.marks[slot] = false ;
+                continue ;
+            }
+            if let Some(obj) = self.objects[slot].take() {
+                let size = self.sizes[slot] ;
+                if let Obj::STRING(string) = &obj {
+                    self.strings.remove(string) ;
+                }
+                self.free.push(slot) ;
+                self.stats.bytesAllocated -= size ;
+                self.stats.bytesFreed += size ;
+                self.stats.objectsFreed += 1 ;
+                self.stats.liveObjects -= 1 ;
+            }
+        }
+    }
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        return Heap::new() ;
+    }
+}
+
+/* =============== Unit tests ================= */
+
+#[cfg(test)]
+mod tests {
+
+    use crate::object::* ;
+
+    #[test]
+    fn interns_strings() {
+        let mut heap = Heap::new() ;
+        let a = heap.intern("coyote") ;
+        let b = heap.intern("coyote") ;
+        let c = heap.intern("coyotes") ;
+        assert_eq!(a, b) ;
+        assert_ne!(a, c) ;
+        assert_eq!(heap.string(c), Some("coyotes")) ;
+        assert_eq!(format!("{}", heap.get(a)), "coyote") ;
+    }
+
+    #[test]
+    fn maps_keep_insertion_order() {
+        let mut heap = Heap::new() ;
+        let b = heap.intern("b") ;
+        let mut map = Map::new() ;
+        map.set(MapKey::STRING(b), Value::from(1i64)) ;
+        map.set(MapKey::INTEGER(1), Value::from(2i64)) ;
+        map.set(MapKey::STRING(b), Value::from(3i64)) ;
+        assert_eq!(map.len(), 2) ;
+        assert_eq!(map.entry(0), (MapKey::STRING(b), Value::from(3i64))) ;
+        assert_eq!(map.get(MapKey::INTEGER(1)), Some(Value::from(2i64))) ;
+        assert!(!map.contains(MapKey::BOOL(true))) ;
+
+        let oref = heap.alloc(Obj::MAP(map)) ;
+        assert_eq!(heap.describe(oref), "{b: 3, 1: 2}") ;
+    }
+
+    #[test]
+    fn collects_unreachable_objects() {
+        let mut heap = Heap::new() ;
+        let kept = heap.intern("kept") ;
+        heap.intern("garbage") ;
+        let function = heap.alloc(Obj::FUNCTION(Function {
+            name: "f".to_string(),
+            arity: 0,
+            upvalueCount: 0,
+            chunk: Rc::new(newChunk()),
+        })) ;
+        let closure = heap.alloc(Obj::CLOSURE(Closure { function, upvalues: Vec::new() })) ;
+
+        heap.collect(&[Value::from(kept), Value::from(closure), Value::from(1i64)]) ;
+        let stats = heap.stats() ;
+        assert_eq!(stats.collections, 1) ;
+        assert_eq!(stats.objectsFreed, 1) ;
+        assert_eq!(stats.liveObjects, 3) ;
+        assert!(!heap.isInterned("garbage")) ;
+        assert_eq!(heap.function(function).unwrap().name, "f") ;
+
+        // The freed slot is handed out again
+        let reused = heap.intern("new") ;
+        assert_eq!(reused, ObjRef(1)) ;
+        assert_eq!(heap.stats().liveObjects, 4) ;
+    }
+
+    #[test]
+    fn frees_what_grown_objects_were_charged() {
+        let mut heap = Heap::new() ;
+        let start = heap.stats().bytesAllocated ;
+        let map = heap.alloc(Obj::MAP(Map::new())) ;
+        let allocated = heap.stats().bytesAllocated ;
+        for i in 0..100 {
+            heap.map_mut(map).unwrap().set(MapKey::INTEGER(i), Value::from(i)) ;
+            heap.resized(map) ;
+        }
+        assert!(heap.stats().bytesAllocated > allocated) ;
+
+        heap.collect(&[]) ;
+        let stats = heap.stats() ;
+        assert_eq!(stats.bytesAllocated, start) ;
+        assert_eq!(stats.bytesFreed, heap.sizes[map.0]) ;
+    }
+}
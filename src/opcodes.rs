@@ -1,6 +1,6 @@
 use OpCode::* ;
 
-#[derive(Debug)]
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum OpCode {
     OP_CONSTANT,
     OP_RETURN,
@@ -18,6 +18,50 @@ pub enum OpCode {
     OP_NOT,
     OP_PUSH,
     OP_POP,
+    OP_DADD,
+    OP_DSUB,
+    OP_DMUL,
+    OP_DDIV,
+    OP_ITOD,
+    OP_DEFINE_GLOBAL,
+    OP_GET_GLOBAL,
+    OP_SET_GLOBAL,
+    OP_GET_LOCAL,
+    OP_SET_LOCAL,
+    OP_ADD,
+    OP_SUB,
+    OP_MUL,
+    OP_DIV,
+    OP_JUMP,
+    OP_JUMP_IF_FALSE,
+    OP_LOOP,
+    OP_CALL,
+    OP_CLOSURE,
+    OP_GET_UPVALUE,
+    OP_SET_UPVALUE,
+    OP_CLOSE_UPVALUE,
+    OP_CONCAT,
+    OP_BUILD_ARRAY,
+    OP_INDEX_GET,
+    OP_INDEX_SET,
+    OP_SLICE,
+    OP_BUILD_MAP,
+    OP_IN,
+    OP_LEN,
+    OP_ITER_ITEM,
+    OP_CLASS,
+    OP_METHOD,
+    OP_GET_PROPERTY,
+    OP_SET_PROPERTY,
+    OP_INVOKE,
+    OP_INHERIT,
+    OP_GET_SUPER,
+    OP_SUPER_INVOKE,
+    OP_IMPORT,
+    OP_PRINT,
+    OP_BUILD_STRING,
+    OP_GREATER_EQUAL,
+    OP_LESS_EQUAL,
     OP_UNKNOWN
 
 }
@@ -42,6 +86,50 @@ impl OpCode {
             OP_NOT          => 14,
             OP_PUSH         => 15,
             OP_POP          => 16,
+            OP_DADD         => 17,
+            OP_DSUB         => 18,
+            OP_DMUL         => 19,
+            OP_DDIV         => 20,
+            OP_ITOD         => 21,
+            OP_DEFINE_GLOBAL=> 22,
+            OP_GET_GLOBAL   => 23,
+            OP_SET_GLOBAL   => 24,
+            OP_GET_LOCAL    => 25,
+            OP_SET_LOCAL    => 26,
+            OP_ADD          => 27,
+            OP_SUB          => 28,
+            OP_MUL          => 29,
+            OP_DIV          => 30,
+            OP_JUMP         => 31,
+            OP_JUMP_IF_FALSE=> 32,
+            OP_LOOP         => 33,
+            OP_CALL         => 34,
+            OP_CLOSURE      => 35,
+            OP_GET_UPVALUE  => 36,
+            OP_SET_UPVALUE  => 37,
+            OP_CLOSE_UPVALUE=> 38,
+            OP_CONCAT       => 39,
+            OP_BUILD_ARRAY  => 40,
+            OP_INDEX_GET    => 41,
+            OP_INDEX_SET    => 42,
+            OP_SLICE        => 43,
+            OP_BUILD_MAP    => 44,
+            OP_IN           => 45,
+            OP_LEN          => 46,
+            OP_ITER_ITEM    => 47,
+            OP_CLASS        => 48,
+            OP_METHOD       => 49,
+            OP_GET_PROPERTY => 50,
+            OP_SET_PROPERTY => 51,
+            OP_INVOKE       => 52,
+            OP_INHERIT      => 53,
+            OP_GET_SUPER    => 54,
+            OP_SUPER_INVOKE => 55,
+            OP_IMPORT       => 56,
+            OP_PRINT        => 57,
+            OP_BUILD_STRING => 58,
+            OP_GREATER_EQUAL=> 59,
+            OP_LESS_EQUAL   => 60,
             _ => 0
         }
     }
@@ -65,6 +153,50 @@ impl OpCode {
             14 => OP_NOT,
             15 => OP_PUSH,
             16 => OP_POP,
+            17 => OP_DADD,
+            18 => OP_DSUB,
+            19 => OP_DMUL,
+            20 => OP_DDIV,
+            21 => OP_ITOD,
+            22 => OP_DEFINE_GLOBAL,
+            23 => OP_GET_GLOBAL,
+            24 => OP_SET_GLOBAL,
+            25 => OP_GET_LOCAL,
+            26 => OP_SET_LOCAL,
+            27 => OP_ADD,
+            28 => OP_SUB,
+            29 => OP_MUL,
+            30 => OP_DIV,
+            31 => OP_JUMP,
+            32 => OP_JUMP_IF_FALSE,
+            33 => OP_LOOP,
+            34 => OP_CALL,
+            35 => OP_CLOSURE,
+            36 => OP_GET_UPVALUE,
+            37 => OP_SET_UPVALUE,
+            38 => OP_CLOSE_UPVALUE,
+            39 => OP_CONCAT,
+            40 => OP_BUILD_ARRAY,
+            41 => OP_INDEX_GET,
+            42 => OP_INDEX_SET,
+            43 => OP_SLICE,
+            44 => OP_BUILD_MAP,
+            45 => OP_IN,
+            46 => OP_LEN,
+            47 => OP_ITER_ITEM,
+            48 => OP_CLASS,
+            49 => OP_METHOD,
+            50 => OP_GET_PROPERTY,
+            51 => OP_SET_PROPERTY,
+            52 => OP_INVOKE,
+            53 => OP_INHERIT,
+            54 => OP_GET_SUPER,
+            55 => OP_SUPER_INVOKE,
+            56 => OP_IMPORT,
+            57 => OP_PRINT,
+            58 => OP_BUILD_STRING,
+            59 => OP_GREATER_EQUAL,
+            60 => OP_LESS_EQUAL,
             _ => OP_UNKNOWN
         }
     }
@@ -0,0 +1,212 @@
+/*
+Rendering diagnostics
+
+Lays a diagnostic out the way rustc does: the message, where it is, the
+source lines involved with the spans underlined, then any help. Plain
+output suits logs; colored output adds ANSI escapes for a terminal.
+
+    error[E0102]: Variable 'a' is already declared in this scope.
+     --> main.coy:2:5
+      |
+    1 | let a = 1
+      |     - first declared here
+    2 | let a = 2
+      |     ^
+*/
+
+use crate::ast::Span ;
+use crate::diagnostics::* ;
+
+use std::collections::HashMap ;
+use std::fs ;
+
+const RESET: &str = "\x1b[0m" ;
+const BOLD: &str = "\x1b[1m" ;
+const RED: &str = "\x1b[1;31m" ;
+const YELLOW: &str = "\x1b[1;33m" ;
+const BLUE: &str = "\x1b[1;34m" ;
+
+/**
+Renders diagnostics against the source they were found in. Sources added
+with addSource are used first; any other file is read from disk the
+first time a diagnostic needs it, and rendered without a snippet if it
+can't be.
+*/
+pub struct Renderer {
+    color: bool,
+    sources: HashMap<String, Option<Vec<String>>>,
+}
+
+// A span to underline, with ^ for the diagnostic itself and - for a label
+struct Mark<'d> {
+    span: Span,
+    primary: bool,
+    message: &'d str,
+}
+
+impl Renderer {
+
+    pub fn plain() -> Renderer {
+        return Renderer { color: false, sources: HashMap::new() } ;
+    }
+
+    pub fn colored() -> Renderer {
+        return Renderer { color: true, sources: HashMap::new() } ;
+    }
+
+    /**
+    The source diagnostics against file were found in, for a script that
+    wasn't read from a file of that name
+    */
+    pub fn addSource(&mut self, file: &str, source: &str) {
+        self.sources.insert(file.to_string(), Some(source.lines().map(String::from).collect())) ;
+    }
+
+    pub fn render(&mut self, diagnostic: &Diagnostic) -> String {
+        let style = match diagnostic.severity {
+            Severity::ERROR => RED,
+            Severity::WARNING => YELLOW,
+        } ;
+        let mut out = String::new() ;
+        out.push_str(&self.paint(style, &format!("{}[{}]", diagnostic.severity, diagnostic.code.code()))) ;
+        out.push_str(&self.paint(BOLD, &format!(": {}", diagnostic.message))) ;
+        out.push('\n') ;
+
+        let mut marks = vec![Mark { span: diagnostic.span, primary: true, message: "" }] ;
+        marks.extend(diagnostic.labels.iter().map(|label| Mark { span: label.span, primary: false, message: &label.message })) ;
+        marks.retain(|mark| mark.span.line > 0) ;
+        marks.sort_by_key(|mark| (mark.span.line, mark.span.column)) ;
+
+        let lastLine = marks.iter().map(|mark| mark.span.line).max().unwrap_or(diagnostic.span.line) ;
+        let gutter = " ".repeat(lastLine.to_string().len()) ;
+
+        let mut location = format!("{}:{}", diagnostic.file, diagnostic.span.line) ;
+        if diagnostic.span.column > 0 {
+            location.push_str(&format!(":{}", diagnostic.span.column)) ;
+        }
+        out.push_str(&format!("{}{} {}\n", gutter, self.paint(BLUE, "-->"), location)) ;
+
+        let lines = self.sourceLines(&diagnostic.file) ;
+        if let Some(lines) = lines.filter(|lines| marks.iter().all(|mark| mark.span.line <= lines.len())) {
+            out.push_str(&format!("{} {}\n", gutter, self.paint(BLUE, "|"))) ;
+            let mut previous = 0 ;
+            for mark in marks.iter() {
+                // Marks on the same line share its text
+                let line = mark.span.line ;
+                if line != previous {
+                    if previous > 0 && line > previous + 1 {
+                        out.push_str(&format!("{}\n", self.paint(BLUE, "..."))) ;
+                    }
+                    let number = format!("{:>width$} |", line, width = gutter.len()) ;
+                    out.push_str(&format!("{} {}\n", self.paint(BLUE, &number), lines[line - 1])) ;
+                    previous = line ;
+                }
+                out.push_str(&format!("{} {} {}\n", gutter, self.paint(BLUE, "|"), self.underline(&lines[line - 1], mark, style))) ;
+            }
+        }
+
+        if !diagnostic.help.is_empty() {
+            out.push_str(&format!("{} {}\n", gutter, self.paint(BLUE, "|"))) ;
+            for help in diagnostic.help.iter() {
+                out.push_str(&format!("{} {} {}: {}\n", gutter, self.paint(BLUE, "="), self.paint(BOLD, "help"), help)) ;
+            }
+        }
+        return out ;
+    }
+
+    // The marker for one span, lined up under text. Tabs are copied so that
+    // the marker lines up however wide the terminal shows them.
+    fn underline(&self, text: &str, mark: &Mark, style: &str) -> String {
+        let start = mark.span.column.saturating_sub(1) ;
+        let indent: String = text.chars().chain(std::iter::repeat(' '))
+            .take(start)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect() ;
+        // As many characters as the span has bytes for, up to the end of
+        // the line
+        let mut bytes = mark.span.endByte.saturating_sub(mark.span.startByte) ;
+        let width = std::cmp::max(1, text.chars().skip(start).take_while(|c| {
+            let fits = bytes >= c.len_utf8() ;
+            bytes = bytes.saturating_sub(c.len_utf8()) ;
+            fits
+        }).count()) ;
+        let (symbol, style) = if mark.primary { ("^", style) } else { ("-", BLUE) } ;
+        let mut marker = symbol.repeat(width) ;
+        if !mark.message.is_empty() {
+            marker.push_str(&format!(" {}", mark.message)) ;
+        }
+        let marker = self.paint(style, &marker) ;
+        return format!("{}{}", indent, marker) ;
+    }
+
+    fn sourceLines(&mut self, file: &str) -> Option<Vec<String>> {
+        if !self.sources.contains_key(file) {
+            let lines = fs::read_to_string(file).ok().map(|source| source.lines().map(String::from).collect()) ;
+            self.sources.insert(file.to_string(), lines) ;
+        }
+        return self.sources[file].clone() ;
+    }
+
+    fn paint(&self, style: &str, text: &str) -> String {
+        if self.color {
+            return format!("{}{}{}", style, text, RESET) ;
+        }
+        return text.to_string() ;
+    }
+}
+
+/* =============== Unit tests ================= */
+
+#[cfg(test)]
+mod tests {
+
+    use crate::diagnostics::ErrorCode::* ;
+    use crate::render::* ;
+
+    // Only the length of a span, not where it starts, matters here
+    fn span(line: usize, column: usize, len: usize) -> Span {
+        return Span { startByte: 0, endByte: len, line, column } ;
+    }
+
+    #[test]
+    fn renders_snippets() {
+        let mut renderer = Renderer::plain() ;
+        renderer.addSource("main.coy", "let a = 1\nprint a\nlet a = 2\n") ;
+
+        let diagnostic = inFile(vec![Diagnostic::error(E_REDECLARED, "Variable 'a' is already declared in this scope.", span(3, 5, 1))
+            .withLabel(span(1, 5, 1), "first declared here")], "main.coy").remove(0) ;
+        assert_eq!(renderer.render(&diagnostic), "\
+error[E0102]: Variable 'a' is already declared in this scope.
+ --> main.coy:3:5
+  |
+1 | let a = 1
+  |     - first declared here
+...
+3 | let a = 2
+  |     ^
+") ;
+
+        let diagnostic = inFile(vec![Diagnostic::error(E_UNDEFINED, "Undefined variable 'lett'.", span(2, 1, 4))
+            .withHelp("did you mean `let`?")], "main.coy").remove(0) ;
+        assert_eq!(renderer.render(&diagnostic), "\
+error[E0101]: Undefined variable 'lett'.
+ --> main.coy:2:1
+  |
+2 | print a
+  | ^^^^
+  |
+  = help: did you mean `let`?
+") ;
+    }
+
+    #[test]
+    fn renders_without_source() {
+        let mut renderer = Renderer::colored() ;
+        let diagnostic = inFile(vec![Diagnostic::error(E_SYNTAX, "Expect expression.", span(1, 3, 1))],
+                                "/nonexistent/missing.coy").remove(0) ;
+        assert_eq!(renderer.render(&diagnostic), "\
+\x1b[1;31merror[E0002]\x1b[0m\x1b[1m: Expect expression.\x1b[0m
+ \x1b[1;34m-->\x1b[0m /nonexistent/missing.coy:1:3
+") ;
+    }
+}
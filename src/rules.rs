@@ -1,11 +1,15 @@
 use crate::compiler::* ;
+use crate::ast::Expr ;
 
-pub type ParseFn = fn(&mut Compiler,bool) ;
+// Prefix rules start an expression, infix rules continue one by
+// taking the expression parsed so far as their left operand
+pub type ParseFn = fn(&mut Compiler,bool) -> Expr ;
+pub type InfixFn = fn(&mut Compiler,Expr,bool) -> Expr ;
 
 #[derive(Copy, Clone)]
 pub struct ParseRule {
     pub prefix:  Option<ParseFn>,
-    pub infix:   Option<ParseFn>,
+    pub infix:   Option<InfixFn>,
     pub prec:    Precedence,
 }
 
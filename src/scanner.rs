@@ -1,6 +1,7 @@
 use crate::rules::* ;
 use crate::rules::Precedence::* ;
 use crate::compiler::* ;
+use crate::ast::Span ;
 
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub enum TokenType {
@@ -10,7 +11,7 @@ pub enum TokenType {
     T_LEFT_BRACKET, T_RIGHT_BRACKET,
     T_COMMA, T_DOT, T_MINUS, T_PLUS,
     T_SEMICOLON, T_SLASH, T_STAR, T_COLON,
-    T_DOUBLE_COLON,
+    T_DOUBLE_COLON, T_DOT_DOT,
 
     // One or two character tokens.
     T_BANG, T_BANG_EQUAL,
@@ -20,34 +21,48 @@ pub enum TokenType {
 
     // Literals.
     T_IDENTIFIER, T_STRING, T_INTEGER, T_DOUBLE, T_BOOL,
+    // The part of a string before an interpolated expression
+    T_INTERPOLATION,
 
     // Keywords.
     T_AND, T_CLASS, T_ELSE, T_FALSE,
     T_FOR, T_FN, T_IF, T_NIL, T_OR,
     T_PRINT, T_RETURN, T_IMPORT, T_THIS,
     T_TRUE, T_LET, T_LOOP, T_MODULE,
+    T_BREAK, T_CONTINUE, T_IN, T_SUPER,
 
     T_ERROR,
     T_EOF,
-    T_START,
-    T_CR
+    T_START
 }
 
 impl TokenType {
 
     pub fn get_rule(&self) -> ParseRule {
         match self {
-            T_LEFT_PAREN => ParseRule{prefix: Some(Compiler::grouping), infix: None, prec: PREC_NONE},
+            T_LEFT_PAREN => ParseRule{prefix: Some(Compiler::grouping), infix: Some(Compiler::call), prec: PREC_CALL},
+            T_LEFT_BRACE => ParseRule{prefix: Some(Compiler::map), infix: None, prec: PREC_NONE},
+            T_IN         =>  ParseRule{prefix: None, infix: Some(Compiler::binary), prec: PREC_COMPARISON} ,
+            T_AND        =>  ParseRule{prefix: None, infix: Some(Compiler::and_), prec: PREC_AND} ,
+            T_OR         =>  ParseRule{prefix: None, infix: Some(Compiler::or_), prec: PREC_OR} ,
+            T_LEFT_BRACKET => ParseRule{prefix: Some(Compiler::array), infix: Some(Compiler::index), prec: PREC_INDEX},
+            T_DOT        =>  ParseRule{prefix: None, infix: Some(Compiler::dot), prec: PREC_CALL} ,
+            T_THIS       =>  ParseRule{prefix: Some(Compiler::this), infix: None, prec: PREC_NONE} ,
+            T_SUPER      =>  ParseRule{prefix: Some(Compiler::super_), infix: None, prec: PREC_NONE} ,
             T_INTEGER    =>  ParseRule{prefix: Some(Compiler::integer), infix: None, prec: PREC_NONE} ,
             T_DOUBLE     =>  ParseRule{prefix: Some(Compiler::double), infix: None, prec: PREC_NONE} ,
             T_MINUS      =>  ParseRule{prefix: Some(Compiler::unary), infix: Some(Compiler::binary), prec: PREC_TERM} ,
             T_PLUS       =>  ParseRule{prefix: None, infix: Some(Compiler::binary), prec: PREC_TERM} ,
             T_STAR       =>  ParseRule{prefix: None, infix: Some(Compiler::binary), prec: PREC_FACTOR} ,
             T_SLASH      =>  ParseRule{prefix: None, infix: Some(Compiler::binary), prec: PREC_FACTOR} ,
+            T_IDENTIFIER =>  ParseRule{prefix: Some(Compiler::variable), infix: None, prec: PREC_NONE} ,
+            T_STRING     =>  ParseRule{prefix: Some(Compiler::string), infix: None, prec: PREC_NONE} ,
+            T_INTERPOLATION => ParseRule{prefix: Some(Compiler::interpolation), infix: None, prec: PREC_NONE} ,
             T_FALSE      =>  ParseRule{prefix: Some(Compiler::literal), infix: None, prec: PREC_NONE} ,
             T_TRUE       =>  ParseRule{prefix: Some(Compiler::literal), infix: None, prec: PREC_NONE} ,
             T_NIL        =>  ParseRule{prefix: Some(Compiler::literal), infix: None, prec: PREC_NONE} ,
             T_BANG       =>  ParseRule{prefix: Some(Compiler::unary), infix: None, prec: PREC_NONE} ,
+            T_BANG_EQUAL    =>  ParseRule{prefix: None, infix: Some(Compiler::binary), prec: PREC_EQUALITY} ,
             T_EQUAL_EQUAL   =>  ParseRule{prefix: None, infix: Some(Compiler::binary), prec: PREC_EQUALITY} ,
             T_GREATER       =>  ParseRule{prefix: None, infix: Some(Compiler::binary), prec: PREC_COMPARISON} ,
             T_GREATER_EQUAL =>  ParseRule{prefix: None, infix: Some(Compiler::binary), prec: PREC_COMPARISON},
@@ -62,7 +77,7 @@ impl TokenType {
 
 use TokenType::* ;
 
-pub static TOKEN_LIST: [TokenType;49] =
+pub static TOKEN_LIST: [TokenType;54] =
     [T_LEFT_PAREN, T_RIGHT_PAREN, T_LEFT_BRACE,
         T_RIGHT_BRACE, T_LEFT_BRACKET, T_RIGHT_BRACKET,
         T_COMMA, T_DOT, T_MINUS,
@@ -71,84 +86,118 @@ pub static TOKEN_LIST: [TokenType;49] =
         T_BANG_EQUAL, T_EQUAL, T_EQUAL_EQUAL,
         T_GREATER, T_GREATER_EQUAL, T_LESS,
         T_LESS_EQUAL, T_DOUBLE_COLON, T_IDENTIFIER, T_STRING,
-        T_DOUBLE, T_INTEGER, T_BOOL,
+        T_DOUBLE, T_INTEGER, T_BOOL, T_INTERPOLATION,
         T_AND, T_CLASS, T_ELSE,
         T_FALSE, T_FOR, T_FN,
         T_IF, T_NIL, T_OR,
         T_PRINT, T_RETURN, T_IMPORT,
         T_THIS, T_TRUE, T_LET,
-        T_LOOP, T_MODULE, T_ERROR, T_EOF, T_START, T_CR] ;
+        T_LOOP, T_MODULE, T_BREAK,
+        T_CONTINUE, T_IN, T_DOT_DOT,
+        T_SUPER,
+        T_ERROR, T_EOF, T_START] ;
+
+// The words identifierType reserves
+pub static KEYWORDS: [&str; 21] =
+    ["and", "or", "if", "return", "print", "let", "true", "for", "loop",
+        "break", "continue", "in", "import", "module", "class", "fn",
+        "this", "super", "false", "nil", "else"] ;
 
 macro_rules! SCANNER_NAME {
         ($scanner:expr) => {{
             let start = $scanner.start ;
             let current = $scanner.current ;
-            $scanner.code[start..current].into_iter().collect()
+            $scanner.code[start..current].to_string()
         }}
     }
 
 #[derive(PartialEq)]
 #[derive(Clone)]
 pub struct Token {
+    // The source of the token, except that a string's is its value and an
+    // error's is its message
     pub name: String,
     pub toktype: TokenType,
-    pub line: usize
+    pub span: Span
 }
 
+/**
+The scanner walks the source a character at a time, but start and current
+are byte offsets into it so that they can go straight into a Span.
+*/
 pub struct Scanner {
-    code: Vec<char> ,
+    code: String ,
     start: usize,
     current: usize,
-    line: usize
+    line: usize,
+    // The column current is at, counted in characters as it advances, and
+    // the line and column the token being scanned starts at. A string
+    // running over several lines ends on a later line than it starts.
+    column: usize,
+    startLine: usize,
+    startColumn: usize,
+    // One entry per interpolated expression being scanned, counting the
+    // braces open inside it, so that the '}' ending it can be told apart
+    interpolations: Vec<usize>
 }
 
 pub fn newScanner(source: String) -> Scanner {
     return Scanner {
-        code: source.chars().collect(),
+        code: source,
         start: 0 ,
         current: 0,
-        line: 1
+        line: 1,
+        column: 1,
+        startLine: 1,
+        startColumn: 1,
+        interpolations: Vec::new()
     }
 }
 
 impl Scanner {
     fn advance(&mut self) -> char {
-        self.current += 1;
-        return self.code[self.current - 1];
+        let c = self.peek();
+        self.current += c.len_utf8();
+        self.column += 1;
+        return c;
     }
 
     fn cmatch(&mut self, expected: char) -> bool {
         if self.isAtEnd() {
             return false;
         }
-        if self.code[self.current] != expected {
+        if self.peek() != expected {
             return false;
         }
-        self.current += 1;
+        self.advance();
         return true;
     }
 
     fn peek(&self) -> char {
-        let current = self.current;
-        return self.code[current];
+        return self.code[self.current..].chars().next().unwrap_or('\0');
     }
 
     fn peekNext(&self) -> char {
-        let current = self.current - 1;
-        return self.code[current];
+        return self.code[self.current..].chars().nth(1).unwrap_or('\0');
     }
 
     pub fn scanToken(&mut self) -> Token {
-        if self.isAtEnd() {
-            return self.makeToken(T_EOF);
-        }
-
         self.skipWhitespace();
 
         self.start = self.current;
+        self.startLine = self.line;
+        self.startColumn = self.column;
+
+        if self.isAtEnd() {
+            return self.makeToken(T_EOF);
+        }
 
         let c = self.advance();
 
+        if c == 'r' && self.cmatch('"') {
+            return self.rawString();
+        }
+
         if c.is_alphabetic() || c == '_' {
             return self.identifier();
         }
@@ -160,11 +209,33 @@ impl Scanner {
         return match c {
             '(' => self.makeToken(T_LEFT_PAREN),
             ')' => self.makeToken(T_RIGHT_PAREN),
-            '{' => self.makeToken(T_LEFT_BRACE),
-            '}' => self.makeToken(T_RIGHT_BRACE),
+            '{' => {
+                if let Some(depth) = self.interpolations.last_mut() {
+                    *depth += 1;
+                }
+                self.makeToken(T_LEFT_BRACE)
+            },
+            '}' => match self.interpolations.last_mut() {
+                // The end of an interpolated expression, and the string goes on
+                Some(0) => {
+                    self.interpolations.pop();
+                    self.string()
+                },
+                Some(depth) => {
+                    *depth -= 1;
+                    self.makeToken(T_RIGHT_BRACE)
+                },
+                None => self.makeToken(T_RIGHT_BRACE),
+            },
+            '[' => self.makeToken(T_LEFT_BRACKET),
+            ']' => self.makeToken(T_RIGHT_BRACKET),
             ';' => self.makeToken(T_SEMICOLON),
             ',' => self.makeToken(T_COMMA),
-            '.' => self.makeToken(T_DOT),
+            '.' => if self.cmatch('.') {
+                    self.makeToken(T_DOT_DOT)
+                } else {
+                    self.makeToken(T_DOT)
+                },
             '-' => self.makeToken(T_MINUS),
             '+' => self.makeToken(T_PLUS),
             '/' => self.makeToken(T_SLASH),
@@ -197,21 +268,26 @@ impl Scanner {
                 } else {
                     self.makeToken(T_LESS)
                 },
-            '"' => return self.string(),
-            '\n'=> self.makeToken(T_CR),
+            '"' => if self.code[self.current..].starts_with("\"\"") {
+                    self.advance();
+                    self.advance();
+                    self.blockString()
+                } else {
+                    self.string()
+                },
             _ => self.errorToken("Unexpected character".to_string())
         }
     }
 
     pub fn isAtEnd(&self) -> bool {
-        return self.current >= self.code.len()-1 ;
+        return self.current >= self.code.len() ;
     }
 
     pub fn makeToken(&self, tokType: TokenType) -> Token {
         return Token {
             name: SCANNER_NAME!(self),
             toktype: tokType,
-            line: self.line,
+            span: self.span(),
         }
     }
 
@@ -219,10 +295,25 @@ impl Scanner {
         return Token {
             name: message,
             toktype: T_ERROR,
-            line: self.line,
+            span: self.span(),
         }
     }
 
+    // Where the token being scanned is, up to what has been scanned of it
+    fn span(&self) -> Span {
+        return Span {
+            startByte: self.start,
+            endByte: self.current,
+            line: self.startLine,
+            column: self.startColumn,
+        } ;
+    }
+
+    fn newLine(&mut self) {
+        self.line += 1;
+        self.column = 1;
+    }
+
     fn skipWhitespace(&mut self) {
         loop {
             if self.isAtEnd() {
@@ -234,16 +325,12 @@ impl Scanner {
                 | ' '
                 | '\t' => { self.advance(); },
                 '\n' => {
-                    self.line += 1;
                     self.advance();
+                    self.newLine();
                 },
-                '/' => {
-                    if self.peekNext() == '/' {
-                        while self.peek() != '\n' && !self.isAtEnd() {
-                            self.advance();
-                        }
-                    } else {
-                        return;
+                '/' if self.peekNext() == '/' => {
+                    while self.peek() != '\n' && !self.isAtEnd() {
+                        self.advance();
                     }
                 },
                 _ => return
@@ -257,14 +344,20 @@ impl Scanner {
             "or" => T_OR,
             "if" => T_IF,
             "return" => T_RETURN,
+            "print" => T_PRINT,
             "let" => T_LET,
             "true" => T_TRUE,
             "for" => T_FOR,
             "loop" => T_LOOP,
+            "break" => T_BREAK,
+            "continue" => T_CONTINUE,
+            "in" => T_IN,
             "import" => T_IMPORT,
+            "module" => T_MODULE,
             "class" => T_CLASS,
             "fn" => T_FN,
             "this" => T_THIS,
+            "super" => T_SUPER,
             "false" => T_FALSE,
             "nil" => T_NIL,
             "else" => T_ELSE,
@@ -296,18 +389,309 @@ impl Scanner {
         return self.makeToken( T_INTEGER);
     }
 
+    // Like advance, keeping count of the lines passed
+    fn advanceLine(&mut self) -> char {
+        let c = self.advance();
+        if c == '\n' {
+            self.newLine();
+        }
+        return c;
+    }
+
+    /*
+    "..." once the opening quote has been consumed, or the rest of one once
+    the '}' of an interpolated expression has. The contents are found
+    first, skipping over escaped characters, and then unescaped. A "${"
+    ends the token as a T_INTERPOLATION, and the tokens of the expression
+    come next.
+    */
     fn string(&mut self) -> Token {
-        while self.peek() != '"' && !self.isAtEnd() {
-            if self.peek() == '\n' {
-                self.line += 1;
-                self.advance();
+        let contentStart = self.current;
+        while self.peek() != '"' && !self.code[self.current..].starts_with("${") && !self.isAtEnd() {
+            if self.advanceLine() == '\\' && !self.isAtEnd() {
+                self.advanceLine();
             }
         }
         if self.isAtEnd() {
-            return self.errorToken("Unterminated string".to_string());
+            return self.errorToken("Unterminated string.".to_string());
         }
+        let contentEnd = self.current;
+        let toktype = if self.peek() == '"' {
+            self.advance();
+            T_STRING
+        } else {
+            self.advance();
+            self.advance();
+            self.interpolations.push(0);
+            T_INTERPOLATION
+        };
+
+        return match self.unescape(contentStart, contentEnd) {
+            Ok(value) => Token { name: value, toktype, span: self.span() },
+            Err(error) => error,
+        };
+    }
 
+    // r"..." once the r and the opening quote have been consumed. Nothing in
+    // it is escaped, so it can't hold a quote.
+    fn rawString(&mut self) -> Token {
+        let contentStart = self.current;
+        while self.peek() != '"' && !self.isAtEnd() {
+            self.advanceLine();
+        }
+        if self.isAtEnd() {
+            return self.errorToken("Unterminated string.".to_string());
+        }
+        let value = self.code[contentStart..self.current].to_string();
         self.advance();
-        return self.makeToken( T_STRING);
+        return self.stringToken(value);
+    }
+
+    /*
+    """...""" once the opening quotes have been consumed. A line break
+    straight after the opening quotes and the line holding the closing
+    quotes, if it is only indentation, are left out, and the indentation
+    the remaining lines share is stripped before escapes are processed.
+    Unlike "...", the contents aren't interpolated: ${ is kept as it is.
+    */
+    fn blockString(&mut self) -> Token {
+        let contentStart = self.current;
+        while !self.code[self.current..].starts_with("\"\"\"") && !self.isAtEnd() {
+            if self.advanceLine() == '\\' && !self.isAtEnd() {
+                self.advanceLine();
+            }
+        }
+        if self.isAtEnd() {
+            return self.errorToken("Unterminated string.".to_string());
+        }
+        let contentEnd = self.current;
+        for _ in 0..3 {
+            self.advance();
+        }
+
+        // Byte ranges of the lines of the contents
+        let mut lines = Vec::new();
+        let mut lineStart = contentStart;
+        for (i, c) in self.code[contentStart..contentEnd].char_indices() {
+            if c == '\n' {
+                lines.push((lineStart, contentStart + i));
+                lineStart = contentStart + i + 1;
+            }
+        }
+        lines.push((lineStart, contentEnd));
+
+        let isBlank = |&(start, end): &(usize, usize)| self.code[start..end].chars().all(|c| c == ' ' || c == '\t');
+        if lines.len() > 1 && isBlank(lines.last().unwrap()) {
+            lines.pop();
+        }
+        let mut first = 0;
+        if lines.len() > 1 && isBlank(&lines[0]) {
+            lines.remove(0);
+        } else {
+            // Text on the line of the opening quotes has no indentation
+            first = 1;
+        }
+        let indentOf = |&(start, end): &(usize, usize)| self.code[start..end].len() - self.code[start..end].trim_start_matches([' ', '\t']).len();
+        let indent = lines.iter().skip(first).filter(|line| !isBlank(line)).map(indentOf).min().unwrap_or(0);
+
+        let mut value = String::new();
+        for (i, &(start, end)) in lines.iter().enumerate() {
+            if i > 0 {
+                value.push('\n');
+            }
+            let start = if i < first { start } else { std::cmp::min(start + indent, end) };
+            match self.unescape(start, end) {
+                Ok(line) => value.push_str(&line),
+                Err(error) => return error,
+            }
+        }
+        return self.stringToken(value);
+    }
+
+    // The value of the string contents in code[start..end], with its escape
+    // sequences replaced. An invalid one is reported where it is.
+    fn unescape(&self, start: usize, end: usize) -> Result<String, Token> {
+        let mut value = String::new();
+        let mut chars = self.code[start..end].char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            if c != '\\' {
+                value.push(c);
+                continue;
+            }
+            let escapeStart = start + i;
+            let escaped = match chars.next() {
+                Some((_, 'n')) => '\n',
+                Some((_, 't')) => '\t',
+                Some((_, 'r')) => '\r',
+                Some((_, '0')) => '\0',
+                Some((_, '"')) => '"',
+                Some((_, '\\')) => '\\',
+                Some((_, '$')) => '$',
+                Some((j, 'u')) => {
+                    // \u{1F600}: one to six hex digits between braces
+                    let mut escapeEnd = start + j + 1;
+                    let mut digits = String::new();
+                    let opened = chars.next_if(|&(_, c)| c == '{').is_some();
+                    if opened {
+                        escapeEnd += 1;
+                        while let Some((_, digit)) = chars.next_if(|(_, c)| c.is_ascii_hexdigit()) {
+                            digits.push(digit);
+                            escapeEnd += 1;
+                        }
+                    }
+                    let closed = opened && chars.next_if(|&(_, c)| c == '}').is_some();
+                    if closed {
+                        escapeEnd += 1;
+                    }
+                    let code = if closed && !digits.is_empty() && digits.len() <= 6 {
+                        u32::from_str_radix(&digits, 16).ok()
+                    } else {
+                        return Err(self.errorTokenAt("Expect 1 to 6 hex digits between braces after '\\u'.".to_string(), escapeStart, escapeEnd));
+                    };
+                    match code.and_then(char::from_u32) {
+                        Some(c) => c,
+                        None => return Err(self.errorTokenAt(format!("'\\u{{{}}}' is not a unicode character.", digits), escapeStart, escapeEnd)),
+                    }
+                }
+                Some((j, c)) => {
+                    return Err(self.errorTokenAt(format!("Unknown escape sequence '\\{}'.", c.escape_default()), escapeStart, start + j + c.len_utf8()));
+                }
+                None => {
+                    return Err(self.errorTokenAt("Expect a character after '\\'.".to_string(), escapeStart, escapeStart + 1));
+                }
+            };
+            value.push(escaped);
+        }
+        return Ok(value);
     }
-}
\ No newline at end of file
+
+    // For strings, name is their value rather than the source they came from
+    fn stringToken(&self, value: String) -> Token {
+        return Token {
+            name: value,
+            toktype: T_STRING,
+            span: self.span(),
+        }
+    }
+
+    // An error about code[start..end], within the token being scanned,
+    // rather than the whole token
+    fn errorTokenAt(&self, message: String, start: usize, end: usize) -> Token {
+        let before = &self.code[self.start..start];
+        let column = match before.rfind('\n') {
+            Some(i) => before[i + 1..].chars().count() + 1,
+            None => self.startColumn + before.chars().count(),
+        };
+        return Token {
+            name: message,
+            toktype: T_ERROR,
+            span: Span {
+                startByte: start,
+                endByte: end,
+                line: self.startLine + before.matches('\n').count(),
+                column,
+            },
+        }
+    }
+}
+/* =============== Unit tests ================= */
+
+#[cfg(test)]
+mod tests {
+
+    use crate::scanner::* ;
+
+    fn tokens(source: &str) -> Vec<Token> {
+        let mut scanner = newScanner(source.to_string()) ;
+        let mut tokens = Vec::new() ;
+        loop {
+            let token = scanner.scanToken() ;
+            if token.toktype == T_EOF {
+                return tokens ;
+            }
+            tokens.push(token) ;
+        }
+    }
+
+    fn string_value(source: &str) -> String {
+        let token = tokens(source).remove(0) ;
+        assert_eq!(token.toktype, T_STRING, "{}", token.name) ;
+        return token.name ;
+    }
+
+    #[test]
+    fn scans_string_escapes() {
+        assert_eq!(string_value(r#""a\nb\t\"c\" \\ \u{1F600}\u{e9}""#), "a\nb\t\"c\" \\ \u{1F600}\u{e9}") ;
+        assert_eq!(string_value("\"two\nlines\""), "two\nlines") ;
+        assert_eq!(string_value(r#"r"C:\dir\n""#), r"C:\dir\n") ;
+
+        // The whole string is one token, whatever it holds
+        let scanned = tokens(r#"x = "a\"b" + r"\" y"#) ;
+        assert_eq!(scanned.iter().map(|t| t.toktype).collect::<Vec<_>>(),
+                   vec![T_IDENTIFIER, T_EQUAL, T_STRING, T_PLUS, T_STRING, T_IDENTIFIER]) ;
+        assert_eq!(scanned[4].name, "\\") ;
+    }
+
+    #[test]
+    fn tracks_columns() {
+        // Columns count characters, and restart after a string's line breaks
+        let scanned = tokens("é = \"a\nbc\" + x\n\t  y") ;
+        let positions: Vec<(usize, usize)> = scanned.iter().map(|t| (t.span.line, t.span.column)).collect() ;
+        assert_eq!(positions, vec![(1, 1), (1, 3), (1, 5), (2, 5), (2, 7), (3, 4)]) ;
+
+        let long = "x + ".repeat(10000) + "y" ;
+        assert_eq!(tokens(&long).last().unwrap().span.column, 40001) ;
+    }
+
+    #[test]
+    fn scans_interpolated_strings() {
+        // The quotes and braces inside an interpolated expression are its own
+        let scanned = tokens(r#""a ${ f("}") + "${ {} }" } b \${c}" x"#) ;
+        assert_eq!(scanned.iter().map(|t| t.toktype).collect::<Vec<_>>(),
+                   vec![T_INTERPOLATION, T_IDENTIFIER, T_LEFT_PAREN, T_STRING, T_RIGHT_PAREN, T_PLUS,
+                        T_INTERPOLATION, T_LEFT_BRACE, T_RIGHT_BRACE, T_STRING, T_STRING, T_IDENTIFIER]) ;
+        let names: Vec<&str> = scanned.iter().map(|t| t.name.as_str()).collect() ;
+        assert_eq!(names[0], "a ") ;
+        assert_eq!(names[3], "}") ;
+        assert_eq!(names[6], "") ;
+        assert_eq!(names[10], " b ${c}") ;
+    }
+
+    #[test]
+    fn scans_block_strings() {
+        let source = "let s = \"\"\"\n    Dear \\\"you\\\",\n\n      indented\n    bye\n    \"\"\"\nx" ;
+        let scanned = tokens(source) ;
+        assert_eq!(scanned[3].name, "Dear \"you\",\n\n  indented\nbye") ;
+        assert_eq!(scanned[3].span.line, 1) ;
+        assert_eq!((scanned[4].name.as_str(), scanned[4].span.line), ("x", 7)) ;
+
+        assert_eq!(string_value("\"\"\"one line\"\"\""), "one line") ;
+        assert_eq!(string_value("\"\"\"first\n    second\n  \"\"\""), "first\nsecond") ;
+        assert_eq!(string_value("\"\"\"\n  a \"quote\"\n  \"\"\""), "a \"quote\"") ;
+        assert_eq!(string_value("\"\"\"\"\"\""), "") ;
+        assert_eq!(string_value("\"\"\"\n    \"\"\""), "") ;
+        // Only plain strings are interpolated
+        assert_eq!(string_value("\"\"\"\n    total: ${a + b}\n    \"\"\""), "total: ${a + b}") ;
+        assert_eq!(string_value("r\"${a}\""), "${a}") ;
+    }
+
+    #[test]
+    fn reports_invalid_escapes_where_they_are() {
+        let error = |source: &str| {
+            let token = tokens(source).remove(0) ;
+            assert_eq!(token.toktype, T_ERROR) ;
+            return (token.name, token.span.line, token.span.column, token.span.endByte - token.span.startByte) ;
+        } ;
+        assert_eq!(error("\"ok \\q\""), ("Unknown escape sequence '\\q'.".to_string(), 1, 5, 2)) ;
+        assert_eq!(error("\"é\n  \\u{110000}\""), ("'\\u{110000}' is not a unicode character.".to_string(), 2, 3, 10)) ;
+        assert_eq!(error("\"\\u{}\"").0, "Expect 1 to 6 hex digits between braces after '\\u'.") ;
+        assert_eq!(error("\"\\u12\"").3, 2) ;
+        assert_eq!(error("\"\"\"\n    \\x\n    \"\"\"").1, 2) ;
+        assert_eq!(error("\"open").0, "Unterminated string.") ;
+        assert_eq!(error("r\"open").0, "Unterminated string.") ;
+
+        // Scanning carries on after the string with the bad escape
+        let scanned = tokens("\"\\q\" 1") ;
+        assert_eq!(scanned[1].toktype, T_INTEGER) ;
+    }
+}
@@ -0,0 +1,701 @@
+/*
+Type checking
+
+Walks the AST between parsing and code generation and works out the
+static type of every expression. Binary operators are resolved through
+binops::Binop so that codegen can pick the typed instruction, and
+operand combinations that make no sense (true + 1) are rejected here
+instead of failing at runtime.
+*/
+
+use crate::ast::* ;
+use crate::ast::UnaryOp::* ;
+use crate::binops::* ;
+use crate::diagnostics::* ;
+use crate::diagnostics::ErrorCode::* ;
+use crate::modules::Module ;
+use crate::natives::{isNative, NATIVES} ;
+use crate::scanner::KEYWORDS ;
+use crate::value::ValueType ;
+use crate::value::ValueType::* ;
+
+use std::collections::{HashMap, HashSet} ;
+
+/**
+What the checker knows about a declared variable. A variable takes the
+type of its initializer; one declared without a value can hold anything.
+*/
+#[derive(Copy, Clone)]
+struct Variable {
+    ty: ValueType,
+    initialized: bool,
+    // Where it was declared, for pointing back at it in errors
+    span: Span,
+}
+
+// Words other languages use where we have one of our own
+static FOREIGN_KEYWORDS: [(&str, &str); 9] = [
+    ("var", "let"), ("const", "let"), ("function", "fn"), ("def", "fn"), ("func", "fn"),
+    ("while", "for"), ("elif", "else if"), ("null", "nil"), ("self", "this"),
+] ;
+
+#[derive(Clone)]
+pub struct TypeChecker {
+    // scopes[0] holds the globals, every block pushes another scope
+    scopes: Vec<HashMap<String, Variable>>,
+    // Every global the program declares at the top level. A function body
+    // only runs once it is called, so it may use globals declared after it.
+    hoisted: HashSet<String>,
+    // Globals assigned by a function before their declaration was checked.
+    // What they hold isn't known, so they can hold anything.
+    untyped: HashSet<String>,
+    // What each loaded module exports, and which of them the program imports
+    modules: HashMap<String, Vec<String>>,
+    imported: HashSet<String>,
+    fnDepth: usize,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl TypeChecker {
+
+    pub fn new() -> TypeChecker {
+        return TypeChecker {
+            scopes: vec![HashMap::new()],
+            hoisted: HashSet::new(),
+            untyped: HashSet::new(),
+            modules: HashMap::new(),
+            imported: HashSet::new(),
+            fnDepth: 0,
+            diagnostics: Vec::new(),
+        } ;
+    }
+
+    /* Scopes */
+
+    fn beginScope(&mut self) {
+        self.scopes.push(HashMap::new()) ;
+    }
+
+    fn endScope(&mut self) {
+        self.scopes.pop() ;
+    }
+
+    fn declare(&mut self, span: Span, name: &str) {
+        let scope = self.scopes.last_mut().unwrap() ;
+        if let Some(existing) = scope.get(name) {
+            let message = format!("Variable '{}' is already declared in this scope.", name) ;
+            let mut diagnostic = Diagnostic::error(E_REDECLARED, message.as_str(), span) ;
+            if existing.span.line > 0 {
+                diagnostic = diagnostic.withLabel(existing.span, "first declared here") ;
+            }
+            self.diagnostics.push(diagnostic) ;
+            return ;
+        }
+        scope.insert(name.to_string(), Variable { ty: VAL_ANY, initialized: false, span }) ;
+    }
+
+    fn define(&mut self, name: &str, ty: ValueType) {
+        let scope = self.scopes.last_mut().unwrap() ;
+        let var = scope.entry(name.to_string())
+            .or_insert(Variable { ty, initialized: true, span: Span::default() }) ;
+        var.ty = ty ;
+        var.initialized = true ;
+    }
+
+    fn resolve(&mut self, span: Span, name: &str) -> Option<Variable> {
+        let mut found = self.scopes.iter().rev().find_map(|scope| scope.get(name).copied()) ;
+        if found.is_none() && self.fnDepth > 0 && self.hoisted.contains(name) {
+            found = Some(Variable { ty: VAL_ANY, initialized: true, span: Span::default() }) ;
+        }
+        if found.is_none() && isNative(name) {
+            found = Some(Variable { ty: VAL_OBJ, initialized: true, span: Span::default() }) ;
+        }
+        match found {
+            None => {
+                let message = format!("Undefined variable '{}'.", name) ;
+                let diagnostic = Diagnostic::error(E_UNDEFINED, message.as_str(), span) ;
+                let diagnostic = match self.suggest(name) {
+                    Some(suggestion) => diagnostic.withHelp(format!("did you mean `{}`?", suggestion).as_str()),
+                    None => diagnostic,
+                } ;
+                self.diagnostics.push(diagnostic) ;
+            }
+            Some(var) if !var.initialized => {
+                self.error(span, E_UNDEFINED, format!("Can't read variable '{}' in its own initializer.", name).as_str()) ;
+            }
+            _ => {}
+        }
+        return found ;
+    }
+
+    // What an unresolved name was probably meant to be: one of our keywords
+    // if it is another language's, otherwise a variable, native or keyword
+    // it is a typo away from
+    fn suggest(&self, name: &str) -> Option<String> {
+        if let Some((_, ours)) = FOREIGN_KEYWORDS.iter().find(|(theirs, _)| *theirs == name) {
+            return Some(ours.to_string()) ;
+        }
+        let candidates = self.scopes.iter().flat_map(|scope| scope.keys())
+            .chain(self.hoisted.iter())
+            .map(|candidate| candidate.as_str())
+            .chain(NATIVES.iter().map(|(native, _, _)| *native))
+            .chain(KEYWORDS.iter().copied()) ;
+        return closest(name, candidates).map(String::from) ;
+    }
+
+    /**
+    Type check a program after any others this checker has seen, whose
+    globals and imports are still in scope
+    */
+    pub fn check(&mut self, program: &mut [Stmt], modules: &[Module]) -> Result<(),Vec<Diagnostic>> {
+        for module in modules {
+            self.modules.insert(module.name.clone(), module.exports.clone()) ;
+        }
+        // Where an earlier program declared its globals is no place in this one
+        for var in self.scopes[0].values_mut() {
+            var.span = Span::default() ;
+        }
+        self.program(program) ;
+
+        if !self.diagnostics.is_empty() {
+            // Function bodies are checked last, but their errors go in order
+            self.diagnostics.sort_by_key(|diagnostic| diagnostic.span.startByte) ;
+            return Result::Err(std::mem::take(&mut self.diagnostics));
+        }
+        return Result::Ok(());
+    }
+
+    /**
+    The bodies of the functions and methods declared at the top level are
+    checked after everything else there, so that the globals they use have
+    their types by then, wherever they are declared
+    */
+    pub fn program(&mut self, program: &mut [Stmt]) {
+        let topLevel = self.scopes.len() == 1 ;
+        if topLevel {
+            for (i, stmt) in program.iter().enumerate() {
+                match &stmt.kind {
+                    StmtKind::Let { name, .. } | StmtKind::Fn { name, .. } | StmtKind::Class { name, .. } => {
+                        self.hoisted.insert(name.clone()) ;
+                    }
+                    StmtKind::Module(_) if i > 0 => {
+                        self.error(stmt.span, E_MISPLACED, "A module declaration must be the first statement.") ;
+                    }
+                    StmtKind::Import(name) => {
+                        if !self.modules.contains_key(name) {
+                            self.error(stmt.span, E_MODULE, format!("Module '{}' has not been loaded.", name).as_str()) ;
+                        }
+                        self.imported.insert(name.clone()) ;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        for stmt in program.iter_mut() {
+            self.statement(stmt) ;
+        }
+        if topLevel {
+            for stmt in program.iter_mut() {
+                self.bodies(stmt) ;
+            }
+        }
+    }
+
+    // The bodies of a function or class declaration
+    fn bodies(&mut self, stmt: &mut Stmt) {
+        match &mut stmt.kind {
+            StmtKind::Fn { params, body, .. } => self.function(stmt.span, params, body),
+            StmtKind::Class { methods, .. } => {
+                for method in methods.iter_mut() {
+                    self.bodies(method) ;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // The type a global declared at the top level gets, given the type of
+    // its value
+    fn globalType(&self, name: &str, ty: ValueType) -> ValueType {
+        if self.scopes.len() == 1 && self.untyped.contains(name) {
+            return VAL_ANY ;
+        }
+        return ty ;
+    }
+
+    fn statement(&mut self, stmt: &mut Stmt) {
+        match &mut stmt.kind {
+            StmtKind::Expression(expr) | StmtKind::Print(expr) => { self.expression(expr) ; }
+            StmtKind::Block(statements) => {
+                self.beginScope() ;
+                self.program(statements) ;
+                self.endScope() ;
+            }
+            StmtKind::Let { name, initializer } => {
+                self.declare(stmt.span, name) ;
+                let ty = match initializer {
+                    Some(expr) => match self.expression(expr) {
+                        VAL_NIL => VAL_ANY,
+                        ty => ty,
+                    },
+                    None => VAL_ANY,
+                } ;
+                let ty = self.globalType(name, ty) ;
+                self.define(name, ty) ;
+            }
+            StmtKind::If { condition, then_branch, else_branch } => {
+                self.expression(condition) ;
+                self.beginScope() ;
+                self.program(then_branch) ;
+                self.endScope() ;
+                if let Some(stmt) = else_branch {
+                    self.statement(stmt) ;
+                }
+            }
+            StmtKind::Loop { body } => {
+                self.beginScope() ;
+                self.program(body) ;
+                self.endScope() ;
+            }
+            StmtKind::Fn { name, .. } => {
+                // Defined before the body is checked so that it can recurse
+                self.declare(stmt.span, name) ;
+                self.define(name, self.globalType(name, VAL_OBJ)) ;
+                if self.scopes.len() > 1 {
+                    self.bodies(stmt) ;
+                }
+            }
+            StmtKind::Class { name, superclass, .. } => {
+                self.declare(stmt.span, name) ;
+                self.define(name, self.globalType(name, VAL_OBJ)) ;
+                // Whether it really is a class is only known at runtime
+                if let Some(superclass) = superclass {
+                    let stype = self.expression(superclass) ;
+                    if stype != VAL_OBJ && stype != VAL_ANY {
+                        self.error(stmt.span, E_TYPE, format!("Superclass must be a class, found {}.", stype).as_str()) ;
+                    }
+                }
+                if self.scopes.len() > 1 {
+                    self.bodies(stmt) ;
+                }
+            }
+            StmtKind::While { condition, body } => {
+                self.expression(condition) ;
+                self.beginScope() ;
+                self.program(body) ;
+                self.endScope() ;
+            }
+            StmtKind::ForRange { name, start, end, body } => {
+                let span = stmt.span ;
+                let stype = self.expression(start) ;
+                let etype = self.expression(end) ;
+                for bound in [stype, etype] {
+                    if bound != VAL_INTEGER && bound != VAL_ANY {
+                        self.error(span, E_TYPE, format!("Range bounds must be integers, found {}.", bound).as_str()) ;
+                    }
+                }
+
+                // The loop variable gets a scope of its own around the body
+                self.beginScope() ;
+                self.define(name, stype) ;
+                self.beginScope() ;
+                self.program(body) ;
+                self.endScope() ;
+                self.endScope() ;
+            }
+            StmtKind::ForIn { name, iterable, body } => {
+                let itype = self.expression(iterable) ;
+                if itype != VAL_ARRAY && itype != VAL_MAP && itype != VAL_ANY {
+                    self.error(stmt.span, E_TYPE, format!("Can only iterate over arrays and maps, found {}.", itype).as_str()) ;
+                }
+                self.beginScope() ;
+                self.define(name, VAL_ANY) ;
+                self.beginScope() ;
+                self.program(body) ;
+                self.endScope() ;
+                self.endScope() ;
+            }
+            StmtKind::Break | StmtKind::Continue => {}
+            StmtKind::Module(_) | StmtKind::Import(_) => {
+                if self.scopes.len() > 1 {
+                    self.error(stmt.span, E_MISPLACED, "Modules can only be declared and imported at the top level.") ;
+                }
+            }
+            StmtKind::Return(value) => {
+                if let Some(expr) = value {
+                    self.expression(expr) ;
+                }
+            }
+        }
+    }
+
+    fn function(&mut self, span: Span, params: &[String], body: &mut [Stmt]) {
+        self.beginScope() ;
+        self.fnDepth += 1 ;
+        for param in params.iter() {
+            self.declare(span, param) ;
+            self.define(param, VAL_ANY) ;
+        }
+        self.program(body) ;
+        self.fnDepth -= 1 ;
+        self.endScope() ;
+    }
+
+    /**
+    Works out the type of an expression, records it on the node and
+    returns it
+    */
+    fn expression(&mut self, expr: &mut Expr) -> ValueType {
+        let span = expr.span ;
+        let ty = match &mut expr.kind {
+            ExprKind::Literal(value) => value.get_type(),
+            ExprKind::String(_) => VAL_STRING,
+            ExprKind::Grouping(inner) => self.expression(inner),
+            ExprKind::Unary { op, right } => {
+                let rtype = self.expression(right) ;
+                match op {
+                    U_NEGATE => {
+                        if rtype != VAL_INTEGER && rtype != VAL_DOUBLE && rtype != VAL_ANY {
+                            self.error(span, E_TYPE, format!("Operand of '-' must be a number, found {}.", rtype).as_str()) ;
+                        }
+                        rtype
+                    }
+                    U_NOT => VAL_BOOL,
+                }
+            }
+            ExprKind::Binary { op, left, right } => {
+                let ltype = self.expression(left) ;
+                let rtype = self.expression(right) ;
+                match Binop::new(*op, (ltype, rtype)).resolve() {
+                    Some(typed) => typed.result,
+                    None => {
+                        self.error(span, E_TYPE, format!("Incompatible operand types for '{}': {} and {}.",
+                                                 op.symbol(), ltype, rtype).as_str()) ;
+                        // Carry on as if the types had matched to avoid cascading errors
+                        ltype
+                    }
+                }
+            }
+            // Either operand can be the result
+            ExprKind::Logical { left, right, .. } => {
+                let ltype = self.expression(left) ;
+                let rtype = self.expression(right) ;
+                if ltype == rtype { ltype } else { VAL_ANY }
+            }
+            ExprKind::Assign { name, value } => {
+                let vtype = self.expression(value) ;
+                if self.fnDepth > 0 && self.hoisted.contains(name.as_str())
+                    && !self.scopes.iter().any(|scope| scope.contains_key(name.as_str())) {
+                    self.untyped.insert(name.clone()) ;
+                }
+                match self.resolve(span, name) {
+                    Some(var) if var.ty != VAL_ANY && vtype != VAL_ANY && var.ty != vtype => {
+                        let message = format!("Can't assign {} to variable '{}' of type {}.", vtype, name, var.ty) ;
+                        let mut diagnostic = Diagnostic::error(E_TYPE, message.as_str(), span) ;
+                        if var.span.line > 0 {
+                            diagnostic = diagnostic.withLabel(var.span, format!("declared as {} here", var.ty).as_str()) ;
+                        }
+                        self.diagnostics.push(diagnostic) ;
+                        var.ty
+                    }
+                    _ => vtype,
+                }
+            }
+            ExprKind::Call { callee, args } => {
+                let ctype = self.expression(callee) ;
+                if ctype != VAL_OBJ && ctype != VAL_ANY {
+                    self.error(span, E_TYPE, format!("Can only call functions, found {}.", ctype).as_str()) ;
+                }
+                for arg in args.iter_mut() {
+                    self.expression(arg) ;
+                }
+                // Functions aren't typed, so neither is what they return
+                VAL_ANY
+            }
+            ExprKind::Array(elements) => {
+                for element in elements.iter_mut() {
+                    self.expression(element) ;
+                }
+                VAL_ARRAY
+            }
+            // Any value can be interpolated, as it would be printed
+            ExprKind::Interpolation(parts) => {
+                for part in parts.iter_mut() {
+                    self.expression(part) ;
+                }
+                VAL_STRING
+            }
+            ExprKind::Map(entries) => {
+                for (key, value) in entries.iter_mut() {
+                    let ktype = self.expression(key) ;
+                    self.checkMapKey(span, ktype) ;
+                    self.expression(value) ;
+                }
+                VAL_MAP
+            }
+            ExprKind::Index { object, index } => {
+                self.indexable(span, object, index) ;
+                // Elements aren't typed
+                VAL_ANY
+            }
+            ExprKind::IndexSet { object, index, value } => {
+                self.indexable(span, object, index) ;
+                self.expression(value)
+            }
+            ExprKind::Slice { object, start, end } => {
+                let otype = self.expression(object) ;
+                if otype != VAL_ARRAY && otype != VAL_ANY {
+                    self.error(span, E_TYPE, format!("Can only slice arrays, found {}.", otype).as_str()) ;
+                }
+                for bound in [start, end].into_iter().flatten() {
+                    let btype = self.expression(bound) ;
+                    if btype != VAL_INTEGER && btype != VAL_ANY {
+                        self.error(span, E_TYPE, format!("Slice bounds must be integers, found {}.", btype).as_str()) ;
+                    }
+                }
+                VAL_ARRAY
+            }
+            // Instances aren't typed, and neither are their fields
+            ExprKind::Get { object, .. } => {
+                self.property(span, object) ;
+                VAL_ANY
+            }
+            ExprKind::Set { object, value, .. } => {
+                self.property(span, object) ;
+                self.expression(value)
+            }
+            ExprKind::This | ExprKind::Super { .. } => VAL_ANY,
+            ExprKind::Qualified { module, name } => {
+                if !self.imported.contains(module.as_str()) {
+                    self.error(span, E_MODULE, format!("Module '{}' is not imported.", module).as_str()) ;
+                } else if !self.modules[module.as_str()].contains(name) {
+                    let exports = &self.modules[module.as_str()] ;
+                    let message = format!("Module '{}' has no member '{}'.", module, name) ;
+                    let mut diagnostic = Diagnostic::error(E_MODULE, message.as_str(), span) ;
+                    if let Some(suggestion) = closest(name, exports.iter().map(|export| export.as_str())) {
+                        diagnostic = diagnostic.withHelp(format!("did you mean `{}::{}`?", module, suggestion).as_str()) ;
+                    }
+                    self.diagnostics.push(diagnostic) ;
+                }
+                VAL_ANY
+            }
+            ExprKind::Variable(name) => match self.resolve(span, name) {
+                Some(var) => var.ty,
+                None => VAL_ANY,
+            },
+        } ;
+        expr.ty = Some(ty) ;
+        return ty ;
+    }
+
+    // Checks the object and index of a[i] or a[i] = v
+    fn indexable(&mut self, span: Span, object: &mut Expr, index: &mut Expr) {
+        let otype = self.expression(object) ;
+        let itype = self.expression(index) ;
+        match otype {
+            VAL_ARRAY => {
+                if itype != VAL_INTEGER && itype != VAL_ANY {
+                    self.error(span, E_TYPE, format!("Array index must be an integer, found {}.", itype).as_str()) ;
+                }
+            }
+            VAL_MAP => self.checkMapKey(span, itype),
+            VAL_ANY => {}
+            _ => self.error(span, E_TYPE, format!("Can only index arrays and maps, found {}.", otype).as_str()),
+        }
+    }
+
+    fn property(&mut self, span: Span, object: &mut Expr) {
+        let otype = self.expression(object) ;
+        if otype != VAL_OBJ && otype != VAL_ANY {
+            self.error(span, E_TYPE, format!("Only instances have properties, found {}.", otype).as_str()) ;
+        }
+    }
+
+    fn checkMapKey(&mut self, span: Span, ktype: ValueType) {
+        match ktype {
+            VAL_STRING | VAL_INTEGER | VAL_BOOL | VAL_ANY => {}
+            _ => self.error(span, E_TYPE, format!("Map keys must be strings, integers or booleans, found {}.", ktype).as_str()),
+        }
+    }
+
+    /* Error management*/
+
+    fn error(&mut self, span: Span, code: ErrorCode, message: &str) {
+        self.diagnostics.push(Diagnostic::error(code, message, span)) ;
+    }
+}
+
+impl Default for TypeChecker {
+    fn default() -> Self {
+        return TypeChecker::new() ;
+    }
+}
+
+/**
+Type check a parsed program, annotating every expression with its type.
+modules are the ones loaded for it to import.
+*/
+pub fn check(program: &mut [Stmt], modules: &[Module]) -> Result<(),Vec<Diagnostic>> {
+    return TypeChecker::new().check(program, modules) ;
+}
+
+/* =============== Unit tests ================= */
+
+#[cfg(test)]
+mod tests {
+
+    use crate::compiler::parse ;
+    use crate::typechecker::* ;
+
+    fn check_source(source: &str) -> Result<(),Vec<Diagnostic>> {
+        let mut program = parse(source.to_string())? ;
+        return check(&mut program, &[]) ;
+    }
+
+    fn type_of(source: &str) -> Result<Option<ValueType>,Vec<Diagnostic>> {
+        let mut program = parse(source.to_string())? ;
+        check(&mut program, &[])? ;
+        match &program[0].kind {
+            StmtKind::Expression(e) => Ok(e.ty),
+            _ => Err(Vec::new())
+        }
+    }
+
+    #[test]
+    fn infers_arithmetic() {
+        assert_eq!(type_of("1 + 2 * 3"), Ok(Some(VAL_INTEGER))) ;
+        assert_eq!(type_of("1.5 + 2.0"), Ok(Some(VAL_DOUBLE))) ;
+        assert_eq!(type_of("1 + 2.0"), Ok(Some(VAL_DOUBLE))) ;
+        assert_eq!(type_of("1 < 2.0"), Ok(Some(VAL_BOOL))) ;
+        assert_eq!(type_of("\"a\" + \"b\""), Ok(Some(VAL_STRING))) ;
+        assert!(type_of("\"a\" + 1").is_err()) ;
+        assert!(type_of("\"a\" < \"b\"").is_err()) ;
+    }
+
+    #[test]
+    fn rejects_incompatible_operands() {
+        assert!(type_of("true + 1").is_err()) ;
+        assert!(type_of("-nil").is_err()) ;
+        assert!(type_of("nil < 1").is_err()) ;
+    }
+
+    #[test]
+    fn infers_variables() {
+        assert!(check_source("let x = 1.5; x * 2").is_ok()) ;
+        assert!(check_source("let x; x = true; x = 1").is_ok()) ;
+        assert!(check_source("let x = 1; x = 2.5").is_err()) ;
+        assert!(check_source("let b = true; b + 1").is_err()) ;
+    }
+
+    #[test]
+    fn checks_logical_operators() {
+        assert_eq!(type_of("true and false"), Ok(Some(VAL_BOOL))) ;
+        assert_eq!(type_of("nil or 1"), Ok(Some(VAL_ANY))) ;
+        assert_eq!(type_of("!1"), Ok(Some(VAL_BOOL))) ;
+        assert!(check_source("(1 or 2) + 1").is_ok()) ;
+        assert!(check_source("(true and false) + 1").is_err()) ;
+    }
+
+    #[test]
+    fn checks_range_bounds() {
+        assert!(check_source("for i in 0..10 { i + 1 }").is_ok()) ;
+        assert!(check_source("for i in 0..2.5 { }").is_err()) ;
+    }
+
+    #[test]
+    fn checks_functions() {
+        // Calls can return anything
+        assert!(check_source("fn f() { } let x = f(); x = 1; x = true").is_ok()) ;
+        assert!(check_source("fn f(n) { return n * f(n - 1) }").is_ok()) ;
+        assert!(check_source("fn f() { return g() } fn g() { return 1 }").is_ok()) ;
+        assert!(check_source("fn f(a, a) { }").is_err()) ;
+        assert!(check_source("let x = 1; x()").is_err()) ;
+        assert!(check_source("g() fn g() { }").is_err()) ;
+    }
+
+    #[test]
+    fn checks_globals_declared_later() {
+        // Function bodies see the type a global is declared with below them
+        assert!(check_source("fn f() { x = 2.5 } let x = 1").is_err()) ;
+        assert!(check_source("fn f() { x = 2 } let x = 1").is_ok()) ;
+        assert!(check_source("class A { fn f() { return x + true } } let x = 1").is_err()) ;
+        let diagnostics = check_source("fn f() { x = 2.5 }\nlet x = 1\ny").err().unwrap() ;
+        assert_eq!(diagnostics.iter().map(|d| d.span.line).collect::<Vec<_>>(), vec![1, 3]) ;
+
+        // A function in a block is checked where it is, so a global it
+        // assigns before the declaration can hold anything
+        let mut program = parse("{ fn g() { x = 2.5 } } let x = 1; x + 1".to_string()).ok().unwrap() ;
+        assert!(check(&mut program, &[]).is_ok()) ;
+        match &program[2].kind {
+            StmtKind::Expression(e) => assert_eq!(e.ty, Some(VAL_ANY)),
+            k => panic!("Expected an expression statement, got {:?}", k)
+        }
+    }
+
+    #[test]
+    fn checks_arrays() {
+        assert_eq!(type_of("[1, true, nil]"), Ok(Some(VAL_ARRAY))) ;
+        assert_eq!(type_of("[1, 2][0]"), Ok(Some(VAL_ANY))) ;
+        assert_eq!(type_of("[1, 2][0..1]"), Ok(Some(VAL_ARRAY))) ;
+        assert!(check_source("let a = [1]; a[0] = 2.5; a[0] + 1").is_ok()) ;
+        assert!(check_source("let a = [1]; a = 2").is_err()) ;
+        assert!(check_source("let n = 1; n[0]").is_err()) ;
+        assert!(check_source("[1][true]").is_err()) ;
+        assert!(check_source("[1][0..1.5]").is_err()) ;
+    }
+
+    #[test]
+    fn checks_classes() {
+        assert!(check_source("class A { fn f() { return this.x + 1 } } let a = A(); a.x = 1; a.f()").is_ok()) ;
+        assert!(check_source("fn make() { return A() } class A { }").is_ok()) ;
+        assert!(check_source("class A { } let A = 1").is_err()) ;
+        assert!(check_source("let n = 1; n.x").is_err()) ;
+        assert!(check_source("\"s\".x = 1").is_err()) ;
+    }
+
+    #[test]
+    fn checks_modules() {
+        assert!(check_source("module shapes; let x = 1").is_ok()) ;
+        assert!(check_source("let x = 1; module shapes").is_err()) ;
+        assert!(check_source("fn f() { import geometry }").is_err()) ;
+        // Only modules that have been loaded can be imported or referred to
+        assert!(check_source("import geometry").is_err()) ;
+        assert!(check_source("geometry::area(1)").is_err()) ;
+    }
+
+    #[test]
+    fn checks_maps() {
+        assert!(check_source("let m = {\"a\": 1, 2: true}; m = {}").is_ok()) ;
+        assert_eq!(type_of("\"a\" in {\"a\": 1}"), Ok(Some(VAL_BOOL))) ;
+        assert!(check_source("let m = {}; m[\"k\"] = 1; m[true] + 1; len(m)").is_ok()) ;
+        assert!(check_source("for k in {1: 2} { k + 1 }").is_ok()) ;
+        assert!(check_source("for k in 5 { }").is_err()) ;
+        assert!(check_source("let m = {1.5: 2}").is_err()) ;
+        assert!(check_source("let m = {}; m[[1]]").is_err()) ;
+        assert!(check_source("1 in 2").is_err()) ;
+        assert!(check_source("[1][0..1] in {}").is_ok()) ;
+    }
+
+    #[test]
+    fn rejects_bad_declarations() {
+        assert!(check_source("let x = 1; let x = 2").is_err()) ;
+        assert!(check_source("let x = 1; { let x = 2 }").is_ok()) ;
+        assert!(check_source("{ let x = 1; let x = 2 }").is_err()) ;
+        assert!(check_source("y + 1").is_err()) ;
+        assert!(check_source("{ let z = z }").is_err()) ;
+    }
+
+    #[test]
+    fn explains_errors() {
+        let diagnostics = check_source("let count = 1\nlet count = 2").err().unwrap() ;
+        assert_eq!(diagnostics[0].span.line, 2) ;
+        assert_eq!(diagnostics[0].labels[0].span, Span { startByte: 4, endByte: 9, line: 1, column: 5 }) ;
+        assert_eq!(diagnostics[0].labels[0].message, "first declared here") ;
+
+        let help = |source: &str| check_source(source).err().unwrap()[0].help.clone() ;
+        assert_eq!(help("let count = 1; coutn + 1"), Vec::<String>::new()) ;
+        assert_eq!(help("let amount = 1; amout + 1"), vec!["did you mean `amount`?".to_string()]) ;
+        assert_eq!(help("var x = 1"), vec!["did you mean `let`?".to_string()]) ;
+        assert_eq!(help("retrun"), vec!["did you mean `return`?".to_string()]) ;
+    }
+}
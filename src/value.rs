@@ -1,6 +1,6 @@
+use std::convert::TryFrom;
 use std::fmt;
-use std::fmt::{Formatter, Error};
-use std::convert::TryInto;
+use std::fmt::Formatter;
 
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub enum ValueType {
@@ -8,119 +8,178 @@ pub enum ValueType {
     VAL_NIL,
     VAL_INTEGER,
     VAL_DOUBLE,
-    VAL_STRING
+    VAL_STRING,
+    VAL_ARRAY,
+    VAL_MAP,
+    VAL_OBJ,
+    // Only known at runtime, e.g. a variable declared without a value
+    VAL_ANY
 }
 
-/**
-Value structure
-valtype : ValueType
-value : Vec<u8>
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            VAL_BOOL    => write!(f, "bool"),
+            VAL_NIL     => write!(f, "nil"),
+            VAL_INTEGER => write!(f, "integer"),
+            VAL_DOUBLE  => write!(f, "double"),
+            VAL_STRING  => write!(f, "string"),
+            VAL_ARRAY   => write!(f, "array"),
+            VAL_MAP     => write!(f, "map"),
+            VAL_OBJ     => write!(f, "object"),
+            VAL_ANY     => write!(f, "any"),
+        }
+    }
+}
 
-This is the object we put in the value type in the ast
-It stores an enum value identifying the type the value is
-and is keeps a vector of bytes to represent the structure
+/**
+ObjRef is a handle to an object that lives on the heap rather than
+on the stack. It is just an index, so it can be copied around freely
+and the heap stays the single owner of the object itself.
+*/
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct ObjRef(pub usize);
 
-The "val_type" trait had functions
+/**
+Value enum
 
+This is what gets pushed onto the VM stack, stored in the constants
+pool of a chunk and printed by the debugger. Scalars are stored inline
+and everything else is an ObjRef into the heap, which keeps Value
+small and Copy.
 */
-pub struct Value {
-    valtype: ValueType,
-    value: Vec<u8>,
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Value {
+    NIL,
+    BOOL(bool),
+    INTEGER(i64),
+    DOUBLE(f64),
+    OBJ(ObjRef),
 }
 
 use ValueType::* ;
 
 impl Value {
-
-    /**
-    Instantiate the struct with a value. As long as the value is on of the
-    ones in ValueType, it'll take care of assigning the right enum member and
-    converting the value to a vector of bytes
-    */
-    pub fn new<T: val_type>(v:T) -> Value {
-        return Value{valtype: v.get_valtype(), value: v.to_bytes()} ;
-    }
     // In case we need to check the type at compile-time
     pub fn get_type(&self) -> ValueType {
-        return self.valtype.clone() ;
+        return match self {
+            Value::NIL => VAL_NIL,
+            Value::BOOL(_) => VAL_BOOL,
+            Value::INTEGER(_) => VAL_INTEGER,
+            Value::DOUBLE(_) => VAL_DOUBLE,
+            Value::OBJ(_) => VAL_OBJ,
+        }
     }
 
-    /**
-    The following functions convert the struct to a concrete version of the
-    data
-    */
-    pub fn to_integer(&self) -> i64 {
-        return i64::from_le_bytes(  self.value
-            .as_slice()
-            .try_into()
-            .unwrap()) ;
+    pub fn is_nil(&self) -> bool {
+        return matches!(self, Value::NIL) ;
     }
 
-    pub fn to_double(&self) -> f64 {
-        return f64::from_le_bytes(  self.value
-            .as_slice()
-            .try_into()
-            .unwrap()) ;
+    pub fn is_bool(&self) -> bool {
+        return matches!(self, Value::BOOL(_)) ;
     }
 
-    pub fn to_string(&self) -> String {
-        return String::from_utf8_lossy(&self.value)
-            .to_string() ;
+    pub fn is_number(&self) -> bool {
+        return matches!(self, Value::INTEGER(_) | Value::DOUBLE(_)) ;
     }
+}
 
+impl Default for Value {
+    fn default() -> Self {
+        return Value::NIL ;
+    }
 }
 
-/**d
-"val_type" trait to help treat the structs as values
+/*
+Conversions from native Rust values into Values
 */
-pub trait val_type {
-    // Simply brings back the enum member indicating what kind of value it is
-    fn get_valtype(&self) -> ValueType ;
-    // Converts the base value to a vector of bytes
-    fn to_bytes(&self) -> Vec<u8>;
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        return Value::BOOL(b) ;
+    }
 }
 
-impl val_type for i64 {
-    fn get_valtype(&self) -> ValueType {
-        return VAL_INTEGER ;
-    }
-    fn to_bytes(&self) -> Vec<u8>{
-        return self.to_le_bytes().to_vec() ;
+impl From<i64> for Value {
+    fn from(i: i64) -> Self {
+        return Value::INTEGER(i) ;
     }
 }
 
-impl val_type for f64 {
-    fn get_valtype(&self) -> ValueType {
-        return VAL_DOUBLE ;
-    }
-    fn to_bytes(&self) -> Vec<u8>{
-        return self.to_le_bytes().to_vec() ;
+impl From<f64> for Value {
+    fn from(d: f64) -> Self {
+        return Value::DOUBLE(d) ;
     }
 }
 
-impl val_type for String {
-    fn get_valtype(&self) -> ValueType {
-        return VAL_STRING ;
+impl From<ObjRef> for Value {
+    fn from(o: ObjRef) -> Self {
+        return Value::OBJ(o) ;
     }
+}
 
-    fn to_bytes(&self) -> Vec<u8>{
-        return self.clone().into_bytes() ;
+impl From<()> for Value {
+    fn from(_: ()) -> Self {
+        return Value::NIL ;
     }
 }
 
-#[derive(PartialEq, Debug)]
-pub enum Binop {
-    PLUS, MINUS, DIV, MUL, MOD
+/**
+Returned when a Value is converted into a native type it doesn't hold
+*/
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct TypeMismatch {
+    pub expected: ValueType,
+    pub found: ValueType,
 }
 
-impl fmt::Display for Binop {
+impl fmt::Display for TypeMismatch {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        match self {
-            PLUS    => write!(f, "+"),
-            MINUS   => write!(f, "-"),
-            DIV     => write!(f, "/"),
-            MUL     => write!(f, "*"),
-            MOD     => write!(f, "%"),
+        write!(f, "expected {} but found {}", self.expected, self.found)
+    }
+}
+
+/*
+Conversions from Values back into native Rust values
+*/
+macro_rules! TRY_FROM_VALUE {
+    ($native:ty, $variant:ident, $vtype:expr) => {
+        impl TryFrom<Value> for $native {
+            type Error = TypeMismatch;
+
+            fn try_from(value: Value) -> Result<Self, Self::Error> {
+                match value {
+                    Value::$variant(v) => Ok(v),
+                    _ => Err(TypeMismatch { expected: $vtype, found: value.get_type() }),
+                }
+            }
         }
+    };
+}
+
+TRY_FROM_VALUE!(bool, BOOL, VAL_BOOL);
+TRY_FROM_VALUE!(i64, INTEGER, VAL_INTEGER);
+TRY_FROM_VALUE!(f64, DOUBLE, VAL_DOUBLE);
+TRY_FROM_VALUE!(ObjRef, OBJ, VAL_OBJ);
+
+/* =============== Unit tests ================= */
+
+#[cfg(test)]
+mod tests {
+
+    use crate::value::* ;
+
+    #[test]
+    fn round_trip_conversions() {
+        assert_eq!(Value::from(42i64), Value::INTEGER(42));
+        assert_eq!(i64::try_from(Value::from(42i64)), Ok(42));
+        assert_eq!(f64::try_from(Value::from(1.5)), Ok(1.5));
+        assert_eq!(bool::try_from(Value::from(true)), Ok(true));
+        assert_eq!(Value::from(()), Value::NIL);
+    }
+
+    #[test]
+    fn conversion_type_mismatch() {
+        let err = i64::try_from(Value::DOUBLE(2.0)).unwrap_err();
+        assert_eq!(err, TypeMismatch { expected: VAL_INTEGER, found: VAL_DOUBLE });
     }
-}
\ No newline at end of file
+}
@@ -2,64 +2,684 @@ use crate::chunk::* ;
 use crate::debug::* ;
 use crate::value::* ;
 use crate::compiler::* ;
+use crate::natives::* ;
+use crate::diagnostics::Diagnostic ;
+use crate::object::* ;
 use crate::opcodes::* ;
 use crate::opcodes::OpCode::* ;
 
-use crate::value::ValueType::VAL_NIL;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
+
+// Deepest call nesting allowed before we report a stack overflow
+pub const FRAMES_MAX: usize = 256 ;
+// Slots on the value stack, shared by every frame
+pub const STACK_MAX: usize = 64000 ;
+
+/**
+A function invocation in progress. slots is where the frame's window
+starts on the value stack: the callee sits there, followed by the
+arguments and then the locals.
+*/
+struct CallFrame {
+    closure: ObjRef,
+    chunk: Rc<Chunk>,
+    ip: usize,
+    slots: usize,
+}
 
 pub struct VM {
-    chunk: Chunk,
+    // chunk, ip and slots belong to the innermost frame. They are cached
+    // here while it runs and saved back into it when it calls out.
+    chunk: Rc<Chunk>,
     ip: usize,
+    slots: usize,
+    frames: Vec<CallFrame>,
+
+    pub stack: [Value;STACK_MAX],
+    stacktop: usize,
+    // Set when an instruction pops more than the stack holds, which only
+    // broken bytecode does
+    stackUnderflow: bool,
 
-    pub stack: [Value;64000],
-    stacktop: usize
+    // Indexed by the operand of OP_*_GLOBAL. None until the global's
+    // declaration has run.
+    globals: Vec<Option<Value>>,
+    // Upvalues still pointing into the stack. Closures capturing the same
+    // slot have to share one upvalue, so captureUpvalue looks here first.
+    openUpvalues: Vec<ObjRef>,
+    // The functions of the modules imported so far, which don't run again
+    imported: HashSet<ObjRef>,
+    // Directories searched for imported modules, in order
+    searchPath: Vec<PathBuf>,
+    // What the scripts compiled so far declared and loaded, for the next
+    session: Session,
+    // What compile errors in the script are reported against
+    scriptName: String,
+    // Where print statements, and the results echoed, are written
+    out: Box<dyn Write>,
+    options: VmOptions,
+    // Where the diagnostics turned on in options are written
+    debugOut: Box<dyn Write>,
+    heap: Heap,
+}
+/**
+Diagnostics the VM can produce while it compiles and runs a script
+*/
+#[derive(Copy, Clone, Default, Debug)]
+pub struct VmOptions {
+    // Show the stack and disassemble each instruction before it runs
+    pub traceExecution: bool,
+    // Print the value of a script ending in an expression, unless it is
+    // nil, as the repl does
+    pub echoResult: bool,
+    pub compiler: CompilerOptions,
 }
-#[derive(PartialEq)]
+
+#[derive(PartialEq, Debug)]
 pub enum InterpretResult {
     INTERPRET_OK,
-    INTERPRET_COMPILE_ERROR,
-    INTERPRET_RUNTIME_ERROR
+    INTERPRET_COMPILE_ERROR(Vec<Diagnostic>),
+    INTERPRET_RUNTIME_ERROR(RuntimeError)
+}
+
+/**
+A call that was in progress when a runtime error happened. function is
+empty for the top level script.
+*/
+#[derive(Clone, PartialEq, Debug)]
+pub struct TraceFrame {
+    pub function: String,
+    pub line: usize,
+}
+
+/**
+An error that stopped a running script. line is where it happened, and
+trace lists the calls in progress at the time, innermost first.
+*/
+#[derive(Clone, PartialEq, Debug)]
+pub struct RuntimeError {
+    pub message: String,
+    pub line: usize,
+    pub trace: Vec<TraceFrame>,
 }
 
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.function.is_empty() {
+            return write!(f, "[line {}] in script", self.line) ;
+        }
+        return write!(f, "[line {}] in {}()", self.line, self.function) ;
+    }
+}
+
+// Runs of the same call, as deep recursion leaves, are shown once
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[line {}] Runtime error: {}", self.line, self.message)? ;
+        let mut i = 0 ;
+        while i < self.trace.len() {
+            let repeats = self.trace[i..].iter().take_while(|frame| **frame == self.trace[i]).count() ;
+            write!(f, "\n  {}", self.trace[i])? ;
+            if repeats > 1 {
+                write!(f, " ({} times)", repeats)? ;
+            }
+            i += repeats ;
+        }
+        return Ok(()) ;
+    }
+}
+
+/**
+Compile and run a script on a fresh VM
+*/
 pub fn interpret(source: String) -> InterpretResult {
+    let mut vm = VM::new() ;
+    return vm.interpret(source) ;
+}
 
-    let res = compile(source);
-    if !res.is_ok() {
-        return InterpretResult::INTERPRET_COMPILE_ERROR ;
+impl VM {
+
+    pub fn new() -> VM {
+        return VM::withConfig(GcConfig::default()) ;
     }
 
-    let mut vm = VM {
-        chunk: res.unwrap(),
-        ip: 0,
-        stack: [Value::OBJ(Obj{vtype: VAL_NIL, oref: 0});64000],
-        stacktop: 0
-    } ;
+    pub fn withConfig(config: GcConfig) -> VM {
+        return VM {
+            chunk: Rc::new(newChunk()),
+            ip: 0,
+            slots: 0,
+            frames: Vec::new(),
+            stack: [Value::NIL;STACK_MAX],
+            stacktop: 0,
+            stackUnderflow: false,
+            globals: Vec::new(),
+            openUpvalues: Vec::new(),
+            imported: HashSet::new(),
+            searchPath: vec![PathBuf::from(".")],
+            session: Session::new(),
+            scriptName: SCRIPT_NAME.to_string(),
+            out: Box::new(io::stdout()),
+            options: VmOptions::default(),
+            debugOut: Box::new(io::stderr()),
+            heap: Heap::withConfig(config),
+        } ;
+    }
 
-    let result = vm.run() ;
+    /**
+    Where imported modules are looked for, replacing the default of just
+    the current directory
+    */
+    pub fn setSearchPath(&mut self, searchPath: Vec<PathBuf>) {
+        self.searchPath = searchPath ;
+    }
 
-    return result ;
+    /**
+    The file compile errors in the scripts interpreted from now on are
+    reported against, such as the path they were read from
+    */
+    pub fn setScriptName(&mut self, scriptName: &str) {
+        self.scriptName = scriptName.to_string() ;
+    }
 
-}
+    /**
+    Send script output somewhere other than stdout, such as a buffer
+    */
+    pub fn setOutput(&mut self, out: Box<dyn Write>) {
+        self.out = out ;
+    }
+
+    pub fn setOptions(&mut self, options: VmOptions) {
+        self.options = options ;
+    }
+
+    /**
+    Send diagnostics somewhere other than stderr
+    */
+    pub fn setDebugOutput(&mut self, debugOut: Box<dyn Write>) {
+        self.debugOut = debugOut ;
+    }
+
+    /**
+    Compile and run a script. It sees the globals of the scripts this VM
+    ran before it, as the lines typed at the repl do, and modules they
+    imported aren't run again.
+    */
+    pub fn interpret(&mut self, source: String) -> InterpretResult {
+        let script = match compileInSession(source, &self.scriptName, &mut self.heap, &self.searchPath,
+                                            &self.options.compiler, &mut *self.debugOut, &mut self.session) {
+            Ok(script) => script,
+            Err(diagnostics) => return InterpretResult::INTERPRET_COMPILE_ERROR(diagnostics),
+        } ;
+
+        self.stacktop = 0 ;
+        self.stackUnderflow = false ;
+        self.frames.clear() ;
+        self.openUpvalues.clear() ;
+
+        // Keep the script reachable while its closure is allocated
+        self.push(Value::from(script)) ;
+        let closure = self.alloc(Obj::CLOSURE(Closure { function: script, upvalues: Vec::new() })) ;
+        self.pop() ;
+        self.push(Value::from(closure)) ;
+        if let Err(error) = self.call(closure, 0) {
+            return InterpretResult::INTERPRET_RUNTIME_ERROR(error) ;
+        }
+        self.defineNatives() ;
+        return match self.run() {
+            Ok(()) => InterpretResult::INTERPRET_OK,
+            Err(error) => InterpretResult::INTERPRET_RUNTIME_ERROR(error),
+        } ;
+    }
+
+    // Natives are ordinary globals that are defined before the script runs,
+    // unless an earlier script gave the name a value of its own
+    fn defineNatives(&mut self) {
+        let names = self.chunk.globals.clone() ;
+        if self.globals.len() < names.len() {
+            self.globals.resize(names.len(), None) ;
+        }
+        for (global, name) in names.iter().enumerate() {
+            if let (Some(native), None) = (native(name), self.globals[global]) {
+                let oref = self.alloc(Obj::NATIVE(native)) ;
+                self.globals[global] = Some(Value::from(oref)) ;
+            }
+        }
+    }
+
+    pub fn gcStats(&self) -> GcStats {
+        return self.heap.stats() ;
+    }
 
-impl VM {
     fn push(&mut self, value: Value) {
         self.stack[self.stacktop] = value;
         self.stacktop += 1;
     }
 
+    // An empty stack gives nil, and run reports the underflow once the
+    // instruction is done
     fn pop(&mut self) -> Value {
+        if self.stacktop == 0 {
+            self.stackUnderflow = true ;
+            return Value::NIL ;
+        }
         self.stacktop -= 1;
         let top = self.stacktop ;
         let val = self.stack[top] ;
         return val;
     }
 
-    fn peek(&mut self, distance: usize) -> Value {
+    fn peek(&self, distance: usize) -> Value {
+        if distance >= self.stacktop {
+            return Value::NIL ;
+        }
         let dist = self.stacktop -1 - distance;
         return self.stack[dist];
     }
 
-    fn run(&mut self) -> InterpretResult {
+    // nil and false are falsey, everything else is truthy
+    fn isFalse(value: Value) -> bool {
+        return value.is_nil() || value == Value::BOOL(false) ;
+    }
+
+    // Strings are interned, so comparing references compares contents. An
+    // integer and a double are compared as doubles, as < and + do.
+    fn valuesEqual(a: Value, b: Value) -> bool {
+        return match (a, b) {
+            (Value::INTEGER(i), Value::DOUBLE(d)) | (Value::DOUBLE(d), Value::INTEGER(i)) => i as f64 == d,
+            _ => a == b,
+        } ;
+    }
+
+    // The stack, then the instruction about to run
+    fn traceInstruction(&mut self) {
+        let out = &mut *self.debugOut ;
+        let _ = write!(out, "          ") ;
+        for slot in 0..self.stacktop {
+            let _ = write!(out, "[ ") ;
+            printValue(out, &self.heap, self.stack[slot]) ;
+            let _ = write!(out, " ]") ;
+        }
+        let _ = writeln!(out) ;
+        disassembleInstruction(out, &self.heap, &self.chunk, self.ip) ;
+    }
+
+    // Write a value and a newline to the output, flushing so it interleaves
+    // with anything the script writes to stderr
+    fn print(&mut self, value: Value) -> Result<(), RuntimeError> {
+        let text = self.heap.formatValue(value) ;
+        if let Err(e) = writeln!(self.out, "{}", text).and_then(|_| self.out.flush()) {
+            return Err(self.runtimeError(format!("Can't write output: {}", e).as_str())) ;
+        }
+        return Ok(()) ;
+    }
+
+    /**
+    A runtime error at the instruction just read, with a trace of the
+    calls in progress
+    */
+    fn runtimeError(&self, message: &str) -> RuntimeError {
+        let lineAt = |chunk: &Chunk, ip: usize| chunk.line(ip.saturating_sub(1)) ;
+        let mut trace = Vec::with_capacity(self.frames.len()) ;
+        for (depth, frame) in self.frames.iter().rev().enumerate() {
+            // Only suspended frames have their ip saved
+            let ip = if depth == 0 { self.ip } else { frame.ip } ;
+            let function = self.heap.closure(frame.closure)
+                .and_then(|closure| self.heap.function(closure.function))
+                .map(|function| function.name.clone())
+                .unwrap_or_default() ;
+            trace.push(TraceFrame { function, line: lineAt(&frame.chunk, ip) }) ;
+        }
+        return RuntimeError { message: message.to_string(), line: lineAt(&self.chunk, self.ip), trace } ;
+    }
+
+    /**
+    Integer arithmetic is checked, so that overflow and division by zero
+    are runtime errors rather than panics
+    */
+    fn integerArithmetic(&self, op: OpCode, l: i64, r: i64) -> Result<i64, RuntimeError> {
+        let result = match op {
+            OP_IADD | OP_ADD => l.checked_add(r),
+            OP_ISUB | OP_SUB => l.checked_sub(r),
+            OP_IMUL | OP_MUL => l.checked_mul(r),
+            _ if r == 0 => return Err(self.runtimeError("Division by zero.")),
+            _ => l.checked_div(r),
+        } ;
+        return result.ok_or_else(|| self.runtimeError("Integer overflow.")) ;
+    }
+
+    /* Memory */
+
+    // Every allocation made while running goes through here or intern so
+    // that the collector gets a chance to run first
+    fn alloc(&mut self, obj: Obj) -> ObjRef {
+        if self.heap.shouldCollect() {
+            self.collectGarbage() ;
+        }
+        return self.heap.alloc(obj) ;
+    }
+
+    fn intern(&mut self, string: &str) -> ObjRef {
+        if !self.heap.isInterned(string) && self.heap.shouldCollect() {
+            self.collectGarbage() ;
+        }
+        return self.heap.intern(string) ;
+    }
+
+    fn collectGarbage(&mut self) {
+        let mut roots: Vec<Value> = self.stack[0..self.stacktop].to_vec() ;
+        for frame in self.frames.iter() {
+            roots.push(Value::from(frame.closure)) ;
+            roots.extend(frame.chunk.constants.iter().copied()) ;
+        }
+        roots.extend(self.globals.iter().flatten().copied()) ;
+        roots.extend(self.openUpvalues.iter().map(|upvalue| Value::from(*upvalue))) ;
+        roots.extend(self.imported.iter().map(|function| Value::from(*function))) ;
+        roots.extend(self.session.functions().map(Value::from)) ;
+        self.heap.collect(&roots) ;
+    }
+
+    /* Strings */
+
+    fn isString(&self, value: Value) -> bool {
+        return match value {
+            Value::OBJ(oref) => self.heap.string(oref).is_some(),
+            _ => false,
+        }
+    }
+
+    // Replaces the two strings on top of the stack with their concatenation
+    fn concatenate(&mut self) {
+        let b = ObjRef::try_from(self.pop()).unwrap() ;
+        let a = ObjRef::try_from(self.pop()).unwrap() ;
+        let mut result = String::from(self.heap.string(a).unwrap()) ;
+        result.push_str(self.heap.string(b).unwrap()) ;
+        let string = self.intern(&result) ;
+        self.push(Value::from(string)) ;
+    }
+
+    /* Arrays and maps */
+
+    /**
+    Turns an index into a position in an array of length len. Negative
+    indices count back from the end, and an index out of bounds is a
+    runtime error.
+    */
+    fn arrayIndex(&self, index: Value, len: usize) -> Result<usize, RuntimeError> {
+        let i = match index {
+            Value::INTEGER(i) => i,
+            _ => return Err(self.runtimeError("Array index must be an integer.")),
+        } ;
+        let position = if i < 0 { i + len as i64 } else { i } ;
+        if position < 0 || position >= len as i64 {
+            return Err(self.runtimeError(format!("Array index {} out of bounds for length {}.", i, len).as_str())) ;
+        }
+        return Ok(position as usize) ;
+    }
+
+    fn mapKey(&self, value: Value) -> Result<MapKey, RuntimeError> {
+        return match self.heap.mapKey(value) {
+            Some(key) => Ok(key),
+            None => Err(self.runtimeError("Map keys must be strings, integers or booleans.")),
+        } ;
+    }
+
+    // Replaces collection and index on top of the stack with the item found
+    fn indexGet(&mut self) -> Result<(), RuntimeError> {
+        let (collection, index) = (self.peek(1), self.peek(0)) ;
+        let value = match collection {
+            Value::OBJ(oref) if self.heap.array(oref).is_some() => {
+                let len = self.heap.array(oref).unwrap().len() ;
+                let position = self.arrayIndex(index, len)? ;
+                self.heap.array(oref).unwrap()[position]
+            }
+            Value::OBJ(oref) if self.heap.map(oref).is_some() => {
+                let key = self.mapKey(index)? ;
+                match self.heap.map(oref).unwrap().get(key) {
+                    Some(value) => value,
+                    None => {
+                        let msg = format!("Key {} not found in map.", self.heap.formatValue(index)) ;
+                        return Err(self.runtimeError(msg.as_str())) ;
+                    }
+                }
+            }
+            _ => return Err(self.runtimeError("Can only index arrays and maps.")),
+        } ;
+        self.stacktop -= 2 ;
+        self.push(value) ;
+        return Ok(()) ;
+    }
+
+    // Stores the value on top of the stack into collection[index] and
+    // leaves just the value behind
+    fn indexSet(&mut self) -> Result<(), RuntimeError> {
+        let (collection, index, value) = (self.peek(2), self.peek(1), self.peek(0)) ;
+        match collection {
+            Value::OBJ(oref) if self.heap.array(oref).is_some() => {
+                let len = self.heap.array(oref).unwrap().len() ;
+                let position = self.arrayIndex(index, len)? ;
+                self.heap.array_mut(oref).unwrap()[position] = value ;
+            }
+            Value::OBJ(oref) if self.heap.map(oref).is_some() => {
+                let key = self.mapKey(index)? ;
+                self.heap.map_mut(oref).unwrap().set(key, value) ;
+                self.heap.resized(oref) ;
+            }
+            _ => return Err(self.runtimeError("Can only index arrays and maps.")),
+        }
+        self.stacktop -= 3 ;
+        self.push(value) ;
+        return Ok(()) ;
+    }
+
+    // Whether needle is an element of an array or a key of a map
+    fn contains(&self, needle: Value, collection: Value) -> Result<bool, RuntimeError> {
+        if let Value::OBJ(oref) = collection {
+            if let Some(elements) = self.heap.array(oref) {
+                return Ok(elements.iter().any(|element| VM::valuesEqual(*element, needle))) ;
+            }
+            if let Some(map) = self.heap.map(oref) {
+                // Something that can't be a key can't be in the map either
+                return Ok(self.heap.mapKey(needle).map(|key| map.contains(key)).unwrap_or(false)) ;
+            }
+        }
+        return Err(self.runtimeError("Right operand of 'in' must be an array or a map.")) ;
+    }
+
+    // A slice bound: nil means the given default, negative counts from the end
+    fn sliceBound(&self, bound: Value, len: usize, default: usize) -> Result<usize, RuntimeError> {
+        let i = match bound {
+            Value::NIL => return Ok(default),
+            Value::INTEGER(i) => i,
+            _ => return Err(self.runtimeError("Slice bounds must be integers.")),
+        } ;
+        let position = if i < 0 { i + len as i64 } else { i } ;
+        if position < 0 || position > len as i64 {
+            return Err(self.runtimeError(format!("Slice bound {} out of range for length {}.", i, len).as_str())) ;
+        }
+        return Ok(position as usize) ;
+    }
+
+    /* Calls */
+
+    /**
+    Pushes a frame for a closure whose callee and arguments are already
+    on the stack
+    */
+    fn call(&mut self, closure: ObjRef, argCount: usize) -> Result<(), RuntimeError> {
+        let function = match self.heap.closure(closure) {
+            Some(closure) => closure.function,
+            None => return Err(self.runtimeError("Can only call functions.")),
+        } ;
+        let (arity, chunk) = match self.heap.function(function) {
+            Some(function) => (function.arity, function.chunk.clone()),
+            None => return Err(self.runtimeError("Can only call functions.")),
+        } ;
+        if argCount != arity {
+            return Err(self.runtimeError(format!("Expected {} arguments but got {}.", arity, argCount).as_str())) ;
+        }
+        if self.frames.len() == FRAMES_MAX {
+            return Err(self.runtimeError("Stack overflow.")) ;
+        }
+
+        if let Some(frame) = self.frames.last_mut() {
+            frame.ip = self.ip ;
+        }
+        let slots = self.stacktop - argCount - 1 ;
+        self.frames.push(CallFrame { closure, chunk: chunk.clone(), ip: 0, slots }) ;
+        self.chunk = chunk ;
+        self.ip = 0 ;
+        self.slots = slots ;
+        return Ok(()) ;
+    }
+
+    /* Upvalues */
+
+    fn captureUpvalue(&mut self, location: usize) -> ObjRef {
+        for oref in self.openUpvalues.iter() {
+            if let Obj::UPVALUE(upvalue) = self.heap.get(*oref) {
+                if upvalue.location == location {
+                    return *oref ;
+                }
+            }
+        }
+        let oref = self.alloc(Obj::UPVALUE(Upvalue { location, closed: None })) ;
+        self.openUpvalues.push(oref) ;
+        return oref ;
+    }
+
+    // Closes every open upvalue pointing at slot `last` or above
+    fn closeUpvalues(&mut self, last: usize) {
+        let stack = &self.stack ;
+        let heap = &mut self.heap ;
+        self.openUpvalues.retain(|oref| {
+            let upvalue = heap.upvalue_mut(*oref).unwrap() ;
+            if upvalue.location < last {
+                return true ;
+            }
+            upvalue.closed = Some(stack[upvalue.location]) ;
+            return false ;
+        }) ;
+    }
+
+    // The upvalue the running closure has at index
+    fn frameUpvalue(&self, index: usize) -> ObjRef {
+        let closure = self.frames.last().unwrap().closure ;
+        return self.heap.closure(closure).unwrap().upvalues[index] ;
+    }
+
+    // Runs a native function straight away and leaves its result in place
+    // of the callee and arguments
+    fn callNative(&mut self, oref: ObjRef, argCount: usize) -> Result<(), RuntimeError> {
+        let (name, arity, function) = match self.heap.get(oref) {
+            Obj::NATIVE(native) => (native.name, native.arity, native.function),
+            _ => return Err(self.runtimeError("Can only call functions and classes.")),
+        } ;
+        if argCount != arity {
+            return Err(self.runtimeError(format!("{}() expected {} arguments but got {}.", name, arity, argCount).as_str())) ;
+        }
+        let args = self.stack[self.stacktop - argCount..self.stacktop].to_vec() ;
+        match function(&self.heap, &args) {
+            Ok(result) => {
+                self.stacktop -= argCount + 1 ;
+                self.push(result) ;
+                return Ok(()) ;
+            }
+            Err(message) => return Err(self.runtimeError(message.as_str())),
+        }
+    }
+
+    fn callValue(&mut self, callee: Value, argCount: usize) -> Result<(), RuntimeError> {
+        let oref = match callee {
+            Value::OBJ(oref) => oref,
+            _ => return Err(self.runtimeError("Can only call functions and classes.")),
+        } ;
+        match self.heap.get(oref) {
+            Obj::NATIVE(_) => return self.callNative(oref, argCount),
+            Obj::CLASS(_) => return self.instantiate(oref, argCount),
+            // The receiver takes the place of the callee, where the method
+            // expects to find 'this'
+            Obj::BOUND_METHOD(bound) => {
+                let (receiver, method) = (bound.receiver, bound.method) ;
+                self.stack[self.stacktop - argCount - 1] = receiver ;
+                return self.call(method, argCount) ;
+            }
+            _ => return self.call(oref, argCount),
+        }
+    }
+
+    /* Classes */
+
+    /**
+    Calling a class creates an instance in place of the class and passes
+    the arguments on to init, if the class has one
+    */
+    fn instantiate(&mut self, class: ObjRef, argCount: usize) -> Result<(), RuntimeError> {
+        let instance = self.alloc(Obj::INSTANCE(Instance { class, fields: HashMap::new() })) ;
+        self.stack[self.stacktop - argCount - 1] = Value::from(instance) ;
+        match self.heap.class(class).unwrap().initializer {
+            Some(initializer) => return self.call(initializer, argCount),
+            None if argCount != 0 => return Err(self.runtimeError(format!("Expected 0 arguments but got {}.", argCount).as_str())),
+            None => return Ok(()),
+        }
+    }
+
+    fn instanceRef(&self, value: Value, message: &str) -> Result<ObjRef, RuntimeError> {
+        if let Value::OBJ(oref) = value {
+            if self.heap.instance(oref).is_some() {
+                return Ok(oref) ;
+            }
+        }
+        return Err(self.runtimeError(message)) ;
+    }
+
+    fn undefinedProperty(&self, name: ObjRef) -> RuntimeError {
+        let msg = format!("Undefined property '{}'.", self.heap.describe(name)) ;
+        return self.runtimeError(msg.as_str()) ;
+    }
+
+    // Replaces the instance on top of the stack with the method called
+    // name, bound to it
+    fn bindMethod(&mut self, class: ObjRef, name: ObjRef) -> Result<(), RuntimeError> {
+        let method = match self.heap.class(class).unwrap().methods.get(&name) {
+            Some(method) => *method,
+            None => return Err(self.undefinedProperty(name)),
+        } ;
+        let bound = self.alloc(Obj::BOUND_METHOD(BoundMethod { receiver: self.peek(0), method })) ;
+        self.pop() ;
+        self.push(Value::from(bound)) ;
+        return Ok(()) ;
+    }
+
+    /**
+    receiver.name(args) in one step. A field holding something callable
+    takes precedence over a method of the same name.
+    */
+    fn invoke(&mut self, name: ObjRef, argCount: usize) -> Result<(), RuntimeError> {
+        let instance = self.instanceRef(self.peek(argCount), "Only instances have methods.")? ;
+        let instance = self.heap.instance(instance).unwrap() ;
+        if let Some(field) = instance.fields.get(&name).copied() {
+            self.stack[self.stacktop - argCount - 1] = field ;
+            return self.callValue(field, argCount) ;
+        }
+        return self.invokeFromClass(instance.class, name, argCount) ;
+    }
+
+    // Calls the method called name that class has, with the receiver and
+    // arguments already on the stack
+    fn invokeFromClass(&mut self, class: ObjRef, name: ObjRef, argCount: usize) -> Result<(), RuntimeError> {
+        let method = self.heap.class(class).unwrap().methods.get(&name).copied() ;
+        match method {
+            Some(method) => return self.call(method, argCount),
+            None => return Err(self.undefinedProperty(name)),
+        }
+    }
+
+    fn run(&mut self) -> Result<(), RuntimeError> {
         macro_rules! READ_BYTE {
         () => {{
                 let code = self.chunk.code[self.ip];
@@ -82,61 +702,1037 @@ impl VM {
         }}
         }
 
+        // Operands are checked before they are popped so the stack is
+        // left intact when we report the error
+        macro_rules! INTEGER_OP {
+        {$opcode:expr} => {{
+            let rval = i64::try_from(self.peek(0)) ;
+            let lval = i64::try_from(self.peek(1)) ;
+            match (lval, rval) {
+                (Ok(l), Ok(r)) => {
+                    let result = self.integerArithmetic($opcode, l, r)? ;
+                    self.pop() ;
+                    self.pop() ;
+                    self.push(Value::from(result)) ;
+                }
+                (Err(e), _) | (_, Err(e)) => return Err(self.runtimeError(format!("Invalid operand: {}", e).as_str())),
+            }
+        }}
+        }
+
         macro_rules! BINARY_OP {
-        {$macroType:tt,$valType:tt,$op:tt} => {
-            let rval = $macroType!(self.pop()) ;
-            let lval = $macroType!(self.pop()) ;
-            self.push($valType!((lval $op rval)))
+        {$native:ty,$op:tt} => {{
+            let rval = <$native>::try_from(self.peek(0)) ;
+            let lval = <$native>::try_from(self.peek(1)) ;
+            match (lval, rval) {
+                (Ok(l), Ok(r)) => {
+                    self.pop() ;
+                    self.pop() ;
+                    self.push(Value::from(l $op r)) ;
+                }
+                (Err(e), _) | (_, Err(e)) => return Err(self.runtimeError(format!("Invalid operand: {}", e).as_str())),
+            }
+        }}
+        }
+
+        macro_rules! READ_OPERAND {
+        () => {{
+            let operand = u16::from_le_bytes([self.chunk.code[self.ip], self.chunk.code[self.ip+1]]) as usize ;
+            self.ip+=2 ;
+            operand
+        }}
+        }
+
+        // Arithmetic on operands whose types weren't known at compile time
+        macro_rules! ARITHMETIC_OP {
+        {$opcode:expr, $op:tt} => {{
+            let result = match (self.peek(1), self.peek(0)) {
+                (Value::INTEGER(l), Value::INTEGER(r)) => Value::from(self.integerArithmetic($opcode, l, r)?),
+                (Value::INTEGER(l), Value::DOUBLE(r)) => Value::from((l as f64) $op r),
+                (Value::DOUBLE(l), Value::INTEGER(r)) => Value::from(l $op (r as f64)),
+                (Value::DOUBLE(l), Value::DOUBLE(r)) => Value::from(l $op r),
+                _ => return Err(self.runtimeError("Operands must be numbers.")),
+            } ;
+            self.pop() ;
+            self.pop() ;
+            self.push(result) ;
+        }}
+        }
+
+        macro_rules! COMPARISON_OP {
+        {$op:tt} => {{
+            let result = match (self.peek(1), self.peek(0)) {
+                (Value::INTEGER(l), Value::INTEGER(r)) => l $op r,
+                (Value::INTEGER(l), Value::DOUBLE(r)) => (l as f64) $op r,
+                (Value::DOUBLE(l), Value::INTEGER(r)) => l $op (r as f64),
+                (Value::DOUBLE(l), Value::DOUBLE(r)) => l $op r,
+                _ => return Err(self.runtimeError("Operands must be numbers.")),
+            } ;
+            self.pop() ;
+            self.pop() ;
+            self.push(Value::from(result)) ;
+        }}
         }
-    }
 
         loop {
-            print!("          ");
-            for slot in 0..self.stacktop {
-                print!("[ ");
-                printValue(self.stack[slot]);
-                print!(" ]");
+            // Checked between instructions, none of which pushes more than
+            // one value more than it pops
+            if self.stackUnderflow {
+                return Err(self.runtimeError("Stack underflow.")) ;
+            }
+            if self.stacktop == STACK_MAX {
+                return Err(self.runtimeError("Stack overflow.")) ;
+            }
+            if self.options.traceExecution {
+                self.traceInstruction() ;
             }
 
-            print!("\n");
-            disassembleInstruction(&self.chunk, self.ip);
-
-            let instruction = OpCode::from_byte(READ_BYTE!());
+            let byte = READ_BYTE!() ;
+            let instruction = OpCode::from_byte(byte);
             match instruction {
                 OP_RETURN => {
-                    println!("{}", AS_INTEGER!(self.pop()));
-                    return InterpretResult::INTERPRET_OK;
+                    let result = self.pop() ;
+                    let finished = self.frames.pop().unwrap() ;
+                    self.closeUpvalues(finished.slots) ;
+                    if self.frames.is_empty() {
+                        if self.options.echoResult && !result.is_nil() {
+                            return self.print(result) ;
+                        }
+                        return Ok(()) ;
+                    }
+
+                    // Discard the callee, its arguments and locals
+                    self.stacktop = finished.slots ;
+                    self.push(result) ;
+                    let frame = self.frames.last().unwrap() ;
+                    self.chunk = frame.chunk.clone() ;
+                    self.ip = frame.ip ;
+                    self.slots = frame.slots ;
+                },
+                OP_PRINT => {
+                    let value = self.pop() ;
+                    self.print(value)? ;
+                },
+                OP_CLOSURE => {
+                    let function = match READ_CONSTANT!() {
+                        Value::OBJ(function) => function,
+                        _ => return Err(self.runtimeError("Closure constant must be a function.")),
+                    } ;
+                    let upvalueCount = self.heap.function(function).map(|f| f.upvalueCount).unwrap_or(0) ;
+                    let mut upvalues = Vec::with_capacity(upvalueCount) ;
+                    for _ in 0..upvalueCount {
+                        let isLocal = READ_BYTE!() == 1 ;
+                        let index = READ_OPERAND!() ;
+                        if isLocal {
+                            upvalues.push(self.captureUpvalue(self.slots + index)) ;
+                        } else {
+                            upvalues.push(self.frameUpvalue(index)) ;
+                        }
+                    }
+                    let closure = self.alloc(Obj::CLOSURE(Closure { function, upvalues })) ;
+                    self.push(Value::from(closure)) ;
+                },
+                OP_GET_UPVALUE => {
+                    let index = READ_OPERAND!() ;
+                    let oref = self.frameUpvalue(index) ;
+                    let upvalue = self.heap.upvalue_mut(oref).unwrap() ;
+                    let value = match upvalue.closed {
+                        Some(value) => value,
+                        None => self.stack[upvalue.location],
+                    } ;
+                    self.push(value) ;
+                },
+                OP_SET_UPVALUE => {
+                    let index = READ_OPERAND!() ;
+                    let oref = self.frameUpvalue(index) ;
+                    let value = self.peek(0) ;
+                    let upvalue = self.heap.upvalue_mut(oref).unwrap() ;
+                    match upvalue.closed {
+                        Some(_) => upvalue.closed = Some(value),
+                        None => self.stack[upvalue.location] = value,
+                    }
+                },
+                OP_CLOSE_UPVALUE => {
+                    self.closeUpvalues(self.stacktop - 1) ;
+                    self.pop() ;
+                },
+                OP_BUILD_ARRAY => {
+                    let count = READ_OPERAND!() ;
+                    let elements = self.stack[self.stacktop - count..self.stacktop].to_vec() ;
+                    // The elements stay on the stack, and so reachable, until the array exists
+                    let array = self.alloc(Obj::ARRAY(elements)) ;
+                    self.stacktop -= count ;
+                    self.push(Value::from(array)) ;
+                },
+                OP_BUILD_STRING => {
+                    let count = READ_OPERAND!() ;
+                    // Each part as print would show it
+                    let result: String = self.stack[self.stacktop - count..self.stacktop].iter()
+                        .map(|part| self.heap.formatValue(*part))
+                        .collect() ;
+                    self.stacktop -= count ;
+                    let string = self.intern(&result) ;
+                    self.push(Value::from(string)) ;
+                },
+                OP_BUILD_MAP => {
+                    let count = READ_OPERAND!() ;
+                    let mut map = Map::new() ;
+                    for pair in 0..count {
+                        let key = self.stack[self.stacktop - 2 * (count - pair)] ;
+                        let value = self.stack[self.stacktop - 2 * (count - pair) + 1] ;
+                        let key = self.mapKey(key)? ;
+                        map.set(key, value) ;
+                    }
+                    // The entries stay on the stack, and so reachable, until the map exists
+                    let map = self.alloc(Obj::MAP(map)) ;
+                    self.stacktop -= 2 * count ;
+                    self.push(Value::from(map)) ;
+                },
+                OP_INDEX_GET => {
+                    self.indexGet()? ;
+                },
+                OP_INDEX_SET => {
+                    self.indexSet()? ;
+                },
+                OP_IN => {
+                    let found = self.contains(self.peek(1), self.peek(0))? ;
+                    self.stacktop -= 2 ;
+                    self.push(Value::from(found)) ;
+                },
+                OP_LEN => {
+                    let len = match self.peek(0) {
+                        Value::OBJ(oref) if self.heap.array(oref).is_some() => self.heap.array(oref).unwrap().len(),
+                        Value::OBJ(oref) if self.heap.map(oref).is_some() => self.heap.map(oref).unwrap().len(),
+                        _ => return Err(self.runtimeError("Can only iterate over arrays and maps.")),
+                    } ;
+                    self.pop() ;
+                    self.push(Value::from(len as i64)) ;
+                },
+                // The element of an array, or the key of a map, at a position
+                // below its length
+                OP_ITER_ITEM => {
+                    let position = i64::try_from(self.pop()).unwrap_or(0) as usize ;
+                    let item = match self.pop() {
+                        Value::OBJ(oref) => match self.heap.get(oref) {
+                            Obj::ARRAY(elements) => elements[position],
+                            Obj::MAP(map) => map.entry(position).0.value(),
+                            _ => Value::NIL,
+                        },
+                        _ => Value::NIL,
+                    } ;
+                    self.push(item) ;
+                },
+                OP_SLICE => {
+                    let array = match self.peek(2) {
+                        Value::OBJ(oref) if self.heap.array(oref).is_some() => oref,
+                        _ => return Err(self.runtimeError("Can only slice arrays.")),
+                    } ;
+                    let len = self.heap.array(array).unwrap().len() ;
+                    let start = self.sliceBound(self.peek(1), len, 0)? ;
+                    let end = self.sliceBound(self.peek(0), len, len)? ;
+                    if start > end {
+                        return Err(self.runtimeError(format!("Slice start {} is past its end {}.", start, end).as_str())) ;
+                    }
+                    let elements = self.heap.array(array).unwrap()[start..end].to_vec() ;
+                    let slice = self.alloc(Obj::ARRAY(elements)) ;
+                    self.stacktop -= 3 ;
+                    self.push(Value::from(slice)) ;
+                },
+                OP_CALL => {
+                    let argCount = READ_OPERAND!() ;
+                    let callee = self.peek(argCount) ;
+                    self.callValue(callee, argCount)? ;
+                },
+                OP_INVOKE => {
+                    let name = ObjRef::try_from(READ_CONSTANT!()).unwrap() ;
+                    let argCount = READ_OPERAND!() ;
+                    self.invoke(name, argCount)? ;
+                },
+                // The superclass is popped off above the receiver and arguments
+                OP_SUPER_INVOKE => {
+                    let name = ObjRef::try_from(READ_CONSTANT!()).unwrap() ;
+                    let argCount = READ_OPERAND!() ;
+                    let superclass = ObjRef::try_from(self.pop()).unwrap() ;
+                    self.invokeFromClass(superclass, name, argCount)? ;
+                },
+                // A module runs like a call to its top level function the
+                // first time it is imported; after that its result is nil
+                OP_IMPORT => {
+                    let function = ObjRef::try_from(READ_CONSTANT!()).unwrap() ;
+                    if !self.imported.insert(function) {
+                        self.push(Value::NIL) ;
+                    } else {
+                        let closure = self.alloc(Obj::CLOSURE(Closure { function, upvalues: Vec::new() })) ;
+                        self.push(Value::from(closure)) ;
+                        self.call(closure, 0)? ;
+                    }
+                },
+                OP_CLASS => {
+                    let name = ObjRef::try_from(READ_CONSTANT!()).unwrap() ;
+                    let name = self.heap.describe(name) ;
+                    let class = self.alloc(Obj::CLASS(Class { name, methods: HashMap::new(), initializer: None })) ;
+                    self.push(Value::from(class)) ;
+                },
+                // Attaches the closure on top of the stack to the class below it
+                OP_METHOD => {
+                    let name = ObjRef::try_from(READ_CONSTANT!()).unwrap() ;
+                    let method = ObjRef::try_from(self.peek(0)).unwrap() ;
+                    let isInit = self.heap.string(name) == Some("init") ;
+                    let classRef = ObjRef::try_from(self.peek(1)).unwrap() ;
+                    let class = self.heap.class_mut(classRef).unwrap() ;
+                    class.methods.insert(name, method) ;
+                    if isInit {
+                        class.initializer = Some(method) ;
+                    }
+                    self.heap.resized(classRef) ;
+                    self.pop() ;
+                },
+                // Methods are copied down from the superclass before the
+                // subclass declares its own, which then override them
+                OP_INHERIT => {
+                    let superclass = match self.peek(1) {
+                        Value::OBJ(oref) if self.heap.class(oref).is_some() => oref,
+                        _ => return Err(self.runtimeError("Superclass must be a class.")),
+                    } ;
+                    let inherited = self.heap.class(superclass).unwrap() ;
+                    let (methods, initializer) = (inherited.methods.clone(), inherited.initializer) ;
+                    let subclass = ObjRef::try_from(self.pop()).unwrap() ;
+                    let class = self.heap.class_mut(subclass).unwrap() ;
+                    class.methods.extend(methods) ;
+                    class.initializer = initializer ;
+                    self.heap.resized(subclass) ;
+                },
+                OP_GET_SUPER => {
+                    let name = ObjRef::try_from(READ_CONSTANT!()).unwrap() ;
+                    let superclass = ObjRef::try_from(self.pop()).unwrap() ;
+                    self.bindMethod(superclass, name)? ;
+                },
+                // Fields shadow methods
+                OP_GET_PROPERTY => {
+                    let name = ObjRef::try_from(READ_CONSTANT!()).unwrap() ;
+                    let instance = self.instanceRef(self.peek(0), "Only instances have properties.")? ;
+                    let instance = self.heap.instance(instance).unwrap() ;
+                    match instance.fields.get(&name).copied() {
+                        Some(value) => {
+                            self.pop() ;
+                            self.push(value) ;
+                        }
+                        None => {
+                            self.bindMethod(instance.class, name)? ;
+                        }
+                    }
+                },
+                OP_SET_PROPERTY => {
+                    let name = ObjRef::try_from(READ_CONSTANT!()).unwrap() ;
+                    let instance = self.instanceRef(self.peek(1), "Only instances have fields.")? ;
+                    let value = self.pop() ;
+                    self.heap.instance_mut(instance).unwrap().fields.insert(name, value) ;
+                    self.heap.resized(instance) ;
+                    self.pop() ;
+                    self.push(value) ;
                 },
                 OP_CONSTANT => {
                     let constant = READ_CONSTANT!();
                     self.push(constant);
                 },
+                OP_POP => { self.pop(); },
 
-                OP_IADD => { BINARY_OP!(AS_INTEGER,INTEGER_VAL,+); },
-                OP_ISUB => { BINARY_OP!(AS_INTEGER,INTEGER_VAL,-); },
-                OP_IMUL => { BINARY_OP!(AS_INTEGER,INTEGER_VAL,*); },
-                OP_IDIV => { BINARY_OP!(AS_INTEGER,INTEGER_VAL,/); },
+                OP_JUMP => {
+                    let offset = READ_OPERAND!() ;
+                    self.ip += offset ;
+                },
+                OP_LOOP => {
+                    let offset = READ_OPERAND!() ;
+                    self.ip -= offset ;
+                },
+                OP_JUMP_IF_FALSE => {
+                    let offset = READ_OPERAND!() ;
+                    if VM::isFalse(self.peek(0)) {
+                        self.ip += offset ;
+                    }
+                },
 
-                OP_NIL => { self.push(NIL_VAL!()); },
-                OP_TRUE => { self.push(BOOL_VAL!(true)); },
-                OP_FALSE => { self.push(BOOL_VAL!(false)); },
+                OP_DEFINE_GLOBAL => {
+                    let global = READ_OPERAND!() ;
+                    if self.globals.len() <= global {
+                        self.globals.resize(global + 1, None) ;
+                    }
+                    self.globals[global] = Some(self.pop()) ;
+                },
+                OP_GET_GLOBAL => {
+                    let global = READ_OPERAND!() ;
+                    match self.globals.get(global).copied().flatten() {
+                        Some(value) => self.push(value),
+                        None => {
+                            let msg = format!("Undefined variable '{}'.", self.chunk.globals[global]) ;
+                            return Err(self.runtimeError(msg.as_str())) ;
+                        }
+                    }
+                },
+                OP_SET_GLOBAL => {
+                    let global = READ_OPERAND!() ;
+                    if self.globals.get(global).copied().flatten().is_none() {
+                        let msg = format!("Undefined variable '{}'.", self.chunk.globals[global]) ;
+                        return Err(self.runtimeError(msg.as_str())) ;
+                    }
+                    self.globals[global] = Some(self.peek(0)) ;
+                },
+                OP_GET_LOCAL => {
+                    let slot = READ_OPERAND!() ;
+                    self.push(self.stack[self.slots + slot]) ;
+                },
+                OP_SET_LOCAL => {
+                    let slot = READ_OPERAND!() ;
+                    self.stack[self.slots + slot] = self.peek(0) ;
+                },
+
+                OP_IADD => { INTEGER_OP!(OP_IADD); },
+                OP_ISUB => { INTEGER_OP!(OP_ISUB); },
+                OP_IMUL => { INTEGER_OP!(OP_IMUL); },
+                OP_IDIV => { INTEGER_OP!(OP_IDIV); },
+
+                OP_ADD => {
+                    if self.isString(self.peek(0)) && self.isString(self.peek(1)) {
+                        self.concatenate() ;
+                    } else {
+                        ARITHMETIC_OP!(OP_ADD, +);
+                    }
+                },
+                OP_SUB => { ARITHMETIC_OP!(OP_SUB, -); },
+                OP_MUL => { ARITHMETIC_OP!(OP_MUL, *); },
+                OP_DIV => { ARITHMETIC_OP!(OP_DIV, /); },
+
+                OP_DADD => { BINARY_OP!(f64,+); },
+                OP_DSUB => { BINARY_OP!(f64,-); },
+                OP_DMUL => { BINARY_OP!(f64,*); },
+                OP_DDIV => { BINARY_OP!(f64,/); },
+                OP_ITOD => {
+                    let val = match self.peek(0) {
+                        Value::INTEGER(i) => i as f64,
+                        _ => return Err(self.runtimeError("Operand must be an integer.")),
+                    } ;
+                    self.pop() ;
+                    self.push(Value::from(val)) ;
+                },
 
-                OP_GREATER=> { BINARY_OP!(AS_BOOL,BOOL_VAL,>); },
-                OP_LESS => { BINARY_OP!(AS_BOOL,BOOL_VAL,<); },
+                OP_NIL => { self.push(Value::NIL); },
+                OP_TRUE => { self.push(Value::from(true)); },
+                OP_FALSE => { self.push(Value::from(false)); },
+
+                OP_GREATER=> { COMPARISON_OP!(>); },
+                OP_LESS => { COMPARISON_OP!(<); },
+                OP_GREATER_EQUAL => { COMPARISON_OP!(>=); },
+                OP_LESS_EQUAL => { COMPARISON_OP!(<=); },
+                OP_CONCAT => {
+                    if !self.isString(self.peek(0)) || !self.isString(self.peek(1)) {
+                        return Err(self.runtimeError("Operands must be two strings.")) ;
+                    }
+                    self.concatenate() ;
+                },
                 OP_EQUAL=> {
                     let y = self.pop() ;
                     let x = self.pop() ;
-                    self.push(BOOL_VAL!(x==y)) ;
+                    self.push(Value::from(VM::valuesEqual(x, y))) ;
+                }
+                OP_NOT => {
+                    let val = self.pop() ;
+                    self.push(Value::from(VM::isFalse(val))) ;
                 }
 
                 OP_NEGATE => {
-                    let val = -AS_INTEGER!(self.pop());
-                    self.push(INTEGER_VAL!(val));
+                    let val = match self.peek(0) {
+                        Value::INTEGER(i) => match i.checked_neg() {
+                            Some(negated) => Value::from(negated),
+                            None => return Err(self.runtimeError("Integer overflow.")),
+                        },
+                        Value::DOUBLE(d) => Value::from(-d),
+                        _ => return Err(self.runtimeError("Operand must be a number.")),
+                    } ;
+                    self.pop() ;
+                    self.push(val);
                 },
-                _ => {
-                    return InterpretResult::INTERPRET_RUNTIME_ERROR;
+                _ => return Err(self.runtimeError(format!("Unknown opcode {}.", byte).as_str())),
+            }
+        }
+    }
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        return VM::new() ;
+    }
+}
+
+/* =============== Unit tests ================= */
+
+#[cfg(test)]
+mod tests {
+
+    use crate::vm::* ;
+    use crate::vm::InterpretResult::* ;
+
+    use std::cell::RefCell ;
+
+    // Output sink whose contents the test can read back
+    #[derive(Clone, Default)]
+    struct Capture(Rc<RefCell<Vec<u8>>>) ;
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            return self.0.borrow_mut().write(buf) ;
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            return Ok(()) ;
+        }
+    }
+
+    // What a script run on vm prints
+    fn run_captured(vm: &mut VM, source: &str) -> (InterpretResult, String) {
+        let capture = Capture::default() ;
+        vm.setOutput(Box::new(capture.clone())) ;
+        let result = vm.interpret(source.to_string()) ;
+        let output = String::from_utf8(capture.0.borrow().clone()).unwrap() ;
+        return (result, output) ;
+    }
+
+    fn output_of(source: &str) -> (InterpretResult, String) {
+        return run_captured(&mut VM::new(), source) ;
+    }
+
+    fn printed(output: &str) -> (InterpretResult, String) {
+        return (INTERPRET_OK, output.to_string()) ;
+    }
+
+    #[test]
+    fn interpret_arithmetic() {
+        assert_eq!(output_of("print 1 + 2 * 3; print (4 - 1) / 3; print 7 / 2; print -(2 - 5)"),
+                   printed("7\n1\n3\n3\n")) ;
+    }
+
+    #[test]
+    fn interpret_comparison_and_not() {
+        assert_eq!(output_of("print 1 < 2.5; print 1 == 2; print !(1 == 2); print 2 >= 2; print \"a\" == \"a\""),
+                   printed("True\nFalse\nTrue\nTrue\nTrue\n")) ;
+    }
+
+    #[test]
+    fn interpret_mixed_comparisons() {
+        // Integers are compared with doubles as doubles, and nothing is
+        // ordered with NaN
+        let source = "
+            let nan = 0.0 / 0.0
+            let one; one = 1
+            print [1 == 1.0, 1.0 != 1, one == 1.0, 2 in [1.0, 2.0], 2 <= 2.5, 3 >= 2.5]
+            print [nan >= 1, nan <= 1, 1 >= nan, nan == nan, nan != nan]" ;
+        assert_eq!(output_of(source),
+                   printed("[True, False, True, True, True, True]\n[False, False, False, False, True]\n")) ;
+    }
+
+    #[test]
+    fn interpret_logical_operators() {
+        // The deciding operand is the result, and the other one never runs
+        let source = "
+            let calls = 0
+            fn touch(v) { calls = calls + 1; return v }
+            let a = nil or 2
+            let b = 0 and touch(3)
+            let c = false and touch(true)
+            let d = 1 or touch(2)
+            let e = !(false or nil) and touch(4)
+            print [a, b, c, d, e, calls]
+        " ;
+        assert_eq!(output_of(source), printed("[2, 3, False, 1, 4, 2]\n")) ;
+        assert_eq!(output_of("let n; print !1 and n + 1"), printed("False\n")) ;
+    }
+
+    #[test]
+    fn interpret_double_arithmetic() {
+        assert_eq!(output_of("print 1.5 + 2 * 0.5; print 1.5 + 2.0; print 7.0 / 2; print 1.5 * 2; print 0.1 + 0.2"),
+                   printed("2.5\n3.5\n3.5\n3\n0.30000000000000004\n")) ;
+    }
+
+    #[test]
+    fn interpret_type_mismatch() {
+        assert!(matches!(interpret("true < 1".to_string()), INTERPRET_COMPILE_ERROR(_)));
+        assert!(matches!(interpret("true + 1".to_string()), INTERPRET_COMPILE_ERROR(_)));
+        assert!(matches!(interpret("fn f() { x = 2.5 } let x = 1; f(); print x + 1".to_string()), INTERPRET_COMPILE_ERROR(_)));
+        assert_eq!(output_of("let h; { fn g() { x = 2.5 } h = g } let x = 1; h(); print x + 1"), printed("3.5\n")) ;
+    }
+
+    #[test]
+    fn interpret_variables() {
+        assert_eq!(output_of("let a = 1; let b; b = 2.5; { let c = a; c = c + 1; a = c } print a; print a * b"),
+                   printed("2\n5\n")) ;
+        assert!(matches!(interpret("let x = 1; let x = 2".to_string()), INTERPRET_COMPILE_ERROR(_)));
+        assert!(matches!(interpret("let n; n + 1".to_string()), INTERPRET_RUNTIME_ERROR(_)));
+    }
+
+    #[test]
+    fn interpret_if_else() {
+        assert_eq!(output_of("let x = 0; if x > 1 { x = 1 } else if nil { x = 2 } else { x = 3 } print x"), printed("3\n")) ;
+        assert_eq!(output_of("if 0 { let y = 1; y = y + 1; print y }"), printed("2\n")) ;
+    }
+
+    #[test]
+    fn interpret_loops() {
+        let source = "
+            let total = 0
+            for i in 0..10 {
+                if i == 3 { continue }
+                if i == 8 { break }
+                let doubled = i * 2
+                total = total + doubled
+            }
+            print total
+            let n = 0
+            for n < 5 { n = n + 1 }
+            print n
+            loop { if n == 0 { break } n = n - 1 }
+            print n" ;
+        assert_eq!(output_of(source), printed("50\n5\n0\n")) ;
+    }
+
+    #[test]
+    fn interpret_functions() {
+        let source = "
+            fn fib(n) {
+                if n < 2 { return n }
+                return fib(n - 2) + fib(n - 1)
+            }
+            fn noop() { }
+            let result = fib(10)
+            print noop()
+            {
+                fn twice(x) { return x * 2 }
+                result = twice(result)
+            }
+            print result" ;
+        assert_eq!(output_of(source), printed("nil\n110\n")) ;
+    }
+
+    #[test]
+    fn interpret_call_errors() {
+        assert!(matches!(interpret("fn f(a) { } f()".to_string()), INTERPRET_RUNTIME_ERROR(_)));
+        assert!(matches!(interpret("fn f() { return f() } f()".to_string()), INTERPRET_RUNTIME_ERROR(_)));
+        assert!(matches!(interpret("let g; g()".to_string()), INTERPRET_RUNTIME_ERROR(_)));
+    }
+
+    #[test]
+    fn interpret_closures() {
+        let source = "
+            fn counter() {
+                let count = 0
+                fn next() {
+                    count = count + 1
+                    return count
+                }
+                return next
+            }
+            let a = counter()
+            let b = counter()
+            a(); a(); b()
+            print [a(), b()]
+
+            // Both closures share the variable they capture
+            let get; let set
+            {
+                let shared = 1
+                fn g() { return shared }
+                fn s(v) { shared = v }
+                get = g; set = s
+            }
+            set(5)
+            print get()
+
+            // Each loop iteration gets its own variable
+            let first
+            for i in 0..3 {
+                let j = i
+                fn f() { return j }
+                if i == 0 { first = f }
+            }
+            print first()" ;
+        assert_eq!(output_of(source), printed("[3, 2]\n5\n0\n")) ;
+    }
+
+    #[test]
+    fn interpret_strings() {
+        let source = "
+            let greeting = \"hello\" + \", \" + \"world\"
+            let anything
+            anything = \"hello\"
+            let joined = anything + \", world\"
+            print greeting
+            print greeting == joined
+            print \"a\" == \"b\"" ;
+        assert_eq!(output_of(source), printed("hello, world\nTrue\nFalse\n")) ;
+        assert!(matches!(interpret("let s; s = \"a\"; s + 1".to_string()), INTERPRET_RUNTIME_ERROR(_)));
+    }
+
+    #[test]
+    fn interpret_under_gc_stress() {
+        let config = GcConfig { stress: true, ..GcConfig::default() } ;
+        let source = "
+            fn counter() {
+                let count = 0
+                fn next() { count = count + 1 return count }
+                return next
+            }
+            let next = counter()
+            let s = \"\"
+            for i in 0..50 {
+                s = s + \"x\"
+                next()
+            }
+            print next()
+            print len(s)" ;
+        let mut vm = VM::withConfig(config) ;
+        assert_eq!(run_captured(&mut vm, source), printed("51\n50\n")) ;
+        let stats = vm.gcStats() ;
+        assert!(stats.collections > 50) ;
+        assert!(stats.objectsFreed > 0) ;
+    }
+
+    #[test]
+    fn interpret_collects_past_threshold() {
+        let config = GcConfig { initialThreshold: 4096, ..GcConfig::default() } ;
+        let mut vm = VM::withConfig(config) ;
+        let source = "let s = \"\" for i in 0..200 { s = s + \"ab\" }" ;
+        assert_eq!(run_captured(&mut vm, source), printed("")) ;
+        let stats = vm.gcStats() ;
+        assert!(stats.collections > 0) ;
+        assert!(stats.bytesAllocated <= stats.nextGC) ;
+        // Only the latest few strings are still alive
+        assert!(stats.liveObjects < 200) ;
+    }
+
+    #[test]
+    fn interpret_arrays() {
+        let source = "
+            let a = [1, 2, 3, 4]
+            a[0] = a[-1] + 10
+            let b = a[1..3]
+            b[0] = 0
+            print a
+            print b
+            print [a[2..], a[..-2]]
+            let nested = [[1], [2, [3]]]
+            print nested[1][1][0]" ;
+        assert_eq!(output_of(source), printed("[14, 2, 3, 4]\n[0, 3]\n[[3, 4], [14, 2]]\n3\n")) ;
+    }
+
+    #[test]
+    fn interpret_array_errors() {
+        assert!(matches!(interpret("let a = [1, 2]; a[2]".to_string()), INTERPRET_RUNTIME_ERROR(_)));
+        assert!(matches!(interpret("let a = [1, 2]; a[-3] = 1".to_string()), INTERPRET_RUNTIME_ERROR(_)));
+        assert!(matches!(interpret("let a = [1, 2]; a[1..3]".to_string()), INTERPRET_RUNTIME_ERROR(_)));
+        assert!(matches!(interpret("let a = [1, 2]; a[2..1]".to_string()), INTERPRET_RUNTIME_ERROR(_)));
+        assert!(matches!(interpret("let a; a = 1; a[0]".to_string()), INTERPRET_RUNTIME_ERROR(_)));
+    }
+
+    #[test]
+    fn interpret_maps() {
+        let source = "
+            let m = {\"one\": 1, 2: \"two\", true: [3]}
+            m[\"four\"] = 4
+            m[\"one\"] = m[\"one\"] + 10
+            print [m[\"one\"], m[true][0], len(m), len([1, 2]), len(\"abc\")]
+            print [\"four\" in m, 5 in m, 2 in [1, 2]]
+
+            // Keys come back in the order they were first inserted
+            let order = \"\"
+            for k in {\"c\": 1, \"a\": 2, \"b\": 3} {
+                order = order + k
+            }
+            print order
+            for x in [10, 20] {
+                if x == 20 { continue }
+                print x
+            }
+            print m" ;
+        assert_eq!(output_of(source),
+                   printed("[11, 3, 4, 2, 3]\n[True, False, True]\ncab\n10\n{one: 11, 2: two, True: [3], four: 4}\n")) ;
+    }
+
+    #[test]
+    fn interpret_map_errors() {
+        assert!(matches!(interpret("let m = {1: 2}; m[3]".to_string()), INTERPRET_RUNTIME_ERROR(_)));
+        assert!(matches!(interpret("let k; k = 1.5; let m = {k: 1}".to_string()), INTERPRET_RUNTIME_ERROR(_)));
+        assert!(matches!(interpret("len(1)".to_string()), INTERPRET_RUNTIME_ERROR(_)));
+        assert!(matches!(interpret("let c; c = 1; 1 in c".to_string()), INTERPRET_RUNTIME_ERROR(_)));
+        assert!(matches!(interpret("let c; c = 1; for x in c { }".to_string()), INTERPRET_RUNTIME_ERROR(_)));
+    }
+
+    #[test]
+    fn interpret_classes() {
+        let source = "
+            class Counter {
+                fn init(start) {
+                    this.count = start
                 }
+                fn add(n) {
+                    this.count = this.count + n
+                    return this
+                }
+                fn adder() {
+                    fn add(n) { return this.add(n) }
+                    return add
+                }
+            }
+            let c = Counter(10)
+            c.add(1).add(2)
+            let bound = c.add
+            bound(3)
+            c.adder()(4)
+
+            // A field holding a function is called like a method
+            fn twice(n) { return n * 2 }
+            c.twice = twice
+            print [c.count, c.twice(5)]
+
+            // init can be called again, and hands back the instance
+            print c.init(0).count
+            class Empty { }
+            let e = Empty()
+            e.value = 7
+            print e.value
+            print [c, Counter, bound]" ;
+        // Collecting on every allocation checks that classes, instances and
+        // bound methods keep what they refer to alive
+        let mut vm = VM::withConfig(GcConfig { stress: true, ..GcConfig::default() }) ;
+        assert_eq!(run_captured(&mut vm, source),
+                   printed("[20, 10]\n0\n7\n[<Counter instance>, <class Counter>, <fn add>]\n")) ;
+    }
+
+    #[test]
+    fn interpret_inheritance() {
+        let source = "
+            class Shape {
+                fn init(name) { this.name = name }
+                fn area() { return 0 }
+                fn label() { return this.name + \": \" }
+            }
+            class Circle < Shape {
+                fn init(r) {
+                    super.init(\"circle\")
+                    this.r = r
+                }
+                fn area() { return 3 * this.r * this.r + super.area() }
+                fn base() {
+                    let area = super.area
+                    return area()
+                }
+            }
+            // Inherits init along with everything else
+            class Square < Shape { }
+            class Unit < Circle {
+                fn init() { super.init(1) }
+            }
+
+            let c = Circle(2)
+            let s = Square(\"square\")
+            print [c.area(), c.base(), s.area(), Unit().area()]
+            print c.label() + s.label()" ;
+        assert_eq!(output_of(source), printed("[12, 0, 0, 3]\ncircle: square: \n")) ;
+    }
+
+    #[test]
+    fn interpret_class_errors() {
+        assert!(matches!(interpret("let x; x = 1; class A < x { }".to_string()), INTERPRET_RUNTIME_ERROR(_)));
+        assert!(matches!(interpret("class A { } class B < A { fn f() { return super.f() } } B().f()".to_string()), INTERPRET_RUNTIME_ERROR(_)));
+        assert!(matches!(interpret("class A { } A(1)".to_string()), INTERPRET_RUNTIME_ERROR(_)));
+        assert!(matches!(interpret("class A { fn init(x) { } } A()".to_string()), INTERPRET_RUNTIME_ERROR(_)));
+        assert!(matches!(interpret("class A { } A().missing".to_string()), INTERPRET_RUNTIME_ERROR(_)));
+        assert!(matches!(interpret("class A { } A().missing()".to_string()), INTERPRET_RUNTIME_ERROR(_)));
+        assert!(matches!(interpret("let a; a = 1; a.x".to_string()), INTERPRET_RUNTIME_ERROR(_)));
+        assert!(matches!(interpret("let a; a = [1]; a.x = 1".to_string()), INTERPRET_RUNTIME_ERROR(_)));
+        assert!(matches!(interpret("let a; a = 1; a.f()".to_string()), INTERPRET_RUNTIME_ERROR(_)));
+    }
+
+    #[test]
+    fn interpret_modules() {
+        let dir = std::env::temp_dir().join(format!("coyote-vm-modules-{}", std::process::id())) ;
+        std::fs::create_dir_all(&dir).unwrap() ;
+        std::fs::write(dir.join("counter.coy"), "
+            module counter
+            let count = 0
+            fn bump() { count = count + 1 return count }").unwrap() ;
+        std::fs::write(dir.join("geometry.coy"), "
+            module geometry
+            import counter
+            let pi = 3
+            fn area(r) { counter::bump() return pi * r * r }").unwrap() ;
+
+        // Each module has globals of its own and runs only once
+        let source = "
+            import geometry
+            import counter
+            import geometry
+            let pi = 100
+            print [geometry::area(2), geometry::area(1), geometry::pi, pi]
+            print counter::count" ;
+        let mut vm = VM::new() ;
+        vm.setSearchPath(vec![dir.clone()]) ;
+        assert_eq!(run_captured(&mut vm, source), printed("[12, 3, 3, 100]\n2\n")) ;
+        // A later script on the same VM doesn't run them again
+        assert_eq!(run_captured(&mut vm, "import geometry; geometry::area(1); print counter::count"), printed("3\n")) ;
+        assert!(matches!(vm.interpret("import missing".to_string()), INTERPRET_COMPILE_ERROR(_)));
+        assert!(matches!(vm.interpret("import geometry; geometry::volume".to_string()), INTERPRET_COMPILE_ERROR(_)));
+        let _ = std::fs::remove_dir_all(&dir) ;
+    }
+
+    #[test]
+    fn interpret_keeps_globals() {
+        // A script sees the globals of the ones run before it on the same VM
+        let capture = Capture::default() ;
+        let mut vm = VM::new() ;
+        vm.setOutput(Box::new(capture.clone())) ;
+        assert_eq!(vm.interpret("let x = 1; fn bump() { x = x + 1 }".to_string()), INTERPRET_OK);
+        assert_eq!(vm.interpret("bump(); print x".to_string()), INTERPRET_OK);
+        assert!(matches!(vm.interpret("let x = 2.5".to_string()), INTERPRET_COMPILE_ERROR(_)));
+        // and not those of a script that failed to compile
+        assert!(matches!(vm.interpret("let y = 1; y + true".to_string()), INTERPRET_COMPILE_ERROR(_)));
+        assert!(matches!(vm.interpret("y".to_string()), INTERPRET_COMPILE_ERROR(_)));
+        let output = String::from_utf8(capture.0.borrow().clone()).unwrap() ;
+        assert_eq!(output, "2\n") ;
+    }
+
+    #[test]
+    fn interpret_echo_result() {
+        // What the repl does: a line ending in an expression shows its value
+        let capture = Capture::default() ;
+        let mut vm = VM::new() ;
+        vm.setOutput(Box::new(capture.clone())) ;
+        vm.setOptions(VmOptions { echoResult: true, ..VmOptions::default() }) ;
+        for line in ["let x = 2", "x * 3", "print x", "nil", "[x, \"s\"]"] {
+            assert_eq!(vm.interpret(line.to_string()), INTERPRET_OK);
+        }
+        let output = String::from_utf8(capture.0.borrow().clone()).unwrap() ;
+        assert_eq!(output, "6\n2\n[2, s]\n") ;
+    }
+
+    #[test]
+    fn interpret_print() {
+        let source = "
+            class Point { }
+            print 1 + 2; print 2.5
+            print \"a\" + \"b\"
+            for x in [true, nil] { print x }
+            print [1, \"s\"]
+            print Point()
+            1 + 1" ;
+        // The value the script ends with isn't printed
+        assert_eq!(output_of(source),
+                   (INTERPRET_OK, "3\n2.5\nab\nTrue\nnil\n[1, s]\n<Point instance>\n".to_string())) ;
+        assert_eq!(output_of("print 1.5 + 2.0"), (INTERPRET_OK, "3.5\n".to_string())) ;
+        // Output written before a runtime error is kept
+        let (result, output) = output_of("print 1; let n; n + 1") ;
+        assert!(matches!(result, INTERPRET_RUNTIME_ERROR(_))) ;
+        assert_eq!(output, "1\n") ;
+    }
+
+    #[test]
+    fn interpret_string_interpolation() {
+        let source = r#"
+            let count = 3
+            let price = 1.5
+            let item = "apple"
+            print "Total: ${count * price}"
+            print "${count} ${ item + "${"s"}" }, ${[true, nil]} \${not}""# ;
+        assert_eq!(output_of(source),
+                   (INTERPRET_OK, "Total: 4.5\n3 apples, [True, nil] ${not}\n".to_string())) ;
+    }
+
+    #[test]
+    fn interpret_string_literals() {
+        let source = r#"
+            fn letter(name) {
+                return """
+                    Dear "friend",
+                      \u{2603}\tsee """ + name + """
+                    """
+            }
+            print letter(r"C:\new")
+            print len("\u{1F600}\n")"# ;
+        assert_eq!(output_of(source),
+                   (INTERPRET_OK, "Dear \"friend\",\n  \u{2603}\tsee C:\\new\n2\n".to_string())) ;
+    }
+
+    #[test]
+    fn interpret_debug_options() {
+        let capture = Capture::default() ;
+        let mut vm = VM::new() ;
+        vm.setOutput(Box::new(io::sink())) ;
+        vm.setDebugOutput(Box::new(capture.clone())) ;
+        assert_eq!(vm.interpret("1 + 2".to_string()), INTERPRET_OK);
+        assert!(capture.0.borrow().is_empty()) ;
+
+        vm.setOptions(VmOptions { traceExecution: true, compiler: CompilerOptions { printCode: true, parserDebug: true }, ..VmOptions::default() }) ;
+        assert_eq!(vm.interpret("1 + 2".to_string()), INTERPRET_OK);
+        let debug = String::from_utf8(capture.0.borrow().clone()).unwrap() ;
+        // The parser log, then the disassembly, then the trace
+        let prec = debug.find("PREC (after advance): T_PLUS").unwrap() ;
+        let code = debug.find("== Done ==").unwrap() ;
+        let trace = debug.find("[ 1 ][ 2 ]").unwrap() ;
+        assert!(prec < code && code < trace) ;
+        assert_eq!(debug.matches("OP_IADD").count(), 2) ;
+    }
+
+    fn runtime_error(source: &str) -> RuntimeError {
+        match interpret(source.to_string()) {
+            INTERPRET_RUNTIME_ERROR(error) => return error,
+            result => panic!("Expected a runtime error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn interpret_runtime_error_trace() {
+        let error = runtime_error("fn inner(x) {\n  return x / 0\n}\nfn outer() { return inner(1) }\n\nouter()") ;
+        assert_eq!(error.message, "Division by zero.") ;
+        assert_eq!(error.line, 2) ;
+        let calls: Vec<(&str, usize)> = error.trace.iter().map(|frame| (frame.function.as_str(), frame.line)).collect() ;
+        assert_eq!(calls, vec![("inner", 2), ("outer", 4), ("", 6)]) ;
+        assert_eq!(error.to_string(),
+                   "[line 2] Runtime error: Division by zero.\n  [line 2] in inner()\n  [line 4] in outer()\n  [line 6] in script") ;
+    }
+
+    #[test]
+    fn interpret_arithmetic_errors() {
+        assert_eq!(runtime_error("1 / 0").message, "Division by zero.") ;
+        assert_eq!(runtime_error("9223372036854775807 + 1").message, "Integer overflow.") ;
+        assert_eq!(runtime_error("let n = 0 - 9223372036854775807 - 1; -n").message, "Integer overflow.") ;
+        assert_eq!(runtime_error("let n; n = 4611686018427387904; n * 2").message, "Integer overflow.") ;
+        assert_eq!(runtime_error("let s; s = \"a\"; s - 1").message, "Operands must be numbers.") ;
+        // Doubles follow IEEE 754 instead
+        assert_eq!(output_of("print 1.0 / 0.0"), printed("inf\n")) ;
+    }
+
+    #[test]
+    fn interpret_stack_overflow() {
+        let error = runtime_error("fn f(n) {\n  return f(n + 1)\n}\nf(0)") ;
+        assert_eq!(error.message, "Stack overflow.") ;
+        assert_eq!(error.trace.len(), FRAMES_MAX) ;
+        assert!(error.to_string().ends_with("[line 2] in f() (255 times)\n  [line 4] in script")) ;
+    }
+
+    #[test]
+    fn interpret_compile_error() {
+        assert!(matches!(interpret("(1".to_string()), INTERPRET_COMPILE_ERROR(_)));
+    }
+
+    #[test]
+    fn interpret_compile_error_diagnostics() {
+        let mut vm = VM::new() ;
+        vm.setScriptName("main.coy") ;
+        match vm.interpret("let a = 1\nlet a = 2\nb".to_string()) {
+            INTERPRET_COMPILE_ERROR(diagnostics) => {
+                let shown: Vec<String> = diagnostics.iter().map(|d| d.to_string()).collect() ;
+                assert_eq!(shown, vec![
+                    "main.coy:2:5: error[E0102]: Variable 'a' is already declared in this scope.".to_string(),
+                    "main.coy:3:1: error[E0101]: Undefined variable 'b'.".to_string(),
+                ]) ;
             }
+            result => panic!("Expected a compile error, got {:?}", result)
         }
     }
 }